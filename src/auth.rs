@@ -0,0 +1,422 @@
+use crate::config::Config;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::future::{self, Future};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tonic::body::BoxBody;
+use tonic::codegen::http::{self, HeaderValue};
+use tonic::server::NamedService;
+use tonic::transport::server::TcpConnectInfo;
+use tonic::transport::Channel;
+use tonic::Status;
+use tower::Service;
+use tracing::warn;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const AUTH_HEADER: &str = "x-lally-auth";
+const TOKEN_VERSION: &str = "v2";
+// how often tokens too old to be replayed anyway are dropped from the replay cache
+const PRUNE_INTERVAL_MS: i64 = 1000;
+
+// which secret a service is guarded by, node to node traffic and replicated kv traffic
+// can be keyed separately
+#[derive(Clone, Copy, Debug)]
+pub enum Scope {
+    Cluster,
+    Kv,
+}
+
+impl Scope {
+    fn name(self) -> &'static str {
+        match self {
+            Scope::Cluster => "cluster",
+            Scope::Kv => "kv",
+        }
+    }
+}
+
+// tokens that got through recently, a second call with one of them is a replay
+#[derive(Default)]
+struct Seen {
+    tokens: HashMap<String, i64>,
+    pruned_at: i64,
+}
+
+// shared-secret authentication for the grpc port: every call carries a token of
+// `v2.<unix ms>.<nonce>.<hmac>.<node id>`, the hmac also covering the method called. the
+// receiving side checks the hmac, that the timestamp is recent and that it hasn't seen the
+// token before, so a sniffed token can't be used again or for another method. the request
+// body isn't covered, and nothing is hidden, that's what tls is for
+pub struct Auth {
+    node_id: String,
+    cluster_secret: Option<Vec<u8>>,
+    kv_secret: Option<Vec<u8>>,
+    admin_secret: Option<Vec<u8>>,
+    max_skew: i64,
+    seen: Mutex<Seen>,
+}
+
+impl Auth {
+    pub fn new(config: &Config) -> Self {
+        let cluster_secret = config.cluster_secret().map(|s| s.as_bytes().to_vec());
+        Auth {
+            node_id: config.node_id().to_string(),
+            // the kv services fall back to the cluster secret unless they have their own
+            kv_secret: config
+                .kv_secret()
+                .map(|s| s.as_bytes().to_vec())
+                .or_else(|| cluster_secret.clone()),
+            admin_secret: config
+                .admin_secret()
+                .map(|s| s.as_bytes().to_vec())
+                .or_else(|| cluster_secret.clone()),
+            cluster_secret,
+            max_skew: i64::try_from(config.auth_max_skew()).unwrap_or(i64::MAX),
+            seen: Mutex::new(Seen::default()),
+        }
+    }
+
+    fn secret(&self, scope: Scope) -> Option<&[u8]> {
+        match scope {
+            Scope::Cluster => self.cluster_secret.as_deref(),
+            Scope::Kv => self.kv_secret.as_deref(),
+        }
+    }
+
+    fn mac(
+        secret: &[u8],
+        scope: Scope,
+        path: &str,
+        timestamp: &str,
+        nonce: &str,
+        node_id: &str,
+    ) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(secret).expect("hmac accepts keys of any size");
+        for part in [scope.name(), path, timestamp, nonce, node_id] {
+            mac.update(part.as_bytes());
+            mac.update(b"\n");
+        }
+        mac
+    }
+
+    fn sign(&self, scope: Scope, path: &str) -> Option<String> {
+        let secret = self.secret(scope)?;
+        let timestamp = Utc::now().timestamp_millis().to_string();
+        let nonce = format!("{:016x}", rand::thread_rng().gen::<u64>());
+        let mac = Self::mac(secret, scope, path, &timestamp, &nonce, &self.node_id);
+        Some(format!(
+            "{}.{}.{}.{}.{}",
+            TOKEN_VERSION,
+            timestamp,
+            nonce,
+            hex::encode(mac.finalize().into_bytes()),
+            self.node_id
+        ))
+    }
+
+    fn verify(&self, scope: Scope, path: &str, token: Option<&str>) -> Result<(), &'static str> {
+        let Some(secret) = self.secret(scope) else {
+            return Ok(());
+        };
+        let token = token.ok_or("missing token")?;
+        // the node id goes last since it's the only part that may contain dots
+        let mut parts = token.splitn(5, '.');
+        let (Some(TOKEN_VERSION), Some(timestamp), Some(nonce), Some(signature), Some(node_id)) = (
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+        ) else {
+            return Err("malformed token");
+        };
+        let sent_at: i64 = timestamp.parse().map_err(|_| "malformed timestamp")?;
+        let now = Utc::now().timestamp_millis();
+        if (now - sent_at).abs() > self.max_skew {
+            return Err("token expired");
+        }
+        let decoded = hex::decode(signature).map_err(|_| "malformed signature")?;
+        Self::mac(secret, scope, path, timestamp, nonce, node_id)
+            .verify_slice(&decoded)
+            .map_err(|_| "bad signature")?;
+
+        // only tokens that are still within the skew need remembering
+        let mut seen = self.seen.lock().expect("auth lock poisoned");
+        if now - seen.pruned_at >= PRUNE_INTERVAL_MS {
+            let max_skew = self.max_skew;
+            seen.tokens
+                .retain(|_, sent_at| (now - *sent_at).abs() <= max_skew);
+            seen.pruned_at = now;
+        }
+        if seen.tokens.insert(signature.to_string(), sent_at).is_some() {
+            return Err("token replayed");
+        }
+        Ok(())
+    }
+
+    // the admin http routes take the secret itself as a bearer token, without one set only
+    // requests from this machine get through
+    pub fn verify_admin(&self, token: Option<&str>, local: bool) -> Result<(), &'static str> {
+        let Some(secret) = self.admin_secret.as_deref() else {
+            return if local {
+                Ok(())
+            } else {
+                Err("admin routes are only open to localhost without an admin secret")
+            };
+        };
+        let token = token.ok_or("missing token")?;
+        // compared through the hmac so the time taken doesn't give the secret away
+        let mut mac = HmacSha256::new_from_slice(secret).expect("hmac accepts keys of any size");
+        mac.update(token.as_bytes());
+        HmacSha256::new_from_slice(secret)
+            .expect("hmac accepts keys of any size")
+            .chain_update(secret)
+            .verify_slice(&mac.finalize().into_bytes())
+            .map_err(|_| "bad token")
+    }
+
+    // a channel whose calls carry a fresh token each
+    pub fn sign_channel(self: &Arc<Self>, channel: Channel, scope: Scope) -> Signed {
+        Signed {
+            channel,
+            auth: Arc::clone(self),
+            scope,
+        }
+    }
+
+    // a service that only lets calls with a valid token through
+    pub fn verify_service<S>(self: &Arc<Self>, inner: S, scope: Scope) -> Verified<S> {
+        Verified {
+            inner,
+            auth: Arc::clone(self),
+            scope,
+        }
+    }
+}
+
+// attaches a fresh token to every outgoing call, a no-op without a secret. it wraps the
+// channel rather than being an interceptor since the token covers the method's path
+#[derive(Clone)]
+pub struct Signed {
+    channel: Channel,
+    auth: Arc<Auth>,
+    scope: Scope,
+}
+
+impl Service<http::Request<BoxBody>> for Signed {
+    type Response = <Channel as Service<http::Request<BoxBody>>>::Response;
+    type Error = <Channel as Service<http::Request<BoxBody>>>::Error;
+    type Future = <Channel as Service<http::Request<BoxBody>>>::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Service::poll_ready(&mut self.channel, cx)
+    }
+
+    fn call(&mut self, mut request: http::Request<BoxBody>) -> Self::Future {
+        if let Some(token) = self.auth.sign(self.scope, request.uri().path()) {
+            match HeaderValue::try_from(token) {
+                Ok(token) => {
+                    request.headers_mut().insert(AUTH_HEADER, token);
+                }
+                // the peer turns the call down without a token
+                Err(_) => warn!("Node id can't be sent in a header"),
+            }
+        }
+        Service::call(&mut self.channel, request)
+    }
+}
+
+// rejects calls without a valid token, lets everything through without a secret
+#[derive(Clone)]
+pub struct Verified<S> {
+    inner: S,
+    auth: Arc<Auth>,
+    scope: Scope,
+}
+
+impl<S: NamedService> NamedService for Verified<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S, B> Service<http::Request<B>> for Verified<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<BoxBody>>,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let token = request
+            .headers()
+            .get(AUTH_HEADER)
+            .and_then(|value| value.to_str().ok());
+        match self.auth.verify(self.scope, request.uri().path(), token) {
+            Ok(()) => Box::pin(self.inner.call(request)),
+            Err(reason) => {
+                warn!(
+                    "Rejected unauthenticated {} call to {} from {:?}: {}",
+                    self.scope.name(),
+                    request.uri().path(),
+                    request
+                        .extensions()
+                        .get::<TcpConnectInfo>()
+                        .and_then(|info| info.remote_addr()),
+                    reason
+                );
+                let response =
+                    Status::unauthenticated("Invalid or missing cluster token").into_http();
+                Box::pin(future::ready(Ok(response)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATH: &str = "/lally.KvStore/Replicate";
+
+    fn auth(node_id: &str, secret: Option<&str>) -> Auth {
+        let secret = secret.map(|s| s.as_bytes().to_vec());
+        Auth {
+            node_id: node_id.to_string(),
+            cluster_secret: secret.clone(),
+            kv_secret: secret.clone(),
+            admin_secret: secret,
+            max_skew: 30_000,
+            seen: Mutex::new(Seen::default()),
+        }
+    }
+
+    // a token signed as if it had been sent at the given time
+    fn token_at(auth: &Auth, sent_at: i64) -> String {
+        let timestamp = sent_at.to_string();
+        let nonce = "0123456789abcdef";
+        let secret = auth.secret(Scope::Kv).unwrap();
+        let mac = Auth::mac(secret, Scope::Kv, PATH, &timestamp, nonce, &auth.node_id);
+        format!(
+            "{}.{}.{}.{}.{}",
+            TOKEN_VERSION,
+            timestamp,
+            nonce,
+            hex::encode(mac.finalize().into_bytes()),
+            auth.node_id
+        )
+    }
+
+    #[test]
+    fn signed_tokens_verify() {
+        let sender = auth("node-a", Some("secret"));
+        let receiver = auth("node-b", Some("secret"));
+        let token = sender.sign(Scope::Kv, PATH).unwrap();
+        assert_eq!(receiver.verify(Scope::Kv, PATH, Some(&token)), Ok(()));
+    }
+
+    #[test]
+    fn node_ids_may_contain_dots() {
+        let sender = auth("node.a.local", Some("secret"));
+        let token = sender.sign(Scope::Cluster, PATH).unwrap();
+        assert_eq!(sender.verify(Scope::Cluster, PATH, Some(&token)), Ok(()));
+    }
+
+    #[test]
+    fn tokens_are_bound_to_the_secret_and_method() {
+        let sender = auth("node-a", Some("secret"));
+        let token = sender.sign(Scope::Kv, PATH).unwrap();
+        let stranger = auth("node-b", Some("other"));
+        assert_eq!(
+            stranger.verify(Scope::Kv, PATH, Some(&token)),
+            Err("bad signature")
+        );
+        let receiver = auth("node-b", Some("secret"));
+        assert_eq!(
+            receiver.verify(Scope::Kv, "/lally.KvStore/Remove", Some(&token)),
+            Err("bad signature")
+        );
+        assert_eq!(
+            receiver.verify(Scope::Cluster, PATH, Some(&token)),
+            Err("bad signature")
+        );
+    }
+
+    #[test]
+    fn tampered_tokens_are_refused() {
+        let sender = auth("node-a", Some("secret"));
+        let receiver = auth("node-b", Some("secret"));
+        let token = sender.sign(Scope::Kv, PATH).unwrap();
+        let impostor = token.replace("node-a", "node-c");
+        assert_eq!(
+            receiver.verify(Scope::Kv, PATH, Some(&impostor)),
+            Err("bad signature")
+        );
+        assert_eq!(receiver.verify(Scope::Kv, PATH, None), Err("missing token"));
+        assert_eq!(
+            receiver.verify(Scope::Kv, PATH, Some("v1.garbage")),
+            Err("malformed token")
+        );
+    }
+
+    #[test]
+    fn tokens_outside_the_skew_are_refused() {
+        let sender = auth("node-a", Some("secret"));
+        let receiver = auth("node-b", Some("secret"));
+        let now = Utc::now().timestamp_millis();
+        for sent_at in [now - 60_000, now + 60_000] {
+            assert_eq!(
+                receiver.verify(Scope::Kv, PATH, Some(&token_at(&sender, sent_at))),
+                Err("token expired")
+            );
+        }
+        assert_eq!(
+            receiver.verify(Scope::Kv, PATH, Some(&token_at(&sender, now - 10_000))),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn tokens_only_verify_once() {
+        let sender = auth("node-a", Some("secret"));
+        let receiver = auth("node-b", Some("secret"));
+        let token = sender.sign(Scope::Kv, PATH).unwrap();
+        assert_eq!(receiver.verify(Scope::Kv, PATH, Some(&token)), Ok(()));
+        assert_eq!(
+            receiver.verify(Scope::Kv, PATH, Some(&token)),
+            Err("token replayed")
+        );
+        // a fresh token for the same call is fine
+        let token = sender.sign(Scope::Kv, PATH).unwrap();
+        assert_eq!(receiver.verify(Scope::Kv, PATH, Some(&token)), Ok(()));
+    }
+
+    #[test]
+    fn nothing_is_checked_without_a_secret() {
+        let open = auth("node-a", None);
+        assert!(open.sign(Scope::Kv, PATH).is_none());
+        assert_eq!(open.verify(Scope::Kv, PATH, None), Ok(()));
+    }
+
+    #[test]
+    fn admin_routes_take_the_secret_or_localhost() {
+        let secured = auth("node-a", Some("secret"));
+        assert_eq!(secured.verify_admin(Some("secret"), false), Ok(()));
+        assert_eq!(secured.verify_admin(Some("guess"), true), Err("bad token"));
+        assert_eq!(secured.verify_admin(None, true), Err("missing token"));
+        let open = auth("node-a", None);
+        assert_eq!(open.verify_admin(None, true), Ok(()));
+        assert!(open.verify_admin(Some("secret"), false).is_err());
+    }
+}
@@ -1,21 +1,42 @@
+// lints newer clippy raises on what tonic generates: its clients and servers return the
+// large Status and it marks fns that return futures as must_use
+#[allow(clippy::result_large_err, clippy::double_must_use)]
 pub mod services {
     tonic::include_proto!("lally");
 }
 
+use crate::auth::Scope;
 use crate::config::Config;
+use crate::lally::protocol;
+use crate::lally::stats::Stats;
 use crate::lally::Lally;
 use crate::utils::Operation;
 use anyhow::{Context, Result};
+use services::anti_entropy_server::{AntiEntropy, AntiEntropyServer};
 use services::cluster_management_server::{ClusterManagement, ClusterManagementServer};
 use services::kv_store_server::{KvStore, KvStoreServer};
+use services::rebalance_server::{Rebalance, RebalanceServer};
 use services::{
-    AddKvResponse, AddNodeRequest, AddNodeResponse, GetKvResponse, JoinResponse, KvOperation,
-    NoContentRequest, RemoveKvResponse, RemoveNodeResponse,
+    AddKvResponse, AddNodeRequest, AddNodeResponse, BootstrapChunk, BootstrapRequest,
+    ClusterStatusResponse, GetKvResponse, GossipRequest, GossipResponse, JoinRequest, JoinResponse,
+    KvOperation, MerkleNodesRequest, MerkleNodesResponse, NoContentRequest, NodeStats,
+    PingReqRequest, PingReqResponse, PingResponse, RemoveKvResponse, RemoveNodeRequest,
+    RemoveNodeResponse, ReplicateAck, ReplicateBatch, ReplicateEntry, ReplicateResult,
+    SyncRangeRequest, SyncRangeResponse, TransferRequest, TransferResponse,
 };
+use std::collections::HashMap;
 use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::transport::Server;
-use tonic::{Request, Response, Status};
-use tracing::{error, info};
+use tonic::{Code, Request, Response, Status, Streaming};
+use tracing::{debug, error, info};
+
+// acks a replica may have ready before the coordinator reads them
+const REPLICATE_ACK_BUFFER: usize = 64;
+
+const DECOMMISSIONING: &str = "Node is being decommissioned";
 
 fn convert_to_operation(request: KvOperation) -> Operation {
     // this of a fn would convert the grpc kvOperation to Operation struct which is widely
@@ -26,6 +47,51 @@ fn convert_to_operation(request: KvOperation) -> Operation {
         value: request.value,
         timestamp: request.timestamp.expect("Timestamp should be present"),
         key: request.key,
+        annotations: HashMap::new(),
+    }
+}
+
+// a write that came over a replication stream, applied just like add_kv or remove_kv would
+fn apply_replicated(lally: &Lally, entry: ReplicateEntry) -> ReplicateResult {
+    let seq = entry.seq;
+    let Some(operation) = entry
+        .operation
+        .filter(|operation| operation.timestamp.is_some())
+    else {
+        return ReplicateResult {
+            seq,
+            code: Code::InvalidArgument as i32,
+            message: "Write without an operation".to_string(),
+        };
+    };
+    // whatever a decommissioning node takes in now would never be handed off, the
+    // coordinator hints it and the other replicas still have it
+    if lally.decommission.is_draining() {
+        return ReplicateResult {
+            seq,
+            code: Code::Unavailable as i32,
+            message: DECOMMISSIONING.to_string(),
+        };
+    }
+    let operation = convert_to_operation(operation);
+
+    lally.hooks.invoke_all(&operation);
+
+    if entry.remove {
+        if !lally.store.remove(&operation).success {
+            return ReplicateResult {
+                seq,
+                code: Code::InvalidArgument as i32,
+                message: "Failed to remove kv because it doesn't exist".to_string(),
+            };
+        }
+    } else {
+        let _add_response = lally.store.add(&operation);
+    }
+    ReplicateResult {
+        seq,
+        code: Code::Ok as i32,
+        message: String::new(),
     }
 }
 
@@ -47,12 +113,28 @@ impl GrpcServer {
 
         info!("GRPC server listening on {}", addr);
 
-        tokio::spawn(
-            Server::builder()
-                .add_service(ClusterManagementServer::new(grpc_server.clone()))
-                .add_service(KvStoreServer::new(grpc_server))
-                .serve(addr),
-        );
+        let tls = grpc_server.lally.tls.clone();
+        let auth = Arc::clone(&grpc_server.lally.auth);
+        let router = Server::builder()
+            .add_service(auth.verify_service(
+                ClusterManagementServer::new(grpc_server.clone()),
+                Scope::Cluster,
+            ))
+            .add_service(auth.verify_service(KvStoreServer::new(grpc_server.clone()), Scope::Kv))
+            .add_service(
+                auth.verify_service(RebalanceServer::new(grpc_server.clone()), Scope::Cluster),
+            )
+            .add_service(auth.verify_service(AntiEntropyServer::new(grpc_server), Scope::Cluster));
+        match tls {
+            Some(tls) => {
+                info!("GRPC server requires mutual TLS");
+                let incoming = tls.incoming(addr).await?;
+                tokio::spawn(router.serve_with_incoming(incoming));
+            }
+            None => {
+                tokio::spawn(router.serve(addr));
+            }
+        }
 
         Ok(())
     }
@@ -64,6 +146,10 @@ impl KvStore for GrpcServer {
         &self,
         request: Request<KvOperation>,
     ) -> Result<Response<GetKvResponse>, Status> {
+        // a node still bootstrapping would answer with whatever it has copied so far
+        if !self.lally.bootstrap.is_ready() {
+            return Err(Status::unavailable("Node is still bootstrapping"));
+        }
         let operation = convert_to_operation(request.into_inner());
 
         let get_response = self.lally.store.get(&operation);
@@ -78,6 +164,9 @@ impl KvStore for GrpcServer {
         &self,
         request: Request<KvOperation>,
     ) -> Result<Response<AddKvResponse>, Status> {
+        if self.lally.decommission.is_draining() {
+            return Err(Status::unavailable(DECOMMISSIONING));
+        }
         let operation = convert_to_operation(request.into_inner());
 
         self.lally.hooks.invoke_all(&operation);
@@ -93,6 +182,9 @@ impl KvStore for GrpcServer {
         &self,
         request: Request<KvOperation>,
     ) -> Result<Response<RemoveKvResponse>, Status> {
+        if self.lally.decommission.is_draining() {
+            return Err(Status::unavailable(DECOMMISSIONING));
+        }
         let operation = convert_to_operation(request.into_inner());
 
         self.lally.hooks.invoke_all(&operation);
@@ -110,94 +202,322 @@ impl KvStore for GrpcServer {
             ))
         }
     }
+
+    type ReplicateStream = ReceiverStream<Result<ReplicateAck, Status>>;
+
+    // every batch is applied in order and answered with one ack, the coordinator matches
+    // the results to its writes by sequence number
+    async fn replicate(
+        &self,
+        request: Request<Streaming<ReplicateBatch>>,
+    ) -> Result<Response<Self::ReplicateStream>, Status> {
+        let remote_addr = request.remote_addr();
+        let mut batches = request.into_inner();
+        let (sender, receiver) = mpsc::channel(REPLICATE_ACK_BUFFER);
+        let lally = Arc::clone(&self.lally);
+        tokio::spawn(async move {
+            loop {
+                let batch = match batches.message().await {
+                    Ok(Some(batch)) => batch,
+                    Ok(None) => break,
+                    Err(status) => {
+                        debug!(
+                            "Replication stream from {:?} broke: {}",
+                            remote_addr, status
+                        );
+                        break;
+                    }
+                };
+                let results = batch
+                    .entries
+                    .into_iter()
+                    .map(|entry| apply_replicated(&lally, entry))
+                    .collect();
+                if sender.send(Ok(ReplicateAck { results })).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
 }
 
 #[tonic::async_trait]
 impl ClusterManagement for GrpcServer {
-    async fn join(
-        &self,
-        request: Request<NoContentRequest>,
-    ) -> Result<Response<JoinResponse>, Status> {
-        // getting the addr of the client node that is trying to join the cluster
-        if let Some(mut client_addr) = request.remote_addr() {
-            client_addr.set_port(self.grpc_port);
-            let client_addr_str = client_addr.to_string();
-            info!("Client {} attempting to join cluster", client_addr_str);
-            // we are packing up the store data and the nodes connected in the cluster rn and send it
-            // to the client node so that it could also replicate
-            let nodes_addrs: Vec<String> = self.lally.pool.get_addrs();
-            let store_data = self.lally.store.export_store();
-
-            // gossiping the client node addr
-            self.lally.pool.gossip(client_addr_str.clone()).await;
-
-            self.lally
-                .pool
-                .conn_make(&client_addr_str)
-                .await
-                .map_err(|e| {
-                    error!("Failed to connect to {}: {}", client_addr_str, e);
-                    Status::invalid_argument(format!("Failed to connect to client: {}", e))
-                })?;
-
-            info!("Client {} successfully joined the cluster", client_addr_str);
-
-            Ok(Response::new(JoinResponse {
-                message: "Joined successfully".to_string(),
-                addresses: nodes_addrs,
-                store_data,
-            }))
-        } else {
-            error!("Failed to parse the client address");
-            Err(Status::invalid_argument("Failed to connect to client"))
+    async fn join(&self, request: Request<JoinRequest>) -> Result<Response<JoinResponse>, Status> {
+        // without an advertised address, the address the client dialed to reach us is how
+        // the cluster knows this node
+        if let Some(mut local_addr) = request.local_addr() {
+            local_addr.set_port(self.grpc_port);
+            self.lally.pool.learn_local_addr(&local_addr.to_string());
+        }
+        let remote_addr = request.remote_addr();
+        let request = request.into_inner();
+        let Some(mut node) = request.node.filter(|node| !node.id.is_empty()) else {
+            error!("Join request without a node id");
+            return Err(Status::invalid_argument("Joining node must send its id"));
+        };
+        if node.addr.is_empty() {
+            let Some(mut client_addr) = remote_addr else {
+                error!("Failed to parse the client address");
+                return Err(Status::invalid_argument("Failed to connect to client"));
+            };
+            let grpc_port = u16::try_from(request.grpc_port)
+                .map_err(|_| Status::invalid_argument("Invalid gRPC port"))?;
+            client_addr.set_port(grpc_port);
+            node.addr = client_addr.to_string();
+        }
+        if node.id == self.lally.pool.local_id() {
+            error!("Node {} tried to join with our own id", node.id);
+            return Err(Status::already_exists("Node id is already taken"));
+        }
+
+        // a node too old or too new for us would misread what we send it, it's left out
+        // before anything about it is gossiped
+        let protocol = node.protocol.get_or_insert_with(Default::default);
+        if let Err(e) = protocol::check(protocol) {
+            error!("Refusing to let node {} join: {:#}", node.id, e);
+            return Err(Status::failed_precondition(format!(
+                "Incompatible protocol: {:#}",
+                e
+            )));
         }
+
+        // a node that remembers another cluster would merge the two, it has to be wiped first
+        let cluster_id = self
+            .lally
+            .cluster_state
+            .accept_cluster_id(&request.cluster_id);
+        if !request.cluster_id.is_empty() && request.cluster_id != cluster_id {
+            error!(
+                "Node {} belongs to cluster {}, refusing to let it into {}",
+                node.id, request.cluster_id, cluster_id
+            );
+            return Err(Status::failed_precondition(format!(
+                "Node belongs to cluster {}, this is cluster {}",
+                request.cluster_id, cluster_id
+            )));
+        }
+
+        info!(
+            "Node {} at {} attempting to join cluster",
+            node.id, node.addr
+        );
+        // we are packing up the store data and the nodes connected in the cluster rn and send it
+        // to the client node so that it could also replicate
+        let nodes = self.lally.pool.peers();
+
+        // gossiping the client node
+        self.lally.pool.gossip(node.clone()).await;
+
+        self.lally.pool.conn_make(&node).map_err(|e| {
+            error!("Failed to connect to {}: {}", node.addr, e);
+            Status::invalid_argument(format!("Failed to connect to client: {}", e))
+        })?;
+
+        info!("Node {} successfully joined the cluster", node.id);
+
+        Ok(Response::new(JoinResponse {
+            message: "Joined successfully".to_string(),
+            nodes,
+            address: node.addr,
+            seed: Some(self.lally.pool.local_node()),
+            cluster_id,
+        }))
     }
 
     async fn remove_node(
         &self,
-        request: Request<NoContentRequest>,
+        request: Request<RemoveNodeRequest>,
     ) -> Result<Response<RemoveNodeResponse>, Status> {
-        if let Some(mut client_ip) = request.remote_addr() {
-            client_ip.set_port(self.grpc_port);
-            let client_ip = client_ip.to_string();
+        let request = request.into_inner();
+        let id = request.id;
 
-            info!("Attempting to remove node {}", client_ip);
+        info!("Attempting to remove node {}", id);
+        if request.skip_rereplication && self.lally.pool.get_ids().contains(&id) {
+            self.lally.rebalancer.hold_next();
+        }
 
-            self.lally.pool.remove(&client_ip).map_err(|err| {
-                error!("Failed to remove node {}: {}", client_ip, err);
-                Status::internal(format!("Failed to remove node: {}", err))
-            })?;
+        // gossip carries the departure to whoever the leaving node didn't reach itself
+        self.lally.membership.mark_left(&id);
+        self.lally.pool.remove(&id).map_err(|err| {
+            error!("Failed to remove node {}: {}", id, err);
+            Status::internal(format!("Failed to remove node: {}", err))
+        })?;
 
-            info!("Node {} removed successfully", client_ip);
+        info!("Node {} removed successfully", id);
 
-            return Ok(Response::new(RemoveNodeResponse {
-                message: "Node removed successfully".to_string(),
-            }));
-        } else {
-            error!("Failed to parse the client node address");
-            Err(Status::internal(
-                "Failed to remove node, failed to parse the client node address",
-            ))
-        }
+        Ok(Response::new(RemoveNodeResponse {
+            message: "Node removed successfully".to_string(),
+        }))
     }
 
     async fn add_node(
         &self,
         request: Request<AddNodeRequest>,
     ) -> Result<Response<AddNodeResponse>, Status> {
-        let new_commer = request.into_inner().ip;
+        let Some(mut new_commer) = request.into_inner().node else {
+            return Err(Status::invalid_argument("Missing node to add"));
+        };
+        // an older node gossiping a joiner doesn't say what it speaks
+        let protocol = new_commer.protocol.get_or_insert_with(Default::default);
+        if let Err(e) = protocol::check(protocol) {
+            error!("Refusing to add node {}: {:#}", new_commer.id, e);
+            return Err(Status::failed_precondition(format!(
+                "Incompatible protocol: {:#}",
+                e
+            )));
+        }
 
-        info!("Attempting to add node {}", new_commer);
+        info!(
+            "Attempting to add node {} at {}",
+            new_commer.id, new_commer.addr
+        );
 
-        self.lally.pool.conn_make(&new_commer).await.map_err(|e| {
-            error!("Failed to add node {}: {}", new_commer, e);
+        self.lally.pool.conn_make(&new_commer).map_err(|e| {
+            error!("Failed to add node {}: {}", new_commer.id, e);
             Status::invalid_argument(format!("Failed to add node: {}", e))
         })?;
 
-        info!("Node {} added successfully", new_commer);
+        info!("Node {} added successfully", new_commer.id);
 
         Ok(Response::new(AddNodeResponse {
             message: "Node added successfully".to_string(),
         }))
     }
+
+    type BootstrapStream = ReceiverStream<Result<BootstrapChunk, Status>>;
+
+    async fn bootstrap(
+        &self,
+        request: Request<BootstrapRequest>,
+    ) -> Result<Response<Self::BootstrapStream>, Status> {
+        let request = request.into_inner();
+        let receiver = self.lally.bootstrap.serve(
+            Arc::clone(&self.lally),
+            request.node_id,
+            request.after_key,
+            request.chunk_size as usize,
+            request.since,
+        );
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn stats(
+        &self,
+        _request: Request<NoContentRequest>,
+    ) -> Result<Response<NodeStats>, Status> {
+        Ok(Response::new(self.lally.stats.local(&self.lally).await))
+    }
+
+    async fn cluster_status(
+        &self,
+        _request: Request<NoContentRequest>,
+    ) -> Result<Response<ClusterStatusResponse>, Status> {
+        Ok(Response::new(Stats::cluster(&self.lally).await))
+    }
+
+    async fn ping(
+        &self,
+        _request: Request<NoContentRequest>,
+    ) -> Result<Response<PingResponse>, Status> {
+        Ok(Response::new(PingResponse {
+            message: "pong".to_string(),
+            protocol: Some(protocol::local()),
+            zone: self.lally.pool.local_zone(),
+        }))
+    }
+
+    async fn gossip(
+        &self,
+        request: Request<GossipRequest>,
+    ) -> Result<Response<GossipResponse>, Status> {
+        let request = request.into_inner();
+        // answer with our view as it was before merging, the sender already knows its own
+        let members = self.lally.membership.digest(&self.lally);
+        self.lally
+            .membership
+            .merge(&self.lally, request.members)
+            .await;
+        Ok(Response::new(GossipResponse { members }))
+    }
+
+    async fn ping_req(
+        &self,
+        request: Request<PingReqRequest>,
+    ) -> Result<Response<PingReqResponse>, Status> {
+        let target = request.into_inner().target;
+        let deadline = self.lally.membership.ping_timeout();
+        let reachable = matches!(
+            timeout(deadline, self.lally.pool.ping(&target)).await,
+            Ok(Ok(()))
+        );
+        Ok(Response::new(PingReqResponse { reachable }))
+    }
+}
+
+#[tonic::async_trait]
+impl Rebalance for GrpcServer {
+    async fn transfer(
+        &self,
+        request: Request<TransferRequest>,
+    ) -> Result<Response<TransferResponse>, Status> {
+        if self.lally.decommission.is_draining() {
+            return Err(Status::unavailable(DECOMMISSIONING));
+        }
+        let request = request.into_inner();
+        let accepted = request.entries.len() as u64;
+        info!(
+            "Receiving rebalance batch {} with {} entries",
+            request.batch, accepted
+        );
+
+        // only the entries that are newer than ours go to the hooks, so the aof stays lean
+        for operation in self.lally.store.import_store(request.entries) {
+            self.lally.hooks.invoke_all(&operation);
+        }
+
+        Ok(Response::new(TransferResponse { accepted }))
+    }
+}
+
+#[tonic::async_trait]
+impl AntiEntropy for GrpcServer {
+    async fn merkle_nodes(
+        &self,
+        request: Request<MerkleNodesRequest>,
+    ) -> Result<Response<MerkleNodesResponse>, Status> {
+        let request = request.into_inner();
+        let hashes = self
+            .lally
+            .anti_entropy
+            .merkle_nodes(&self.lally, &request.peer, request.level, &request.indices)
+            .await
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        Ok(Response::new(MerkleNodesResponse { hashes }))
+    }
+
+    async fn sync_range(
+        &self,
+        request: Request<SyncRangeRequest>,
+    ) -> Result<Response<SyncRangeResponse>, Status> {
+        if self.lally.decommission.is_draining() {
+            return Err(Status::unavailable(DECOMMISSIONING));
+        }
+        let request = request.into_inner();
+        info!(
+            "Syncing {} ranges with {}",
+            request.buckets.len(),
+            request.peer
+        );
+        let entries = self.lally.anti_entropy.sync_range(
+            &self.lally,
+            &request.peer,
+            &request.buckets,
+            request.entries,
+        );
+
+        Ok(Response::new(SyncRangeResponse { entries }))
+    }
 }
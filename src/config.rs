@@ -1,11 +1,74 @@
 use anyhow::{bail, Context, Result};
 use argh::FromArgs;
 use directories::ProjectDirs;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::path::{Path, PathBuf};
-use tokio::fs::{canonicalize, copy, create_dir_all, read_to_string, OpenOptions};
+use std::str::FromStr;
+use tokio::fs::{canonicalize, copy, create_dir_all, read_to_string, write, OpenOptions};
 use tracing::{debug, info, warn};
 
+// how read and write quorums are picked: the configured numbers, or a majority of
+// whatever replicas the live cluster has
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum QuorumMode {
+    #[default]
+    Fixed,
+    Majority,
+}
+
+impl FromStr for QuorumMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "fixed" => Ok(QuorumMode::Fixed),
+            "majority" => Ok(QuorumMode::Majority),
+            other => Err(format!(
+                "unknown quorum mode {:?}, expected fixed or majority",
+                other
+            )),
+        }
+    }
+}
+
+// which records peer discovery looks up, plain addresses or addresses with ports
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DiscoveryRecord {
+    #[default]
+    A,
+    Srv,
+}
+
+// whether a read waits for the replicas it found stale to be fixed before it answers
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadRepairMode {
+    Blocking,
+    #[default]
+    Async,
+}
+
+// a secret from the config file, kept out of the debug output of the config
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(transparent)]
+struct Secret(String);
+
+impl Secret {
+    fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "***")
+    }
+}
+
 #[inline]
 fn default_r_quorum() -> usize {
     1
@@ -31,6 +94,186 @@ fn default_aof_flush_interval() -> u64 {
     100
 }
 
+#[inline]
+fn default_replication_factor() -> usize {
+    3
+}
+
+#[inline]
+fn default_virtual_nodes() -> usize {
+    128
+}
+
+#[inline]
+fn default_rebalance_batch_size() -> usize {
+    256
+}
+
+#[inline]
+fn default_rebalance_batch_interval() -> u64 {
+    50
+}
+
+#[inline]
+fn default_hint_ttl() -> u64 {
+    3 * 60 * 60
+}
+
+#[inline]
+fn default_hint_replay_interval() -> u64 {
+    10_000
+}
+
+#[inline]
+fn default_anti_entropy_interval() -> u64 {
+    60_000
+}
+
+#[inline]
+fn default_heartbeat_interval() -> u64 {
+    1000
+}
+
+#[inline]
+fn default_phi_suspect_threshold() -> f64 {
+    5.0
+}
+
+#[inline]
+fn default_phi_dead_threshold() -> f64 {
+    8.0
+}
+
+#[inline]
+fn default_gossip_interval() -> u64 {
+    1000
+}
+
+#[inline]
+fn default_indirect_probes() -> usize {
+    3
+}
+
+#[inline]
+fn default_suspect_timeout() -> u64 {
+    5000
+}
+
+#[inline]
+fn default_bootstrap_chunk_size() -> usize {
+    512
+}
+
+#[inline]
+fn default_decommission_hint_timeout() -> u64 {
+    30000
+}
+
+#[inline]
+fn default_bootstrap_retries() -> usize {
+    5
+}
+
+#[inline]
+fn default_tls_reload_interval() -> u64 {
+    30_000
+}
+
+#[inline]
+fn default_tls_handshake_timeout() -> u64 {
+    5000
+}
+
+#[inline]
+fn default_connect_timeout() -> u64 {
+    1000
+}
+
+#[inline]
+fn default_keepalive_interval() -> u64 {
+    10_000
+}
+
+#[inline]
+fn default_keepalive_timeout() -> u64 {
+    5000
+}
+
+#[inline]
+fn default_reconnect_backoff() -> u64 {
+    1000
+}
+
+#[inline]
+fn default_replication_batch_size() -> usize {
+    128
+}
+
+#[inline]
+fn default_replication_window() -> usize {
+    1024
+}
+
+#[inline]
+fn default_get_timeout() -> u64 {
+    1000
+}
+
+#[inline]
+fn default_add_timeout() -> u64 {
+    2000
+}
+
+#[inline]
+fn default_remove_timeout() -> u64 {
+    2000
+}
+
+#[inline]
+fn default_rpc_retries() -> usize {
+    2
+}
+
+#[inline]
+fn default_rpc_retry_backoff() -> u64 {
+    50
+}
+
+#[inline]
+fn default_read_repair_chance() -> f64 {
+    0.1
+}
+
+#[inline]
+fn default_auth_max_skew() -> u64 {
+    30_000
+}
+
+#[inline]
+fn default_join_retries() -> usize {
+    5
+}
+
+#[inline]
+fn default_join_backoff() -> u64 {
+    500
+}
+
+#[inline]
+fn default_discovery_interval() -> u64 {
+    30000
+}
+
+#[inline]
+fn default_script_max_operations() -> u64 {
+    100_000
+}
+
+#[inline]
+fn default_script_max_data_size() -> usize {
+    64 * 1024
+}
+
 #[derive(FromArgs)]
 /// A simple in memory kv store trying its best to be available
 pub struct CliArgs {
@@ -46,9 +289,13 @@ pub struct CliArgs {
     #[argh(option)]
     replay_log: Option<PathBuf>,
 
-    /// ipv4 address of seed node
+    /// address of a seed node, repeat for several
     #[argh(option)]
-    seed_node: Option<String>,
+    seed_node: Vec<String>,
+
+    /// dns name to discover peers through
+    #[argh(option)]
+    discovery_dns: Option<String>,
 
     /// custom port for http server, default is 3000
     #[argh(option)]
@@ -58,6 +305,30 @@ pub struct CliArgs {
     #[argh(option)]
     grpc_port: Option<u16>,
 
+    /// id of this node, generated and kept in the data directory if not given
+    #[argh(option)]
+    node_id: Option<String>,
+
+    /// address other nodes should use to reach this node's grpc server
+    #[argh(option)]
+    advertise_addr: Option<String>,
+
+    /// zone or rack this node runs in, replicas are spread across zones
+    #[argh(option)]
+    zone: Option<String>,
+
+    /// ca certificate that peers' certificates must be signed by
+    #[argh(option)]
+    tls_ca: Option<PathBuf>,
+
+    /// certificate this node presents to its peers
+    #[argh(option)]
+    tls_cert: Option<PathBuf>,
+
+    /// private key of the node certificate
+    #[argh(option)]
+    tls_key: Option<PathBuf>,
+
     /// read quorum value
     #[argh(option)]
     read_quorum: Option<usize>,
@@ -66,9 +337,21 @@ pub struct CliArgs {
     #[argh(option)]
     write_quorum: Option<usize>,
 
+    /// fixed to use the configured quorums, majority to derive them from the live cluster
+    #[argh(option)]
+    quorum_mode: Option<QuorumMode>,
+
+    /// number of nodes each key is replicated to
+    #[argh(option)]
+    replication_factor: Option<usize>,
+
     /// aof flush interval in milliseconds
     #[argh(option)]
     aof_flush_interval: Option<u64>,
+
+    /// directory of rhai scripts to run on writes
+    #[argh(option)]
+    scripts_dir: Option<PathBuf>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -81,23 +364,200 @@ pub struct Config {
 
     seed_node: Option<String>,
 
+    #[serde(default)]
+    seed_nodes: Vec<String>,
+
+    #[serde(default = "default_join_retries")]
+    join_retries: usize,
+
+    #[serde(default = "default_join_backoff")]
+    join_backoff: u64,
+
+    #[serde(default)]
+    discovery_dns: Option<String>,
+
+    #[serde(default)]
+    discovery_record: DiscoveryRecord,
+
+    #[serde(default)]
+    discovery_port: Option<u16>,
+
+    #[serde(default)]
+    discovery_dns_server: Option<String>,
+
+    #[serde(default = "default_discovery_interval")]
+    discovery_interval: u64,
+
     #[serde(default = "default_http_port")]
     http_port: u16,
 
     #[serde(default = "default_grpc_port")]
     grpc_port: u16,
 
+    #[serde(default)]
+    node_id: String,
+
+    #[serde(default)]
+    advertise_addr: Option<String>,
+
+    #[serde(default)]
+    zone: Option<String>,
+
+    #[serde(default)]
+    tls_ca: Option<PathBuf>,
+
+    #[serde(default)]
+    tls_cert: Option<PathBuf>,
+
+    #[serde(default)]
+    tls_key: Option<PathBuf>,
+
+    #[serde(default)]
+    tls_server_name: Option<String>,
+
+    #[serde(default = "default_tls_reload_interval")]
+    tls_reload_interval: u64,
+
+    #[serde(default = "default_tls_handshake_timeout")]
+    tls_handshake_timeout: u64,
+
+    #[serde(default)]
+    cluster_secret: Option<Secret>,
+
+    #[serde(default)]
+    kv_secret: Option<Secret>,
+
+    // guards the admin http routes, defaults to cluster_secret
+    #[serde(default)]
+    admin_secret: Option<Secret>,
+
+    #[serde(default = "default_auth_max_skew")]
+    auth_max_skew: u64,
+
     #[serde(default = "default_r_quorum")]
     read_quorum: usize,
 
     #[serde(default = "default_w_quorum")]
     write_quorum: usize,
 
+    #[serde(default)]
+    quorum_mode: QuorumMode,
+
+    #[serde(default = "default_connect_timeout")]
+    connect_timeout: u64,
+
+    #[serde(default = "default_keepalive_interval")]
+    keepalive_interval: u64,
+
+    #[serde(default = "default_keepalive_timeout")]
+    keepalive_timeout: u64,
+
+    #[serde(default = "default_reconnect_backoff")]
+    reconnect_backoff: u64,
+
+    // 0 never evicts, peers stay until an admin removes them
+    #[serde(default)]
+    peer_evict_timeout: u64,
+
+    #[serde(default = "default_replication_batch_size")]
+    replication_batch_size: usize,
+
+    // 0 sends whatever has queued up right away
+    #[serde(default)]
+    replication_linger: u64,
+
+    #[serde(default = "default_replication_window")]
+    replication_window: usize,
+
+    #[serde(default = "default_get_timeout")]
+    get_timeout: u64,
+
+    #[serde(default = "default_add_timeout")]
+    add_timeout: u64,
+
+    #[serde(default = "default_remove_timeout")]
+    remove_timeout: u64,
+
+    #[serde(default = "default_rpc_retries")]
+    rpc_retries: usize,
+
+    #[serde(default = "default_rpc_retry_backoff")]
+    rpc_retry_backoff: u64,
+
+    #[serde(default)]
+    read_repair_mode: ReadRepairMode,
+
+    #[serde(default = "default_read_repair_chance")]
+    read_repair_chance: f64,
+
+    #[serde(default = "default_replication_factor")]
+    replication_factor: usize,
+
+    #[serde(default = "default_virtual_nodes")]
+    virtual_nodes: usize,
+
+    #[serde(default = "default_rebalance_batch_size")]
+    rebalance_batch_size: usize,
+
+    #[serde(default = "default_rebalance_batch_interval")]
+    rebalance_batch_interval: u64,
+
+    #[serde(default = "default_hint_ttl")]
+    hint_ttl: u64,
+
+    #[serde(default = "default_hint_replay_interval")]
+    hint_replay_interval: u64,
+
+    #[serde(default = "default_anti_entropy_interval")]
+    anti_entropy_interval: u64,
+
+    #[serde(default = "default_heartbeat_interval")]
+    heartbeat_interval: u64,
+
+    #[serde(default = "default_phi_suspect_threshold")]
+    phi_suspect_threshold: f64,
+
+    #[serde(default = "default_phi_dead_threshold")]
+    phi_dead_threshold: f64,
+
+    #[serde(default = "default_gossip_interval")]
+    gossip_interval: u64,
+
+    #[serde(default = "default_indirect_probes")]
+    indirect_probes: usize,
+
+    #[serde(default = "default_suspect_timeout")]
+    suspect_timeout: u64,
+
+    #[serde(default = "default_bootstrap_chunk_size")]
+    bootstrap_chunk_size: usize,
+
+    #[serde(default = "default_bootstrap_retries")]
+    bootstrap_retries: usize,
+
+    #[serde(default = "default_decommission_hint_timeout")]
+    decommission_hint_timeout: u64,
+
     #[serde(skip)]
     aof_storage_path: PathBuf,
 
+    #[serde(skip)]
+    hints_dir: PathBuf,
+
+    #[serde(skip)]
+    cluster_state_path: PathBuf,
+
     #[serde(default = "default_aof_flush_interval")]
     aof_flush_interval: u64,
+
+    #[serde(default)]
+    scripts_dir: Option<PathBuf>,
+
+    #[serde(default = "default_script_max_operations")]
+    script_max_operations: u64,
+
+    #[serde(default = "default_script_max_data_size")]
+    script_max_data_size: usize,
 }
 
 impl Config {
@@ -117,9 +577,21 @@ impl Config {
             info!("Replay log file set: {:?}", path);
             config.replay_log = Some(path);
         }
-        if let Some(addr) = cli_args.seed_node {
-            info!("Seed node address: {}", addr);
-            config.seed_node = Some(addr);
+        // the single seed_node of older configs goes first
+        if let Some(addr) = config.seed_node.take() {
+            if !config.seed_nodes.contains(&addr) {
+                config.seed_nodes.insert(0, addr);
+            }
+        }
+        if !cli_args.seed_node.is_empty() {
+            config.seed_nodes = cli_args.seed_node;
+        }
+        if !config.seed_nodes.is_empty() {
+            info!("Seed node addresses: {:?}", config.seed_nodes);
+        }
+        if let Some(name) = cli_args.discovery_dns {
+            info!("Discovering peers through DNS name: {}", name);
+            config.discovery_dns = Some(name);
         }
         if let Some(http_port) = cli_args.http_port {
             config.http_port = http_port;
@@ -135,7 +607,50 @@ impl Config {
         if let Some(grpc_port) = cli_args.grpc_port {
             config.grpc_port = grpc_port;
             info!("GRPC server port set to: {}", grpc_port);
-            warn!("This assumes that all nodes in the cluster will use the same port for the gRPC server.");
+        }
+        if let Some(node_id) = cli_args.node_id {
+            info!("Node id set to: {}", node_id);
+            config.node_id = node_id;
+        }
+        if let Some(advertise_addr) = cli_args.advertise_addr {
+            info!("Advertised address set to: {}", advertise_addr);
+            config.advertise_addr = Some(advertise_addr);
+        }
+        if let Some(zone) = cli_args.zone {
+            info!("Zone set to: {}", zone);
+            config.zone = Some(zone);
+        }
+        if let Some(tls_ca) = cli_args.tls_ca {
+            info!("TLS CA certificate set to: {:?}", tls_ca);
+            config.tls_ca = Some(tls_ca);
+        }
+        if let Some(tls_cert) = cli_args.tls_cert {
+            info!("TLS certificate set to: {:?}", tls_cert);
+            config.tls_cert = Some(tls_cert);
+        }
+        if let Some(tls_key) = cli_args.tls_key {
+            info!("TLS key set to: {:?}", tls_key);
+            config.tls_key = Some(tls_key);
+        }
+        let tls_files = [&config.tls_ca, &config.tls_cert, &config.tls_key];
+        if tls_files.iter().any(|file| file.is_some())
+            && !tls_files.iter().all(|file| file.is_some())
+        {
+            bail!("TLS needs all of tls_ca, tls_cert and tls_key to be set");
+        }
+        if config.tls_ca.is_none() {
+            warn!("TLS is not configured; gRPC traffic between nodes is sent in plaintext.");
+        }
+        if config.cluster_secret.is_none() {
+            warn!(
+                "No cluster secret set; anyone who can reach the gRPC port can join the cluster."
+            );
+        }
+        if config.admin_secret.is_none() && config.cluster_secret.is_none() {
+            warn!("No admin secret set; admin HTTP routes only accept requests from localhost.");
+        }
+        if config.advertise_addr.is_none() {
+            warn!("No advertised address set; peers will reach this node on the address they see its requests coming from.");
         }
         if let Some(read_quorum) = cli_args.read_quorum {
             config.read_quorum = read_quorum;
@@ -145,14 +660,67 @@ impl Config {
             config.write_quorum = write_quorum;
             info!("Write quorum set to: {}", write_quorum);
         }
+        if let Some(quorum_mode) = cli_args.quorum_mode {
+            config.quorum_mode = quorum_mode;
+            info!("Quorum mode set to: {:?}", quorum_mode);
+        }
+
+        if let Some(replication_factor) = cli_args.replication_factor {
+            config.replication_factor = replication_factor;
+            info!("Replication factor set to: {}", replication_factor);
+        }
+        config.validate_quorum()?;
+        if !(0.0..=1.0).contains(&config.read_repair_chance) {
+            bail!("read_repair_chance must be between 0 and 1");
+        }
+        if let Some(scripts_dir) = cli_args.scripts_dir {
+            info!("Scripts directory set to: {:?}", scripts_dir);
+            config.scripts_dir = Some(scripts_dir);
+        }
 
         config.initialize_log_file().await?;
+        config.initialize_node_id().await?;
+        config.initialize_scripts_dir()?;
 
         debug!("Final configuration: {:?}", config);
 
         Ok(config)
     }
 
+    // a quorum of zero waits for nobody and one above the replication factor can never be
+    // met, both are refused before the node starts taking requests
+    fn validate_quorum(&self) -> Result<()> {
+        if self.replication_factor == 0 {
+            bail!("replication_factor must be at least 1");
+        }
+        if self.quorum_mode == QuorumMode::Majority {
+            info!("Quorums follow a majority of the live replicas; read_quorum and write_quorum are ignored");
+            return Ok(());
+        }
+        for (name, quorum) in [
+            ("read_quorum", self.read_quorum),
+            ("write_quorum", self.write_quorum),
+        ] {
+            if quorum == 0 {
+                bail!("{} must be at least 1", name);
+            }
+            if quorum > self.replication_factor {
+                bail!(
+                    "{} of {} can never be met with a replication factor of {}",
+                    name,
+                    quorum,
+                    self.replication_factor
+                );
+            }
+        }
+        if self.read_quorum + self.write_quorum <= self.replication_factor {
+            warn!(
+                "read_quorum + write_quorum doesn't exceed the replication factor; reads may miss the latest write."
+            );
+        }
+        Ok(())
+    }
+
     async fn initialize_log_file(&mut self) -> Result<()> {
         // Get project directory
         let project_dirs = ProjectDirs::from("com", "Lally", "Lally")
@@ -167,6 +735,8 @@ impl Config {
         let mut aof_storage_path = project_dirs.data_dir().to_path_buf();
         aof_storage_path.push("aof.txt");
         self.aof_storage_path = aof_storage_path;
+        self.hints_dir = project_dirs.data_dir().join("hints");
+        self.cluster_state_path = project_dirs.data_dir().join("cluster.json");
 
         if self.fresh && self.replay_log.is_some() {
             bail!("Don't specify replay log file when starting fresh");
@@ -204,6 +774,40 @@ impl Config {
         Ok(())
     }
 
+    // the node id outlives restarts and address changes, so it's kept next to the data
+    async fn initialize_node_id(&mut self) -> Result<()> {
+        if !self.node_id.is_empty() {
+            return Ok(());
+        }
+        let project_dirs = ProjectDirs::from("com", "Lally", "Lally")
+            .context("Could not find project directories")?;
+        let node_id_path = project_dirs.data_dir().join("node_id");
+
+        if node_id_path.exists() {
+            let node_id = read_to_string(&node_id_path)
+                .await
+                .context("Failed to read node id")?;
+            self.node_id = node_id.trim().to_string();
+        }
+        if self.node_id.is_empty() {
+            self.node_id = format!("{:016x}", rand::thread_rng().gen::<u64>());
+            write(&node_id_path, &self.node_id)
+                .await
+                .context("Failed to persist node id")?;
+            info!("Generated node id {}", self.node_id);
+        }
+        Ok(())
+    }
+
+    fn initialize_scripts_dir(&mut self) -> Result<()> {
+        if self.scripts_dir.is_none() {
+            let project_dirs = ProjectDirs::from("com", "Lally", "Lally")
+                .context("Could not find project directories")?;
+            self.scripts_dir = Some(project_dirs.config_dir().join("scripts"));
+        }
+        Ok(())
+    }
+
     async fn load_config_file(config_path: &Option<PathBuf>) -> Result<Self> {
         let config_path = if let Some(path) = config_path {
             path.clone()
@@ -231,8 +835,29 @@ impl Config {
     }
 
     // getters
-    pub fn seed_node(&self) -> Option<&str> {
-        self.seed_node.as_deref()
+    pub fn seed_nodes(&self) -> &[String] {
+        &self.seed_nodes
+    }
+    pub fn join_retries(&self) -> usize {
+        self.join_retries
+    }
+    pub fn join_backoff(&self) -> u64 {
+        self.join_backoff
+    }
+    pub fn discovery_dns(&self) -> Option<&str> {
+        self.discovery_dns.as_deref()
+    }
+    pub fn discovery_record(&self) -> DiscoveryRecord {
+        self.discovery_record
+    }
+    pub fn discovery_port(&self) -> u16 {
+        self.discovery_port.unwrap_or(self.grpc_port)
+    }
+    pub fn discovery_dns_server(&self) -> Option<&str> {
+        self.discovery_dns_server.as_deref()
+    }
+    pub fn discovery_interval(&self) -> u64 {
+        self.discovery_interval
     }
     pub fn http_port(&self) -> u16 {
         self.http_port
@@ -240,18 +865,169 @@ impl Config {
     pub fn grpc_port(&self) -> u16 {
         self.grpc_port
     }
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+    pub fn advertise_addr(&self) -> Option<&str> {
+        self.advertise_addr.as_deref()
+    }
+    // nodes without a zone all share the unnamed one
+    pub fn zone(&self) -> &str {
+        self.zone.as_deref().unwrap_or_default()
+    }
+    pub fn tls_ca(&self) -> Option<&Path> {
+        self.tls_ca.as_deref()
+    }
+    pub fn tls_cert(&self) -> Option<&Path> {
+        self.tls_cert.as_deref()
+    }
+    pub fn tls_key(&self) -> Option<&Path> {
+        self.tls_key.as_deref()
+    }
+    pub fn tls_server_name(&self) -> Option<&str> {
+        self.tls_server_name.as_deref()
+    }
+    pub fn tls_reload_interval(&self) -> u64 {
+        self.tls_reload_interval
+    }
+    pub fn tls_handshake_timeout(&self) -> u64 {
+        self.tls_handshake_timeout
+    }
+    pub fn cluster_secret(&self) -> Option<&str> {
+        self.cluster_secret.as_ref().map(Secret::expose)
+    }
+    pub fn kv_secret(&self) -> Option<&str> {
+        self.kv_secret.as_ref().map(Secret::expose)
+    }
+    pub fn admin_secret(&self) -> Option<&str> {
+        self.admin_secret.as_ref().map(Secret::expose)
+    }
+    pub fn auth_max_skew(&self) -> u64 {
+        self.auth_max_skew
+    }
     pub fn read_quorum(&self) -> usize {
         self.read_quorum
     }
     pub fn write_quorum(&self) -> usize {
         self.write_quorum
     }
+    pub fn quorum_mode(&self) -> QuorumMode {
+        self.quorum_mode
+    }
+    pub fn connect_timeout(&self) -> u64 {
+        self.connect_timeout
+    }
+    pub fn keepalive_interval(&self) -> u64 {
+        self.keepalive_interval
+    }
+    pub fn keepalive_timeout(&self) -> u64 {
+        self.keepalive_timeout
+    }
+    pub fn reconnect_backoff(&self) -> u64 {
+        self.reconnect_backoff
+    }
+    pub fn peer_evict_timeout(&self) -> u64 {
+        self.peer_evict_timeout
+    }
+    pub fn replication_batch_size(&self) -> usize {
+        self.replication_batch_size
+    }
+    pub fn replication_linger(&self) -> u64 {
+        self.replication_linger
+    }
+    pub fn replication_window(&self) -> usize {
+        self.replication_window
+    }
+    pub fn get_timeout(&self) -> u64 {
+        self.get_timeout
+    }
+    pub fn add_timeout(&self) -> u64 {
+        self.add_timeout
+    }
+    pub fn remove_timeout(&self) -> u64 {
+        self.remove_timeout
+    }
+    pub fn rpc_retries(&self) -> usize {
+        self.rpc_retries
+    }
+    pub fn rpc_retry_backoff(&self) -> u64 {
+        self.rpc_retry_backoff
+    }
+    pub fn read_repair_mode(&self) -> ReadRepairMode {
+        self.read_repair_mode
+    }
+    pub fn read_repair_chance(&self) -> f64 {
+        self.read_repair_chance
+    }
+    pub fn replication_factor(&self) -> usize {
+        self.replication_factor
+    }
+    pub fn virtual_nodes(&self) -> usize {
+        self.virtual_nodes
+    }
+    pub fn rebalance_batch_size(&self) -> usize {
+        self.rebalance_batch_size
+    }
+    pub fn rebalance_batch_interval(&self) -> u64 {
+        self.rebalance_batch_interval
+    }
+    pub fn hint_ttl(&self) -> u64 {
+        self.hint_ttl
+    }
+    pub fn hint_replay_interval(&self) -> u64 {
+        self.hint_replay_interval
+    }
+    pub fn anti_entropy_interval(&self) -> u64 {
+        self.anti_entropy_interval
+    }
+    pub fn heartbeat_interval(&self) -> u64 {
+        self.heartbeat_interval
+    }
+    pub fn phi_suspect_threshold(&self) -> f64 {
+        self.phi_suspect_threshold
+    }
+    pub fn phi_dead_threshold(&self) -> f64 {
+        self.phi_dead_threshold
+    }
+    pub fn gossip_interval(&self) -> u64 {
+        self.gossip_interval
+    }
+    pub fn indirect_probes(&self) -> usize {
+        self.indirect_probes
+    }
+    pub fn suspect_timeout(&self) -> u64 {
+        self.suspect_timeout
+    }
+    pub fn bootstrap_chunk_size(&self) -> usize {
+        self.bootstrap_chunk_size
+    }
+    pub fn bootstrap_retries(&self) -> usize {
+        self.bootstrap_retries
+    }
+    pub fn decommission_hint_timeout(&self) -> u64 {
+        self.decommission_hint_timeout
+    }
+    pub fn hints_dir(&self) -> &Path {
+        &self.hints_dir
+    }
     pub fn aof_file(&self) -> &Path {
         &self.aof_storage_path
     }
+    pub fn cluster_state_file(&self) -> &Path {
+        &self.cluster_state_path
+    }
     pub fn aof_flush_interval(&self) -> u64 {
         self.aof_flush_interval
     }
+    pub fn scripts_dir(&self) -> &Path {
+        self.scripts_dir.as_deref().unwrap_or(Path::new("scripts"))
+    }
+    pub fn script_max_operations(&self) -> u64 {
+        self.script_max_operations
+    }
+    pub fn script_max_data_size(&self) -> usize {
+        self.script_max_data_size
+    }
 }
 
 impl Default for Config {
@@ -260,12 +1036,86 @@ impl Default for Config {
             fresh: false,
             replay_log: None,
             seed_node: None,
+            seed_nodes: Vec::new(),
+            join_retries: default_join_retries(),
+            join_backoff: default_join_backoff(),
+            discovery_dns: None,
+            discovery_record: DiscoveryRecord::default(),
+            discovery_port: None,
+            discovery_dns_server: None,
+            discovery_interval: default_discovery_interval(),
             http_port: default_http_port(),
             grpc_port: default_grpc_port(),
+            node_id: String::new(),
+            advertise_addr: None,
+            zone: None,
+            tls_ca: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_server_name: None,
+            tls_reload_interval: default_tls_reload_interval(),
+            tls_handshake_timeout: default_tls_handshake_timeout(),
+            cluster_secret: None,
+            kv_secret: None,
+            admin_secret: None,
+            auth_max_skew: default_auth_max_skew(),
             read_quorum: default_r_quorum(),
             write_quorum: default_w_quorum(),
+            quorum_mode: QuorumMode::default(),
+            connect_timeout: default_connect_timeout(),
+            keepalive_interval: default_keepalive_interval(),
+            keepalive_timeout: default_keepalive_timeout(),
+            reconnect_backoff: default_reconnect_backoff(),
+            peer_evict_timeout: 0,
+            replication_batch_size: default_replication_batch_size(),
+            replication_linger: 0,
+            replication_window: default_replication_window(),
+            get_timeout: default_get_timeout(),
+            add_timeout: default_add_timeout(),
+            remove_timeout: default_remove_timeout(),
+            rpc_retries: default_rpc_retries(),
+            rpc_retry_backoff: default_rpc_retry_backoff(),
+            read_repair_mode: ReadRepairMode::default(),
+            read_repair_chance: default_read_repair_chance(),
+            replication_factor: default_replication_factor(),
+            virtual_nodes: default_virtual_nodes(),
+            rebalance_batch_size: default_rebalance_batch_size(),
+            rebalance_batch_interval: default_rebalance_batch_interval(),
             aof_flush_interval: default_aof_flush_interval(),
+            hint_ttl: default_hint_ttl(),
+            hint_replay_interval: default_hint_replay_interval(),
+            anti_entropy_interval: default_anti_entropy_interval(),
+            heartbeat_interval: default_heartbeat_interval(),
+            phi_suspect_threshold: default_phi_suspect_threshold(),
+            phi_dead_threshold: default_phi_dead_threshold(),
+            gossip_interval: default_gossip_interval(),
+            indirect_probes: default_indirect_probes(),
+            suspect_timeout: default_suspect_timeout(),
+            bootstrap_chunk_size: default_bootstrap_chunk_size(),
+            bootstrap_retries: default_bootstrap_retries(),
+            decommission_hint_timeout: default_decommission_hint_timeout(),
             aof_storage_path: PathBuf::new(), // Will be initialized properly in new()
+            hints_dir: PathBuf::new(),
+            cluster_state_path: PathBuf::new(),
+            scripts_dir: None,
+            script_max_operations: default_script_max_operations(),
+            script_max_data_size: default_script_max_data_size(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_stay_out_of_the_debug_output() {
+        let config: Config =
+            serde_yaml::from_str("cluster_secret: hunter2\nkv_secret: swordfish").unwrap();
+        assert_eq!(config.cluster_secret(), Some("hunter2"));
+        assert_eq!(config.kv_secret(), Some("swordfish"));
+        let debug = format!("{:?}", config);
+        assert!(!debug.contains("hunter2") && !debug.contains("swordfish"));
+        assert!(debug.contains("cluster_secret: Some(***)"));
+    }
+}
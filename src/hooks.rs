@@ -1,7 +1,29 @@
 use crate::utils::Operation;
+use anyhow::Result;
+use std::fmt;
 pub mod aof;
+pub mod script;
+
+// a hook refusing an operation on purpose, as opposed to one that broke while looking at it
+#[derive(Debug)]
+pub struct Rejected(pub String);
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Rejected {}
 
 pub trait Hook: Send + Sync {
     // invoke fn might be async in future xD
     fn invoke(&self, operation: &Operation);
+
+    // runs before the operation touches the store, a hook may rewrite the operation or
+    // reject it by returning a `Rejected` error, any other error means the hook itself failed.
+    // Most hooks only observe, so this is a no-op by default
+    fn intercept(&self, _operation: &mut Operation) -> Result<()> {
+        Ok(())
+    }
 }
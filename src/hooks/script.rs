@@ -0,0 +1,216 @@
+use anyhow::{anyhow, bail, Context, Result};
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs::read_dir;
+use tracing::{debug, info, warn};
+
+use super::{Hook, Rejected};
+use crate::config::Config;
+use crate::utils::Operation;
+
+// every script must define this fn, it gets the operation as a map and can return a
+// modified map, return () to leave it untouched, or `throw` to reject the operation
+const ENTRYPOINT: &str = "on_operation";
+
+struct Script {
+    name: String,
+    ast: AST,
+}
+
+pub struct ScriptHook {
+    engine: Engine,
+    scripts: Vec<Script>,
+}
+
+impl Hook for ScriptHook {
+    fn invoke(&self, _operation: &Operation) {}
+
+    fn intercept(&self, operation: &mut Operation) -> Result<()> {
+        for script in self.scripts.iter() {
+            debug!(
+                "Running script {} for the {} operation",
+                script.name, operation.name
+            );
+            let mut scope = Scope::new();
+            let result = self
+                .engine
+                .call_fn::<Dynamic>(
+                    &mut scope,
+                    &script.ast,
+                    ENTRYPOINT,
+                    (operation_to_map(operation),),
+                )
+                .map_err(|e| match thrown(&e) {
+                    Some(reason) => anyhow::Error::new(Rejected(format!(
+                        "rejected by script {}: {}",
+                        script.name, reason
+                    ))),
+                    None => anyhow!("script {} failed: {}", script.name, e),
+                })?;
+            apply_script_result(operation, result)
+                .with_context(|| format!("script {} returned an invalid operation", script.name))?;
+        }
+        Ok(())
+    }
+}
+
+// the value a script threw, also from inside the fns it called. running into a sandbox
+// limit or a bug in the script isn't a throw
+fn thrown(error: &EvalAltResult) -> Option<&Dynamic> {
+    match error {
+        EvalAltResult::ErrorRuntime(reason, _) => Some(reason),
+        EvalAltResult::ErrorInFunctionCall(_, _, inner, _) => thrown(inner),
+        EvalAltResult::ErrorInModule(_, inner, _) => thrown(inner),
+        _ => None,
+    }
+}
+
+fn operation_to_map(operation: &Operation) -> Map {
+    let mut map = Map::new();
+    map.insert("name".into(), operation.name.clone().into());
+    map.insert("key".into(), operation.key.clone().into());
+    map.insert(
+        "value".into(),
+        operation
+            .value
+            .clone()
+            .map(Dynamic::from)
+            .unwrap_or(Dynamic::UNIT),
+    );
+    map.insert("level".into(), operation.level.clone().into());
+    let annotations: Map = operation
+        .annotations
+        .iter()
+        .map(|(k, v)| (k.as_str().into(), v.clone().into()))
+        .collect();
+    map.insert("annotations".into(), annotations.into());
+    map
+}
+
+fn apply_script_result(operation: &mut Operation, result: Dynamic) -> Result<()> {
+    if result.is_unit() {
+        return Ok(());
+    }
+    let map = result
+        .try_cast::<Map>()
+        .context("expected a map or () as the return value")?;
+
+    // the operation name and timestamp are owned by lally, scripts only get to touch the payload
+    if let Some(key) = map.get("key") {
+        let key = key
+            .clone()
+            .into_string()
+            .map_err(|t| anyhow!("key must be a string, got {}", t))?;
+        if key.is_empty() {
+            bail!("key must not be empty");
+        }
+        operation.key = key;
+    }
+    if let Some(value) = map.get("value") {
+        operation.value = if value.is_unit() {
+            None
+        } else {
+            Some(
+                value
+                    .clone()
+                    .into_string()
+                    .map_err(|t| anyhow!("value must be a string, got {}", t))?,
+            )
+        };
+    }
+    if let Some(level) = map.get("level") {
+        operation.level = level
+            .clone()
+            .into_string()
+            .map_err(|t| anyhow!("level must be a string, got {}", t))?;
+    }
+    if let Some(annotations) = map.get("annotations") {
+        let annotations = annotations
+            .clone()
+            .try_cast::<Map>()
+            .context("annotations must be a map")?;
+        for (k, v) in annotations {
+            operation.annotations.insert(k.to_string(), v.to_string());
+        }
+    }
+    Ok(())
+}
+
+impl ScriptHook {
+    fn sandboxed_engine(config: &Config) -> Engine {
+        let mut engine = Engine::new();
+        // no imports from the filesystem, no eval, and hard caps on work and data size
+        engine.set_module_resolver(DummyModuleResolver::new());
+        engine.set_max_modules(0);
+        engine.disable_symbol("eval");
+        engine.set_max_operations(config.script_max_operations());
+        engine.set_max_string_size(config.script_max_data_size());
+        engine.set_max_array_size(config.script_max_data_size());
+        engine.set_max_map_size(config.script_max_data_size());
+        engine.set_max_call_levels(32);
+        engine.set_max_expr_depths(64, 32);
+        // print and debug go to the log instead of stdout
+        engine.on_print(|text| info!(target: "lally::script", "{}", text));
+        engine.on_debug(|text, source, pos| {
+            let source = source.unwrap_or_default();
+            debug!(target: "lally::script", source, "{} at {}", text, pos)
+        });
+        engine
+    }
+
+    async fn load_scripts(engine: &Engine, dir: &Path) -> Result<Vec<Script>> {
+        let mut paths: Vec<PathBuf> = Vec::new();
+        let mut entries = read_dir(dir)
+            .await
+            .context("Failed to read scripts directory")?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "rhai") {
+                paths.push(path);
+            }
+        }
+        // scripts run in file name order, so users can chain them with numeric prefixes
+        paths.sort();
+
+        let mut scripts = Vec::with_capacity(paths.len());
+        for path in paths {
+            let name = path
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let ast = engine
+                .compile_file(path.clone())
+                .map_err(|e| anyhow!("Failed to compile script {}: {}", name, e))?;
+            if !ast.iter_functions().any(|f| f.name == ENTRYPOINT) {
+                warn!(
+                    "Script {} doesn't define {}(op), skipping it",
+                    name, ENTRYPOINT
+                );
+                continue;
+            }
+            info!("Loaded script {}", name);
+            scripts.push(Script { name, ast });
+        }
+        Ok(scripts)
+    }
+
+    pub async fn init(config: &Config) -> Result<Option<Arc<Self>>> {
+        let dir = config.scripts_dir();
+        if !dir.is_dir() {
+            debug!("No scripts directory found at {:?}", dir);
+            return Ok(None);
+        }
+
+        info!("Loading scripts from {:?}", dir);
+        let engine = Self::sandboxed_engine(config);
+        let scripts = Self::load_scripts(&engine, dir).await?;
+        if scripts.is_empty() {
+            info!("No scripts to load from {:?}", dir);
+            return Ok(None);
+        }
+
+        Ok(Some(Arc::new(ScriptHook { engine, scripts })))
+    }
+}
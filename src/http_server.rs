@@ -1,18 +1,29 @@
 use crate::cluster::services::GetKvResponse;
 use crate::config::Config;
+use crate::hooks::Rejected;
+use crate::lally::consistency::{Consistency, Quorum};
+use crate::lally::decommission::Decommission;
+use crate::lally::membership::{MemberState, Membership};
+use crate::lally::pool::{Placement, Replies};
+use crate::lally::protocol::Feature;
+use crate::lally::read_repair::{self, ReadRepair};
+use crate::lally::stats::Stats;
 use crate::lally::Lally;
-use crate::utils::timestamp::{compare_timestamps, create_timestamp, timestamp_to_rfc3339};
-use crate::utils::Operation;
-use actix_web::{web, App, HttpResponse, HttpServer, Responder};
+use crate::utils::timestamp::{create_timestamp, timestamp_to_rfc3339};
+use crate::utils::{KVResult, Operation};
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder};
 use serde::Deserialize;
-use serde_json::json;
+use serde_json::{json, Value};
+use std::collections::HashMap;
 use std::sync::Arc;
-use tracing::{debug, info, span, warn, Level};
+use tracing::{debug, error, info, span, warn, Level};
 
 #[derive(Deserialize)]
 pub struct Payload {
     pub key: String,
     pub value: Option<String>,
+    // overrides the configured quorum for this one request
+    pub consistency: Option<Consistency>,
 }
 
 // helper utility to convert the payload to an operation struct, which will be used across all key-value operations
@@ -23,22 +34,145 @@ fn build_operation(payload: &Payload, operation_type: &str) -> Operation {
         level: String::from("INFO"),
         name: String::from(operation_type),
         timestamp: create_timestamp(),
+        annotations: HashMap::new(),
     }
 }
 
+// writes taken now would only have to be handed off again
+fn decommissioning(lally: &Lally, key: &str) -> HttpResponse {
+    HttpResponse::ServiceUnavailable().json(json!({
+        "status": "error",
+        "key": key,
+        "message": "Node is being decommissioned, send writes to another node.",
+        "decommission": lally.decommission.progress()
+    }))
+}
+
+// the cluster took this node out, whatever it serves now is out of date or lost
+fn removed(key: &str) -> HttpResponse {
+    HttpResponse::ServiceUnavailable().json(json!({
+        "status": "error",
+        "key": key,
+        "message": "Node was removed from the cluster, restart it to join again or ask another node."
+    }))
+}
+
+// response for operations a hook didn't let through, only a deliberate rejection is the
+// client's fault
+fn rejected(operation: &Operation, reason: anyhow::Error) -> HttpResponse {
+    if reason.downcast_ref::<Rejected>().is_some() {
+        warn!(key = %operation.key, "{} operation rejected by hooks: {:#}", operation.name, reason);
+        return HttpResponse::Forbidden().json(json!({
+            "status": "error",
+            "key": operation.key,
+            "message": format!("Operation rejected: {:#}", reason)
+        }));
+    }
+    error!(key = %operation.key, "{} operation failed in hooks: {:#}", operation.name, reason);
+    HttpResponse::InternalServerError().json(json!({
+        "status": "error",
+        "key": operation.key,
+        "message": format!("Operation failed in a hook: {:#}", reason)
+    }))
+}
+
+// what a request waits for from the key's replicas, from its own consistency level if it
+// brought one
+fn required_quorum(
+    lally: &Lally,
+    payload: &Payload,
+    configured_quorum: anyhow::Result<usize>,
+    placement: &Placement,
+) -> Result<Quorum, HttpResponse> {
+    let Some(consistency) = payload.consistency else {
+        // the cluster shrank below the configured quorum, nothing this request can fix
+        return configured_quorum.map(Quorum::votes).map_err(|e| {
+            warn!(key = %payload.key, "Refusing request: {:#}", e);
+            HttpResponse::ServiceUnavailable().json(json!({
+                "status": "error",
+                "key": payload.key,
+                "message": format!("{:#}", e)
+            }))
+        });
+    };
+    let replica_zones: Vec<&str> = placement.zones.values().map(String::as_str).collect();
+    consistency
+        .quorum(&replica_zones, &lally.pool.local_zone())
+        .map_err(|e| {
+            warn!(key = %payload.key, "Refusing request: {:#}", e);
+            HttpResponse::BadRequest().json(json!({
+                "status": "error",
+                "key": payload.key,
+                "message": format!("{:#}", e)
+            }))
+        })
+}
+
+// what the peers still have to make up for, once the local node had its say
+fn after_local(lally: &Lally, quorum: &Quorum, placement: &Placement) -> Quorum {
+    if placement.local {
+        quorum.after_vote(placement.zone(lally.pool.local_id()))
+    } else {
+        quorum.clone()
+    }
+}
+
+// required and achieved votes per zone, only for the levels that count them
+fn zone_votes(quorum: &Quorum, placement: &Placement, voters: &[String]) -> Option<Value> {
+    if quorum.zones.is_empty() {
+        return None;
+    }
+    let zones: serde_json::Map<String, Value> = quorum
+        .zones
+        .iter()
+        .map(|(zone, required)| {
+            let achieved = voters
+                .iter()
+                .filter(|voter| placement.zone(voter) == zone)
+                .count();
+            (
+                zone.clone(),
+                json!({ "required": required, "achieved": achieved }),
+            )
+        })
+        .collect();
+    Some(Value::Object(zones))
+}
+
+// a missed quorum, telling replicas that were too slow apart from ones that broke
+fn partial_message(timed_out: &[String], failed: &[String]) -> String {
+    format!(
+        "Partial quorum achieved; {} replicas timed out and {} failed to respond.",
+        timed_out.len(),
+        failed.len()
+    )
+}
+
 async fn get_nodes_addrs(lally: web::Data<Arc<Lally>>) -> impl Responder {
-    let node_addrs = lally.pool.get_addrs();
+    let nodes: Vec<_> = lally
+        .pool
+        .peers()
+        .into_iter()
+        .map(|node| {
+            json!({
+                "id": node.id,
+                "address": node.addr,
+                "zone": node.zone,
+                "state": lally.detector.state(&node.id),
+                "phi": lally.detector.phi(&node.id),
+                "membership": lally.membership.state(&node.id)
+            })
+        })
+        .collect();
     HttpResponse::Ok().json(json!({
         "status": "success",
-        "nodes": node_addrs
+        "id": lally.pool.local_id(),
+        "incarnation": lally.membership.incarnation(),
+        "nodes": nodes
     }))
 }
 
-async fn add_kv(
-    lally: web::Data<Arc<Lally>>,
-    config: web::Data<Config>,
-    payload: web::Json<Payload>,
-) -> impl Responder {
+async fn add_kv(lally: web::Data<Arc<Lally>>, payload: web::Json<Payload>) -> impl Responder {
     let trace_span = span!(Level::DEBUG, "ADD_KV");
     let _enter = trace_span.enter();
 
@@ -50,22 +184,55 @@ async fn add_kv(
         }));
     }
 
-    let operation = build_operation(&payload, "ADD");
+    if lally.membership.is_removed() {
+        return removed(&payload.key);
+    }
+    if lally.decommission.is_draining() {
+        return decommissioning(&lally, &payload.key);
+    }
+    let mut operation = build_operation(&payload, "ADD");
 
     debug!(key = %operation.key, "Incoming ADD operation");
-    lally.hooks.invoke_all(&operation);
-    let response = lally.store.add(&operation);
-    let response_timestamp = response
-        .timestamp
-        .expect("timestamp will be present for ADD operation");
-
-    debug!(key = %operation.key, "Added key to local store, timestamp: {}", response_timestamp);
+    if let Err(e) = lally.hooks.intercept_all(&mut operation) {
+        return rejected(&operation, e);
+    }
+    if operation.value.is_none() {
+        warn!(key = %operation.key, "Hooks dropped the value of an ADD operation");
+        return HttpResponse::BadRequest().json(json!({
+            "status": "error",
+            "message": "Operation was left without a value by hooks"
+        }));
+    }
+    // quorum is counted against the key's replicas, which may or may not include this node
+    let placement = lally.pool.placement(&operation.key);
+    let quorum = match required_quorum(&lally, &payload, lally.pool.write_quorum(), &placement) {
+        Ok(quorum) => quorum,
+        Err(response) => return response,
+    };
+    if placement.local {
+        lally.hooks.invoke_all(&operation);
+        lally.store.add(&operation);
+        debug!(key = %operation.key, "Added key to local store, timestamp: {}", operation.timestamp);
+    }
 
-    // write_quorum - 1 means leaving out the current local node
-    let needed_quorum_votes = config.write_quorum() - 1;
-    let cluster_responses = lally.pool.add_kv(&operation, needed_quorum_votes).await;
+    let Replies {
+        acked: mut voters,
+        timed_out,
+        failed,
+        ..
+    } = lally
+        .pool
+        .add_kv(
+            &operation,
+            &placement.peers,
+            &after_local(&lally, &quorum, &placement),
+        )
+        .await;
+    if placement.local {
+        voters.push(lally.pool.local_id().to_string());
+    }
 
-    let is_quorum_achieved = cluster_responses.len() == needed_quorum_votes;
+    let is_quorum_achieved = lally.pool.quorum_met(&quorum, &voters);
     let quorum_state = if is_quorum_achieved {
         "success"
     } else {
@@ -75,44 +242,79 @@ async fn add_kv(
     debug!(key = %operation.key, quorum_state = %quorum_state, "Key-Value add complete");
     HttpResponse::Ok().json(json!({
         "status": quorum_state,
-        "key": payload.key,
-        "value": payload.value,
-        "timestamp": timestamp_to_rfc3339(&response_timestamp),
+        "key": operation.key,
+        "value": operation.value,
+        "timestamp": timestamp_to_rfc3339(&operation.timestamp),
         "quorum": {
-            "required": config.write_quorum(),
-            "achieved": cluster_responses.len() + 1
+            "consistency": payload.consistency,
+            "required": quorum.required(),
+            "achieved": voters.len(),
+            "zones": zone_votes(&quorum, &placement, &voters),
+            "timed_out": timed_out,
+            "failed": failed
         },
         "message": if is_quorum_achieved {
-            "Operation completed successfully."
+            "Operation completed successfully.".to_string()
         } else {
-            "Partial quorum achieved; some nodes failed to respond."
-        }
+            partial_message(&timed_out, &failed)
+        },
+        "annotations": operation.annotations
     }))
 }
 
-async fn get_kv(
-    lally: web::Data<Arc<Lally>>,
-    config: web::Data<Config>,
-    payload: web::Json<Payload>,
-) -> impl Responder {
+async fn get_kv(lally: web::Data<Arc<Lally>>, payload: web::Json<Payload>) -> impl Responder {
     let trace_span = span!(Level::DEBUG, "GET_KV");
     let _enter = trace_span.enter();
 
     let operation = build_operation(&payload, "GET");
 
     debug!(key = %operation.key, "Incoming GET operation");
-    let needed_quorum_votes = config.read_quorum() - 1;
-
-    debug!(key = %operation.key, "Retrieving key from local store");
-    let get_op = lally.store.get(&operation);
-
-    let mut cluster_responses = lally.pool.get_kv(&operation, needed_quorum_votes).await;
-    let get_op_converted = GetKvResponse {
-        value: get_op.value,
-        timestamp: get_op.timestamp,
+    if lally.membership.is_removed() {
+        return removed(&operation.key);
+    }
+    if !lally.bootstrap.is_ready() {
+        return HttpResponse::ServiceUnavailable().json(json!({
+            "status": "error",
+            "key": operation.key,
+            "message": "Node is still bootstrapping, try again later or ask another node.",
+            "bootstrap": lally.bootstrap.progress()
+        }));
+    }
+    let placement = lally.pool.placement(&operation.key);
+    let quorum = match required_quorum(&lally, &payload, lally.pool.read_quorum(), &placement) {
+        Ok(quorum) => quorum,
+        Err(response) => return response,
     };
-    cluster_responses.push(("local".to_string(), get_op_converted));
-    let is_quorum_achieved = cluster_responses.len() == config.read_quorum();
+
+    let (
+        Replies {
+            responses: mut cluster_responses,
+            acked: mut voters,
+            timed_out,
+            failed,
+        },
+        late,
+    ) = lally
+        .pool
+        .get_kv(
+            &operation,
+            &placement.peers,
+            &after_local(&lally, &quorum, &placement),
+        )
+        .await;
+    if placement.local {
+        voters.push(lally.pool.local_id().to_string());
+        debug!(key = %operation.key, "Retrieving key from local store");
+        let get_op = lally.store.get(&operation);
+        cluster_responses.push((
+            read_repair::LOCAL.to_string(),
+            GetKvResponse {
+                value: get_op.value,
+                timestamp: get_op.timestamp,
+            },
+        ));
+    }
+    let is_quorum_achieved = lally.pool.quorum_met(&quorum, &voters);
 
     let quorum_state = if is_quorum_achieved {
         "success"
@@ -121,93 +323,37 @@ async fn get_kv(
     };
 
     debug!(key = %operation.key, quorum_state = %quorum_state);
-    // does read repair, prolly will be moved to a seperate function
-
-    let max_timestamp = cluster_responses
-        .iter()
-        .filter_map(|(_, response)| response.timestamp)
-        .max_by(compare_timestamps);
-
-    let nodes_with_latest_timestamp: Vec<&String> = cluster_responses
-        .iter()
-        .filter(|(_, response)| response.timestamp.as_ref() == max_timestamp.as_ref())
-        .map(|(ip, _)| ip)
-        .collect();
-
-    if let Some(latest_timestamp) = max_timestamp {
-        // Find the latest response (assuming all nodes with the latest timestamp have the same value)
-        if let Some((_, latest_response)) = cluster_responses
-            .iter()
-            .find(|(_, response)| response.timestamp.as_ref() == Some(&latest_timestamp))
-        {
-            debug!("Read repair triggered for nodes with outdated data");
-            // Replicate to nodes with older or missing timestamps
-            for (ip, _) in &cluster_responses {
-                if nodes_with_latest_timestamp.contains(&ip) {
-                    // Skip nodes with the latest timestamp
-                    continue;
-                }
-                let read_repair_operation = Operation {
-                    key: operation.key.to_string(),
-                    value: latest_response.value.clone(),
-                    name: String::from(if latest_response.value.is_some() {
-                        // if it is None, the the latest operation occured on the key is REMOVE, so we
-                        // need to remove, the kv on other nodes too, if else the key is
-                        // Some(value), then we should ADD that to other nodes
-                        "ADD"
-                    } else {
-                        "REMOVE"
-                    }),
-                    timestamp: latest_timestamp,
-                    level: String::from("INFO"),
-                };
-                match &latest_response.value {
-                    Some(_) => {
-                        let lally_clone = Arc::clone(&lally);
-                        let ip = ip.clone();
-                        tokio::spawn(async move {
-                            if ip == "local" {
-                                // special case if the local node itself needs to be repaired
-                                let _ = lally_clone.store.add(&read_repair_operation);
-                            } else {
-                                lally_clone
-                                    .pool
-                                    .solo_add_kv(&read_repair_operation, &ip)
-                                    .await;
-                            }
-                        });
-                    }
-                    None => {
-                        let lally_clone = Arc::clone(&lally);
-                        let ip = ip.clone();
-                        tokio::spawn(async move {
-                            if ip == "local" {
-                                let _ = lally_clone.store.remove(&read_repair_operation);
-                            } else {
-                                lally_clone
-                                    .pool
-                                    .solo_remove_kv(&read_repair_operation, &ip)
-                                    .await;
-                            }
-                        });
-                    }
-                }
-            }
+    // fixes the replicas that answered with old data, and sometimes the ones that didn't answer
+    ReadRepair::repair(&lally, &operation.key, &cluster_responses).await;
+    ReadRepair::maybe_read_all(&lally, &operation.key, cluster_responses.len());
+    if let Some(late) = late {
+        let lally = Arc::clone(lally.get_ref());
+        let key = operation.key.clone();
+        let answered = cluster_responses.clone();
+        tokio::spawn(async move {
+            let late = late.collect().await;
+            ReadRepair::repair_late(&lally, &key, &answered, late).await;
+        });
+    }
 
-            if let Some(value) = &latest_response.value {
-                debug!(key = %operation.key, "Key '{}' found with value '{}'", &operation.key, &value);
-                return HttpResponse::Ok().json(json!({
-                        "status": quorum_state,
-                        "key": operation.key,
-                        "value": value,
-                        "timestamp": timestamp_to_rfc3339(&latest_timestamp),
-                        "quorum": {
-                            "required": config.read_quorum(),
-                            "achieved": cluster_responses.len()
-                        },
-                        "message": format!("Key '{}' was fetched successfully.", operation.key)
-                }));
-            }
+    if let Some(latest) = read_repair::newest(&cluster_responses) {
+        if let (Some(value), Some(latest_timestamp)) = (&latest.value, &latest.timestamp) {
+            debug!(key = %operation.key, "Key '{}' found with value '{}'", &operation.key, &value);
+            return HttpResponse::Ok().json(json!({
+                    "status": quorum_state,
+                    "key": operation.key,
+                    "value": value,
+                    "timestamp": timestamp_to_rfc3339(latest_timestamp),
+                    "quorum": {
+                        "consistency": payload.consistency,
+                        "required": quorum.required(),
+                        "achieved": cluster_responses.len(),
+                        "zones": zone_votes(&quorum, &placement, &voters),
+                        "timed_out": timed_out,
+                        "failed": failed
+                    },
+                    "message": format!("Key '{}' was fetched successfully.", operation.key)
+            }));
         }
     }
 
@@ -218,35 +364,70 @@ async fn get_kv(
         "value": null,
         "timestamp": null,
         "quorum": {
-            "required": config.read_quorum(),
-            "achieved": cluster_responses.len()
+            "consistency": payload.consistency,
+            "required": quorum.required(),
+            "achieved": cluster_responses.len(),
+            "zones": zone_votes(&quorum, &placement, &voters),
+            "timed_out": timed_out,
+            "failed": failed
         },
         "message": format!("Key '{}' does not exist or quorum may not be reached", operation.key)
     }))
 }
 
-async fn remove_kv(
-    lally: web::Data<Arc<Lally>>,
-    config: web::Data<Config>,
-    payload: web::Json<Payload>,
-) -> impl Responder {
+async fn remove_kv(lally: web::Data<Arc<Lally>>, payload: web::Json<Payload>) -> impl Responder {
     let trace_span = span!(Level::DEBUG, "REMOVE_KV");
     let _enter = trace_span.enter();
 
-    let operation = build_operation(&payload, "REMOVE");
+    if lally.membership.is_removed() {
+        return removed(&payload.key);
+    }
+    if lally.decommission.is_draining() {
+        return decommissioning(&lally, &payload.key);
+    }
+    let mut operation = build_operation(&payload, "REMOVE");
 
     debug!(key = %operation.key, "Incoming REMOVE operation");
 
-    lally.hooks.invoke_all(&operation);
-
-    debug!("Attempting to remove key from local node");
-    let remove_response = lally.store.remove(&operation);
+    if let Err(e) = lally.hooks.intercept_all(&mut operation) {
+        return rejected(&operation, e);
+    }
 
-    let needed_quorum_votes = config.write_quorum() - 1;
+    let placement = lally.pool.placement(&operation.key);
+    let quorum = match required_quorum(&lally, &payload, lally.pool.write_quorum(), &placement) {
+        Ok(quorum) => quorum,
+        Err(response) => return response,
+    };
+    let remove_response = if placement.local {
+        lally.hooks.invoke_all(&operation);
+        debug!("Attempting to remove key from local node");
+        lally.store.remove(&operation)
+    } else {
+        KVResult {
+            success: false,
+            value: None,
+            timestamp: None,
+        }
+    };
 
-    let cluster_responses = lally.pool.remove_kv(&operation, needed_quorum_votes).await;
+    let Replies {
+        responses: cluster_responses,
+        acked: mut voters,
+        timed_out,
+        failed,
+    } = lally
+        .pool
+        .remove_kv(
+            &operation,
+            &placement.peers,
+            &after_local(&lally, &quorum, &placement),
+        )
+        .await;
+    if placement.local {
+        voters.push(lally.pool.local_id().to_string());
+    }
 
-    let is_quorum_achieved = cluster_responses.len() == needed_quorum_votes;
+    let is_quorum_achieved = lally.pool.quorum_met(&quorum, &voters);
     let quorum_state = if is_quorum_achieved {
         "success"
     } else {
@@ -287,10 +468,206 @@ async fn remove_kv(
             None
         },
         "quorum": {
-            "required": config.write_quorum(),
-            "achieved": cluster_responses.len() + 1
+            "consistency": payload.consistency,
+            "required": quorum.required(),
+            "achieved": voters.len(),
+            "zones": zone_votes(&quorum, &placement, &voters),
+            "timed_out": timed_out,
+            "failed": failed
         },
-        "message": message
+        "message": message,
+        "annotations": operation.annotations
+    }))
+}
+
+async fn get_rebalance_progress(lally: web::Data<Arc<Lally>>) -> impl Responder {
+    HttpResponse::Ok().json(json!({
+        "status": "success",
+        "rebalance": lally.rebalancer.progress()
+    }))
+}
+
+async fn get_bootstrap_progress(lally: web::Data<Arc<Lally>>) -> impl Responder {
+    HttpResponse::Ok().json(json!({
+        "status": "success",
+        "ready": lally.bootstrap.is_ready(),
+        "bootstrap": lally.bootstrap.progress()
+    }))
+}
+
+async fn get_cluster_status(lally: web::Data<Arc<Lally>>) -> impl Responder {
+    let status = Stats::cluster(&lally).await;
+    let nodes: Vec<_> = status
+        .nodes
+        .into_iter()
+        .map(|node| {
+            let state = MemberState::from(node.state());
+            json!({
+                "id": node.id,
+                "address": node.addr,
+                "zone": node.stats.as_ref().map(|stats| stats.zone.clone()),
+                "state": state,
+                "last_contact": node.last_contact.as_ref().map(timestamp_to_rfc3339),
+                "channel": node.channel.as_ref().map(|channel| json!({
+                    "state": channel.state,
+                    "failures": channel.failures,
+                    "last_ok": channel.last_ok.as_ref().map(timestamp_to_rfc3339),
+                    "error": (!channel.error.is_empty()).then_some(channel.error.clone())
+                })),
+                "version": node.stats.as_ref().map(|stats| stats.version.clone()),
+                "uptime_ms": node.stats.as_ref().map(|stats| stats.uptime_ms),
+                "keys": node.stats.as_ref().map(|stats| stats.keys),
+                "tombstones": node.stats.as_ref().map(|stats| stats.tombstones),
+                "aof_bytes": node.stats.as_ref().map(|stats| stats.aof_bytes),
+                "quorums": node.stats.as_ref().map(|stats| json!({
+                    "read": stats.read_quorum,
+                    "write": stats.write_quorum,
+                    "mode": stats.quorum_mode,
+                    "replication_factor": stats.replication_factor
+                })),
+                "cluster_id": node.stats.as_ref().map(|stats| stats.cluster_id.clone()),
+                "protocol": node.stats.as_ref().and_then(|stats| stats.protocol.as_ref()).map(|protocol| json!({
+                    "version": protocol.version,
+                    "min_version": protocol.min_version,
+                    "features": protocol.features
+                })),
+                "error": (!node.error.is_empty()).then_some(node.error)
+            })
+        })
+        .collect();
+    HttpResponse::Ok().json(json!({
+        "status": "success",
+        "cluster_id": status.cluster_id,
+        "features": lally.pool.cluster_features(),
+        "nodes": nodes
+    }))
+}
+
+// routes that change the cluster need the admin secret as a bearer token
+fn authorize_admin(lally: &Lally, request: &HttpRequest) -> Result<(), HttpResponse> {
+    let token = request
+        .headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let local = request
+        .peer_addr()
+        .is_some_and(|addr| addr.ip().is_loopback());
+    lally.auth.verify_admin(token, local).map_err(|reason| {
+        warn!(peer = ?request.peer_addr(), "Refused admin request: {}", reason);
+        HttpResponse::Unauthorized().json(json!({
+            "status": "error",
+            "message": format!("Unauthorized: {}", reason)
+        }))
+    })
+}
+
+async fn start_decommission(lally: web::Data<Arc<Lally>>, request: HttpRequest) -> impl Responder {
+    if let Err(response) = authorize_admin(&lally, &request) {
+        return response;
+    }
+    if !Decommission::start(&lally, "http") {
+        return HttpResponse::Conflict().json(json!({
+            "status": "error",
+            "message": "Decommission is already running",
+            "decommission": lally.decommission.progress()
+        }));
+    }
+    HttpResponse::Accepted().json(json!({
+        "status": "success",
+        "message": "Decommission started",
+        "decommission": lally.decommission.progress()
+    }))
+}
+
+async fn get_decommission_progress(lally: web::Data<Arc<Lally>>) -> impl Responder {
+    HttpResponse::Ok().json(json!({
+        "status": "success",
+        "draining": lally.decommission.is_draining(),
+        "decommission": lally.decommission.progress()
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct RemoveNodeParams {
+    // rebalance the removed node's keys onto the remaining ones, on by default
+    pub rereplicate: Option<bool>,
+    // remove a node even though it still looks alive
+    pub force: Option<bool>,
+}
+
+async fn remove_cluster_node(
+    lally: web::Data<Arc<Lally>>,
+    id: web::Path<String>,
+    params: web::Query<RemoveNodeParams>,
+    request: HttpRequest,
+) -> impl Responder {
+    if let Err(response) = authorize_admin(&lally, &request) {
+        return response;
+    }
+    let id = id.into_inner();
+    if id == lally.pool.local_id() {
+        return HttpResponse::BadRequest().json(json!({
+            "status": "error",
+            "id": id,
+            "message": "A node can't remove itself, use /decommission instead."
+        }));
+    }
+    if !lally.pool.get_ids().contains(&id) {
+        return HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "id": id,
+            "message": "Node is not part of the cluster"
+        }));
+    }
+    // a live node takes its data with it when it's decommissioned instead
+    if !params.force.unwrap_or(false) && !lally.detector.is_dead(&id) {
+        return HttpResponse::Conflict().json(json!({
+            "status": "error",
+            "id": id,
+            "message": "Node still looks alive, decommission it or pass force=true."
+        }));
+    }
+
+    let rereplicate = params.rereplicate.unwrap_or(true);
+    // an older node would rebalance anyway and leave the cluster disagreeing about it
+    if !rereplicate && !lally.pool.supports(Feature::SkipRereplication) {
+        return HttpResponse::Conflict().json(json!({
+            "status": "error",
+            "id": id,
+            "message": "Not every node supports skipping re-replication yet, finish the upgrade first."
+        }));
+    }
+    match Membership::remove_member(&lally, &id, rereplicate).await {
+        Ok(failed) => {
+            let failed: Vec<_> = failed
+                .into_iter()
+                .map(|(peer, error)| json!({ "id": peer, "error": error }))
+                .collect();
+            HttpResponse::Ok().json(json!({
+                "status": "success",
+                "id": id,
+                "message": "Node removed from the cluster",
+                "rereplicate": rereplicate,
+                "failed": failed
+            }))
+        }
+        Err(e) => HttpResponse::InternalServerError().json(json!({
+            "status": "error",
+            "id": id,
+            "message": format!("Failed to remove node: {:#}", e)
+        })),
+    }
+}
+
+async fn get_metrics(lally: web::Data<Arc<Lally>>) -> impl Responder {
+    HttpResponse::Ok().json(json!({
+        "status": "success",
+        "replication": lally.pool.replication_metrics(),
+        "replication_streams": lally.pool.stream_metrics(),
+        "read_repair": lally.read_repair.metrics(),
+        "hinted_handoff": lally.handoff.metrics().await,
+        "anti_entropy": lally.anti_entropy.metrics()
     }))
 }
 
@@ -312,6 +689,13 @@ pub async fn run(lally: Arc<Lally>, config: Config) -> std::io::Result<()> {
             .route("/get", web::post().to(get_kv))
             .route("/remove", web::delete().to(remove_kv))
             .route("/nodes", web::get().to(get_nodes_addrs))
+            .route("/cluster", web::get().to(get_cluster_status))
+            .route("/cluster/nodes/{id}", web::delete().to(remove_cluster_node))
+            .route("/decommission", web::post().to(start_decommission))
+            .route("/decommission", web::get().to(get_decommission_progress))
+            .route("/rebalance", web::get().to(get_rebalance_progress))
+            .route("/bootstrap", web::get().to(get_bootstrap_progress))
+            .route("/metrics", web::get().to(get_metrics))
             .route("/greet", web::get().to(greet))
     })
     .bind(addr)?
@@ -1,11 +1,42 @@
+pub mod anti_entropy;
+pub mod bootstrap;
+pub mod channels;
+pub mod cluster_state;
+pub mod consistency;
+pub mod decommission;
+pub mod detector;
+pub mod discovery;
+pub mod handoff;
 pub mod hook;
+pub mod membership;
 pub mod pool;
+pub mod protocol;
+pub mod read_repair;
+pub mod rebalance;
+pub mod replication;
+pub mod retry;
+pub mod ring;
+pub mod stats;
 pub mod store;
 
+use crate::auth::Auth;
 use crate::config::Config;
+use crate::tls::Tls;
+use anti_entropy::AntiEntropy;
 use anyhow::{Context, Result};
+use bootstrap::Bootstrap;
+use channels::Channels;
+use cluster_state::ClusterState;
+use decommission::Decommission;
+use detector::FailureDetector;
+use discovery::Discovery;
+use handoff::HintedHandoff;
 use hook::Hooks;
+use membership::Membership;
 use pool::Pool;
+use read_repair::ReadRepair;
+use rebalance::Rebalancer;
+use stats::Stats;
 use std::sync::Arc;
 use store::Store;
 use tokio::signal::ctrl_c;
@@ -19,10 +50,33 @@ pub struct Lally {
     pub store: Arc<Store>,
     pub hooks: Arc<Hooks>,
     pub pool: Arc<Pool>,
+    pub handoff: Arc<HintedHandoff>,
+    pub detector: Arc<FailureDetector>,
+    pub channels: Arc<Channels>,
+    pub membership: Arc<Membership>,
+    pub rebalancer: Arc<Rebalancer>,
+    pub anti_entropy: Arc<AntiEntropy>,
+    pub bootstrap: Arc<Bootstrap>,
+    pub cluster_state: Arc<ClusterState>,
+    pub discovery: Arc<Discovery>,
+    pub read_repair: Arc<ReadRepair>,
+    pub stats: Arc<Stats>,
+    pub decommission: Arc<Decommission>,
+    pub tls: Option<Arc<Tls>>,
+    pub auth: Arc<Auth>,
 }
 
 impl Lally {
     pub async fn new(config: &Config) -> Result<Arc<Self>> {
+        let handoff = Arc::new(
+            HintedHandoff::new(config)
+                .await
+                .context("Failed to load hints")?,
+        );
+        let detector = Arc::new(FailureDetector::new(config));
+        let channels = Arc::new(Channels::new(config));
+        let tls = Tls::load(config)?;
+        let auth = Arc::new(Auth::new(config));
         let lally = Arc::new(Lally {
             store: Arc::new(
                 Store::new(config.aof_file())
@@ -30,9 +84,67 @@ impl Lally {
                     .context("Failed to create store")?,
             ),
             hooks: Arc::new(Hooks::default()),
-            pool: Arc::new(Pool::default()),
+            pool: Arc::new(Pool::new(
+                config,
+                Arc::clone(&handoff),
+                Arc::clone(&detector),
+                Arc::clone(&channels),
+                tls.clone(),
+                Arc::clone(&auth),
+            )),
+            handoff,
+            detector,
+            channels,
+            membership: Arc::new(Membership::new(config)),
+            rebalancer: Arc::new(Rebalancer::new(config)),
+            anti_entropy: Arc::new(AntiEntropy::new(config)),
+            bootstrap: Arc::new(Bootstrap::new(config)),
+            cluster_state: Arc::new(
+                ClusterState::load(config)
+                    .await
+                    .context("Failed to load cluster state")?,
+            ),
+            discovery: Arc::new(Discovery::new(config).context("Failed to set up peer discovery")?),
+            read_repair: Arc::new(ReadRepair::new(config)),
+            stats: Arc::new(Stats::new(config)),
+            decommission: Arc::new(Decommission::new(config)),
+            tls,
+            auth,
         });
 
+        // Spawn the certificate watcher, it swaps in renewed certificates without a restart
+        if let Some(tls) = &lally.tls {
+            tokio::spawn(Tls::run(Arc::clone(tls)));
+        }
+
+        // Spawn the failure detector, it pings every peer and tracks how overdue they are
+        tokio::spawn(FailureDetector::run(Arc::clone(&lally)));
+
+        // Spawn the channel keeper, it reconnects to failing peers and evicts the ones gone for good
+        tokio::spawn(Channels::run(Arc::clone(&lally)));
+
+        // Spawn the gossip task, it spreads membership changes and confirms suspicions
+        tokio::spawn(Membership::run(Arc::clone(&lally)));
+
+        // Spawn the hint replayer, it delivers writes that replicas missed while unreachable
+        tokio::spawn(HintedHandoff::run(Arc::clone(&lally)));
+
+        // Spawn the rebalancer, it moves keys around whenever the membership changes
+        tokio::spawn(Rebalancer::run(Arc::clone(&lally)));
+
+        // Spawn the anti-entropy task, it reconciles keys that nobody reads
+        tokio::spawn(AntiEntropy::run(Arc::clone(&lally)));
+
+        // Spawn the cluster state saver, it remembers the peers for the next restart
+        tokio::spawn(ClusterState::run(Arc::clone(&lally)));
+
+        // Spawn peer discovery, it joins the nodes a dns name points at as they show up
+        tokio::spawn(Discovery::run(Arc::clone(&lally)));
+
+        // Spawn the decommission signal listener, only unix has this
+        #[cfg(unix)]
+        tokio::spawn(Decommission::on_signal(Arc::clone(&lally)));
+
         // Spawn a shutdown task
         tokio::spawn(Self::shutdown(Arc::clone(&lally)));
 
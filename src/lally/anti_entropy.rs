@@ -0,0 +1,398 @@
+use crate::cluster::services::KvData;
+use crate::config::Config;
+use crate::lally::ring::{hash_key, HashRing};
+use crate::lally::Lally;
+use anyhow::{bail, Context, Result};
+use rapidhash::v3::rapidhash_v3;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::task::spawn_blocking;
+use tokio::time::{interval, Duration};
+use tracing::{debug, error, info};
+
+// 2^10 leaves, each covering a slice of the hash space
+const TREE_DEPTH: u32 = 10;
+// a peer walks the tree level by level, so the tree it's compared against is kept around
+// for a bit instead of being rebuilt for every level
+const TREE_CACHE_TTL: Duration = Duration::from_secs(10);
+
+pub fn bucket_of(key: &str) -> u32 {
+    (hash_key(key) >> (64 - TREE_DEPTH)) as u32
+}
+
+fn entry_hash(entry: &KvData) -> u64 {
+    let timestamp = entry.timestamp.unwrap_or_default();
+    let mut bytes = Vec::with_capacity(entry.key.len() + entry.value.len() + 14);
+    bytes.extend_from_slice(entry.key.as_bytes());
+    bytes.push(0);
+    bytes.extend_from_slice(entry.value.as_bytes());
+    bytes.extend_from_slice(&timestamp.seconds.to_le_bytes());
+    bytes.extend_from_slice(&timestamp.nanos.to_le_bytes());
+    bytes.push(u8::from(entry.valid));
+    rapidhash_v3(&bytes)
+}
+
+// levels[0] holds the root, levels[TREE_DEPTH] holds the leaves
+pub struct MerkleTree {
+    levels: Vec<Vec<u64>>,
+}
+
+impl MerkleTree {
+    fn build(entries: &[KvData]) -> Self {
+        let leaf_count = 1usize << TREE_DEPTH;
+        let mut buckets: Vec<Vec<(&str, u64)>> = vec![Vec::new(); leaf_count];
+        for entry in entries {
+            buckets[bucket_of(&entry.key) as usize].push((&entry.key, entry_hash(entry)));
+        }
+
+        let leaves: Vec<u64> = buckets
+            .into_iter()
+            .map(|mut bucket| {
+                if bucket.is_empty() {
+                    return 0;
+                }
+                // the hash of a bucket must not depend on the iteration order of the store
+                bucket.sort_unstable_by(|a, b| a.0.cmp(b.0));
+                let bytes: Vec<u8> = bucket
+                    .iter()
+                    .flat_map(|(_, hash)| hash.to_le_bytes())
+                    .collect();
+                rapidhash_v3(&bytes)
+            })
+            .collect();
+
+        let mut levels = vec![leaves];
+        while levels[0].len() > 1 {
+            let parents = levels[0]
+                .chunks(2)
+                .map(|pair| {
+                    let mut bytes = [0u8; 16];
+                    bytes[..8].copy_from_slice(&pair[0].to_le_bytes());
+                    bytes[8..].copy_from_slice(&pair[1].to_le_bytes());
+                    rapidhash_v3(&bytes)
+                })
+                .collect();
+            levels.insert(0, parents);
+        }
+        MerkleTree { levels }
+    }
+
+    fn nodes(&self, level: u32, indices: &[u32]) -> Result<Vec<u64>> {
+        let Some(level) = self.levels.get(level as usize) else {
+            bail!("Merkle tree has no level {}", level);
+        };
+        indices
+            .iter()
+            .map(|index| match level.get(*index as usize) {
+                Some(hash) => Ok(*hash),
+                None => bail!("Merkle tree level has no node {}", index),
+            })
+            .collect()
+    }
+
+    // walks down from the root against another tree, only following the subtrees that
+    // differ, and returns the leaves that still differ at the bottom
+    async fn diff<F, Fut>(&self, mut theirs: F) -> Result<Vec<u32>>
+    where
+        F: FnMut(u32, Vec<u32>) -> Fut,
+        Fut: Future<Output = Result<Vec<u64>>>,
+    {
+        let mut differing: Vec<u32> = vec![0];
+        for level in 0..=TREE_DEPTH {
+            if level > 0 {
+                differing = differing
+                    .iter()
+                    .flat_map(|index| [index * 2, index * 2 + 1])
+                    .collect();
+            }
+            let theirs = theirs(level, differing.clone()).await?;
+            let ours = self.nodes(level, &differing)?;
+            if theirs.len() != ours.len() {
+                bail!("Merkle tree level {} has the wrong number of nodes", level);
+            }
+            differing = differing
+                .into_iter()
+                .zip(ours.into_iter().zip(theirs))
+                .filter(|(_, (ours, theirs))| ours != theirs)
+                .map(|(index, _)| index)
+                .collect();
+            if differing.is_empty() {
+                break;
+            }
+        }
+        Ok(differing)
+    }
+}
+
+#[derive(Serialize)]
+pub struct AntiEntropyMetrics {
+    pub rounds: u64,
+    pub ranges_synced: u64,
+    pub keys_repaired: u64,
+}
+
+pub struct AntiEntropy {
+    interval: Duration,
+    trees: Mutex<HashMap<String, (Instant, Arc<MerkleTree>)>>,
+    // bumped whenever the cache is cleared, so a tree built over the old store isn't cached
+    generation: AtomicU64,
+    next_peer: AtomicUsize,
+    rounds: AtomicU64,
+    ranges_synced: AtomicU64,
+    keys_repaired: AtomicU64,
+}
+
+// keys both nodes replicate, which are the only ones worth comparing between the two
+fn shared_entries(
+    lally: &Lally,
+    ring: &HashRing,
+    local: &str,
+    peer: &str,
+    buckets: Option<&[u32]>,
+) -> Vec<KvData> {
+    let replication_factor = lally.pool.configured_replication_factor();
+    lally.store.export_where(|key| {
+        if let Some(buckets) = buckets {
+            if !buckets.contains(&bucket_of(key)) {
+                return false;
+            }
+        }
+        let replicas = ring.preference_list(key, replication_factor);
+        replicas.iter().any(|node| node == local) && replicas.iter().any(|node| node == peer)
+    })
+}
+
+impl AntiEntropy {
+    pub fn new(config: &Config) -> Self {
+        AntiEntropy {
+            interval: Duration::from_millis(config.anti_entropy_interval()),
+            trees: Mutex::new(HashMap::new()),
+            generation: AtomicU64::new(0),
+            next_peer: AtomicUsize::new(0),
+            rounds: AtomicU64::new(0),
+            ranges_synced: AtomicU64::new(0),
+            keys_repaired: AtomicU64::new(0),
+        }
+    }
+
+    pub fn metrics(&self) -> AntiEntropyMetrics {
+        AntiEntropyMetrics {
+            rounds: self.rounds.load(Ordering::Relaxed),
+            ranges_synced: self.ranges_synced.load(Ordering::Relaxed),
+            keys_repaired: self.keys_repaired.load(Ordering::Relaxed),
+        }
+    }
+
+    // building a tree walks the whole store, so it's done on the blocking pool and not on a
+    // runtime worker
+    async fn build_tree(lally: &Arc<Lally>, peer: &str) -> Result<MerkleTree> {
+        let lally = Arc::clone(lally);
+        let peer = peer.to_string();
+        spawn_blocking(move || {
+            let (ring, local) = lally.pool.ring_snapshot();
+            MerkleTree::build(&shared_entries(&lally, &ring, &local, &peer, None))
+        })
+        .await
+        .context("Building the merkle tree failed")
+    }
+
+    async fn tree_for(&self, lally: &Arc<Lally>, peer: &str) -> Result<Arc<MerkleTree>> {
+        {
+            let mut trees = self.trees.lock().expect("merkle tree cache poisoned");
+            trees.retain(|_, (built_at, _)| built_at.elapsed() < TREE_CACHE_TTL);
+            if let Some((_, tree)) = trees.get(peer) {
+                return Ok(Arc::clone(tree));
+            }
+        }
+        let generation = self.generation.load(Ordering::Acquire);
+        let tree = Arc::new(Self::build_tree(lally, peer).await?);
+        let mut trees = self.trees.lock().expect("merkle tree cache poisoned");
+        if self.generation.load(Ordering::Acquire) == generation {
+            trees.insert(peer.to_string(), (Instant::now(), Arc::clone(&tree)));
+        }
+        Ok(tree)
+    }
+
+    // serves a peer walking down our tree of the keys we share with it
+    pub async fn merkle_nodes(
+        &self,
+        lally: &Arc<Lally>,
+        peer: &str,
+        level: u32,
+        indices: &[u32],
+    ) -> Result<Vec<u64>> {
+        self.tree_for(lally, peer).await?.nodes(level, indices)
+    }
+
+    // takes the peer's side of the differing ranges and hands back ours, both sides then
+    // keep whichever version of each key is newer
+    pub fn sync_range(
+        &self,
+        lally: &Lally,
+        peer: &str,
+        buckets: &[u32],
+        entries: Vec<KvData>,
+    ) -> Vec<KvData> {
+        let (ring, local) = lally.pool.ring_snapshot();
+        let ours = shared_entries(lally, &ring, &local, peer, Some(buckets));
+        self.absorb(lally, entries);
+        ours
+    }
+
+    fn absorb(&self, lally: &Lally, entries: Vec<KvData>) {
+        let applied = lally.store.import_store(entries);
+        self.keys_repaired
+            .fetch_add(applied.len() as u64, Ordering::Relaxed);
+        for operation in applied {
+            lally.hooks.invoke_all(&operation);
+        }
+        let mut trees = self.trees.lock().expect("merkle tree cache poisoned");
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        trees.clear();
+    }
+
+    pub async fn run(lally: Arc<Lally>) {
+        let anti_entropy = Arc::clone(&lally.anti_entropy);
+        if anti_entropy.interval.is_zero() {
+            info!("Anti-entropy is disabled");
+            return;
+        }
+        let mut interval = interval(anti_entropy.interval);
+        loop {
+            interval.tick().await;
+            // a decommissioning node only gives its keys away
+            if lally.membership.is_removed() || lally.decommission.is_draining() {
+                continue;
+            }
+            // one peer per round keeps the background load flat, every peer gets its turn
+            let mut peers = lally.pool.get_ids();
+            if peers.is_empty() {
+                continue;
+            }
+            peers.sort();
+            let peer = &peers[anti_entropy.next_peer.fetch_add(1, Ordering::Relaxed) % peers.len()];
+            if let Err(e) = anti_entropy.reconcile(&lally, peer).await {
+                error!(peer = %peer, "Anti-entropy round failed: {:#}", e);
+            }
+            anti_entropy.rounds.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    async fn reconcile(&self, lally: &Arc<Lally>, peer: &String) -> Result<()> {
+        let (ring, local) = lally.pool.ring_snapshot();
+        let tree = Self::build_tree(lally, peer).await?;
+
+        let buckets = tree
+            .diff(|level, indices| {
+                let local = &local;
+                async move { lally.pool.merkle_nodes(peer, local, level, &indices).await }
+            })
+            .await?;
+        if buckets.is_empty() {
+            debug!(peer = %peer, "In sync");
+            return Ok(());
+        }
+        let entries = shared_entries(lally, &ring, &local, peer, Some(&buckets));
+        info!(
+            peer = %peer,
+            "{} ranges differ, exchanging {} local entries",
+            buckets.len(),
+            entries.len()
+        );
+        let theirs = lally
+            .pool
+            .sync_range(peer, &local, &buckets, entries)
+            .await?;
+        self.absorb(lally, theirs);
+        self.ranges_synced
+            .fetch_add(buckets.len() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost_types::Timestamp;
+
+    fn entry(key: &str, value: &str, seconds: i64) -> KvData {
+        KvData {
+            key: key.to_string(),
+            value: value.to_string(),
+            timestamp: Some(Timestamp { seconds, nanos: 0 }),
+            valid: true,
+        }
+    }
+
+    fn entries() -> Vec<KvData> {
+        (0..200)
+            .map(|i| entry(&format!("key-{}", i), "value", 1))
+            .collect()
+    }
+
+    async fn diff(ours: &[KvData], theirs: &[KvData]) -> Vec<u32> {
+        let ours = MerkleTree::build(ours);
+        let theirs = MerkleTree::build(theirs);
+        ours.diff(|level, indices| std::future::ready(theirs.nodes(level, &indices)))
+            .await
+            .unwrap()
+    }
+
+    fn buckets(keys: &[&str]) -> Vec<u32> {
+        let mut buckets: Vec<u32> = keys.iter().map(|key| bucket_of(key)).collect();
+        buckets.sort_unstable();
+        buckets.dedup();
+        buckets
+    }
+
+    #[tokio::test]
+    async fn same_entries_are_in_sync_in_any_order() {
+        let mut reversed = entries();
+        reversed.reverse();
+        assert!(diff(&entries(), &reversed).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn finds_the_bucket_of_a_changed_value() {
+        let mut theirs = entries();
+        theirs[42] = entry("key-42", "other", 1);
+        assert_eq!(diff(&entries(), &theirs).await, buckets(&["key-42"]));
+    }
+
+    #[tokio::test]
+    async fn finds_newer_writes_and_tombstones() {
+        let mut theirs = entries();
+        theirs[3] = entry("key-3", "value", 2);
+        theirs[7].valid = false;
+        assert_eq!(
+            diff(&entries(), &theirs).await,
+            buckets(&["key-3", "key-7"])
+        );
+    }
+
+    #[tokio::test]
+    async fn finds_keys_only_one_side_has() {
+        let mut theirs = entries();
+        theirs.retain(|entry| entry.key != "key-100");
+        theirs.push(entry("extra", "value", 1));
+        assert_eq!(
+            diff(&entries(), &theirs).await,
+            buckets(&["key-100", "extra"])
+        );
+        // an empty node differs in every bucket the other has keys in
+        let all = entries();
+        let keys: Vec<&str> = all.iter().map(|entry| entry.key.as_str()).collect();
+        assert_eq!(diff(&[], &all).await, buckets(&keys));
+    }
+
+    #[tokio::test]
+    async fn refuses_answers_of_the_wrong_size() {
+        let tree = MerkleTree::build(&entries());
+        let result = tree.diff(|_, _| std::future::ready(Ok(Vec::new()))).await;
+        assert!(result.is_err());
+    }
+}
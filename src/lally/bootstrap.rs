@@ -0,0 +1,362 @@
+use crate::cluster::services::{BootstrapChunk, KvData};
+use crate::config::Config;
+use crate::lally::store::Store;
+use crate::lally::Lally;
+use crate::utils::timestamp::{create_timestamp, timestamp_to_rfc3339};
+use anyhow::{bail, Result};
+use prost_types::Timestamp;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, Duration};
+use tonic::Status;
+use tracing::{debug, error, info, warn};
+
+// keeps every chunk well under tonic's default 4MB message limit
+const MAX_CHUNK_BYTES: usize = 1024 * 1024;
+// chunks the seed may have in flight before it waits for the joiner to catch up
+const CHUNKS_IN_FLIGHT: usize = 4;
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+// how far before its newest write a restarted node asks to catch up from, clocks drift and
+// writes land out of order; anything older that it missed is left to anti-entropy
+const CATCH_UP_MARGIN_SECS: i64 = 60;
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BootstrapState {
+    #[default]
+    Idle,
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Clone, Default, Serialize)]
+pub struct BootstrapProgress {
+    pub state: BootstrapState,
+    pub seed: Option<String>,
+    pub since: Option<String>,
+    pub started_at: Option<String>,
+    pub finished_at: Option<String>,
+    pub total_keys: u64,
+    pub keys_received: u64,
+    pub chunks_received: u64,
+    pub resumes: u64,
+    pub last_key: Option<String>,
+}
+
+// copies the store of a seed node over in chunks, and keeps the node from serving reads
+// until it has all of it
+pub struct Bootstrap {
+    chunk_size: usize,
+    retries: usize,
+    ready: AtomicBool,
+    progress: RwLock<BootstrapProgress>,
+}
+
+impl Bootstrap {
+    pub fn new(config: &Config) -> Self {
+        Bootstrap {
+            chunk_size: config.bootstrap_chunk_size().max(1),
+            retries: config.bootstrap_retries(),
+            // main marks it ready when there turns out to be nobody to join
+            ready: AtomicBool::new(false),
+            progress: RwLock::new(BootstrapProgress::default()),
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+
+    // for when there turns out to be nothing to bootstrap from
+    pub fn mark_ready(&self) {
+        self.ready.store(true, Ordering::Release);
+    }
+
+    pub fn progress(&self) -> BootstrapProgress {
+        self.progress
+            .read()
+            .expect("bootstrap progress lock poisoned")
+            .clone()
+    }
+
+    fn update_progress(&self, update: impl FnOnce(&mut BootstrapProgress)) {
+        let mut progress = self
+            .progress
+            .write()
+            .expect("bootstrap progress lock poisoned");
+        update(&mut progress);
+    }
+
+    // the seed side: streams the keys the joiner replicates after `after_key` in key order,
+    // so a joiner whose stream broke can ask again from the last key it got; with `since`
+    // only the keys written after it are sent. keys the joiner owns but the seed doesn't hold
+    // reach it from their other replicas when they rebalance
+    pub fn serve(
+        &self,
+        lally: Arc<Lally>,
+        joiner: String,
+        after_key: String,
+        chunk_size: usize,
+        since: Option<Timestamp>,
+    ) -> mpsc::Receiver<Result<BootstrapChunk, Status>> {
+        let chunk_size = if chunk_size == 0 {
+            self.chunk_size
+        } else {
+            chunk_size.min(self.chunk_size)
+        };
+        // the bounded channel is the flow control, a slow joiner parks this task
+        let (sender, receiver) = mpsc::channel(CHUNKS_IN_FLIGHT);
+        tokio::spawn(async move {
+            // older joiners don't say who they are, and a joiner the ring doesn't know yet
+            // would get nothing, both get the whole store as before
+            let filtered = !joiner.is_empty() && lally.pool.get_ids().contains(&joiner);
+            let keys = lally.store.sorted_keys(since.as_ref(), |key| {
+                !filtered || lally.pool.placement(key).peers.contains(&joiner)
+            });
+            let total = keys.len() as u64;
+            let mut pending = resume_after(&keys, &after_key);
+            info!(
+                "Streaming {} of {} keys to a bootstrapping node",
+                pending.len(),
+                total
+            );
+
+            let caught_up = pending.is_empty();
+            while !pending.is_empty() {
+                let (chunk, rest) = next_chunk(&lally.store, pending, chunk_size, total);
+                pending = rest;
+                if sender.send(Ok(chunk)).await.is_err() {
+                    debug!("Bootstrapping node went away, stopping the stream");
+                    return;
+                }
+            }
+            if caught_up {
+                let _ = sender
+                    .send(Ok(BootstrapChunk {
+                        entries: Vec::new(),
+                        last_key: after_key,
+                        total,
+                        done: true,
+                    }))
+                    .await;
+            }
+        });
+        receiver
+    }
+
+    // the joiner side: pulls the seed's store, resuming from the last received key
+    // whenever the stream breaks, and opens up reads once it's done. a node that
+    // restarted with data only pulls what was written since shortly before its newest key
+    pub async fn run(lally: Arc<Lally>, seed: String) {
+        let bootstrap = Arc::clone(&lally.bootstrap);
+        let since = lally.store.latest_timestamp().map(|latest| Timestamp {
+            seconds: latest.seconds - CATCH_UP_MARGIN_SECS,
+            nanos: latest.nanos,
+        });
+        bootstrap.update_progress(|progress| {
+            *progress = BootstrapProgress {
+                state: BootstrapState::Running,
+                seed: Some(seed.clone()),
+                since: since.as_ref().map(timestamp_to_rfc3339),
+                started_at: Some(timestamp_to_rfc3339(&create_timestamp())),
+                ..Default::default()
+            };
+        });
+
+        let mut last_key = String::new();
+        let mut attempts = 0;
+        loop {
+            match bootstrap.pull(&lally, &seed, since, &mut last_key).await {
+                Ok(()) => break,
+                Err(e) if attempts < bootstrap.retries => {
+                    attempts += 1;
+                    warn!(
+                        "Bootstrap stream broke after key {:?}, resuming (attempt {}/{}): {:#}",
+                        last_key, attempts, bootstrap.retries, e
+                    );
+                    bootstrap.update_progress(|progress| progress.resumes += 1);
+                    sleep(RETRY_BACKOFF * attempts as u32).await;
+                }
+                Err(e) => {
+                    error!("Bootstrap from {} failed: {:#}", seed, e);
+                    bootstrap.update_progress(|progress| {
+                        progress.state = BootstrapState::Failed;
+                        progress.finished_at = Some(timestamp_to_rfc3339(&create_timestamp()));
+                    });
+                    // serving possibly incomplete data beats not serving at all, anti-entropy
+                    // and read repair fill in the rest
+                    bootstrap.ready.store(true, Ordering::Release);
+                    return;
+                }
+            }
+        }
+
+        bootstrap.update_progress(|progress| {
+            progress.state = BootstrapState::Done;
+            progress.finished_at = Some(timestamp_to_rfc3339(&create_timestamp()));
+        });
+        bootstrap.ready.store(true, Ordering::Release);
+        let progress = bootstrap.progress();
+        info!(
+            "Bootstrap finished: {} keys in {} chunks, {} resumes, now serving reads",
+            progress.keys_received, progress.chunks_received, progress.resumes
+        );
+    }
+
+    async fn pull(
+        &self,
+        lally: &Lally,
+        seed: &str,
+        since: Option<Timestamp>,
+        last_key: &mut String,
+    ) -> Result<()> {
+        let mut stream = lally
+            .pool
+            .bootstrap(seed, last_key.clone(), self.chunk_size, since)
+            .await?;
+        while let Some(chunk) = stream.message().await? {
+            let received = chunk.entries.len() as u64;
+            for operation in lally.store.import_store(chunk.entries) {
+                lally.hooks.invoke_all(&operation);
+            }
+            last_key.clone_from(&chunk.last_key);
+            self.update_progress(|progress| {
+                progress.total_keys = chunk.total;
+                progress.keys_received += received;
+                progress.chunks_received += 1;
+                progress.last_key = Some(chunk.last_key.clone());
+            });
+            let progress = self.progress();
+            debug!(
+                "Bootstrap progress: {}/{} keys",
+                progress.keys_received, progress.total_keys
+            );
+            if chunk.done {
+                return Ok(());
+            }
+        }
+        bail!("Seed closed the stream before sending everything")
+    }
+}
+
+// the sorted keys a stream still has to send after the last key the joiner got
+fn resume_after<'a>(keys: &'a [String], after_key: &str) -> &'a [String] {
+    &keys[keys.partition_point(|key| key.as_str() <= after_key)..]
+}
+
+// the next chunk off the front of `pending` and the keys left after it, the chunk's last
+// key is where the joiner resumes from if the stream breaks
+fn next_chunk<'a>(
+    store: &Store,
+    pending: &'a [String],
+    chunk_size: usize,
+    total: u64,
+) -> (BootstrapChunk, &'a [String]) {
+    let (entries, taken) = collect_chunk(store, pending, chunk_size);
+    let rest = &pending[taken..];
+    let chunk = BootstrapChunk {
+        last_key: pending[taken - 1].clone(),
+        entries,
+        total,
+        done: rest.is_empty(),
+    };
+    (chunk, rest)
+}
+
+// takes keys off the front until either the chunk size or the byte budget runs out, keys
+// removed since the snapshot are skipped
+fn collect_chunk(store: &Store, keys: &[String], chunk_size: usize) -> (Vec<KvData>, usize) {
+    let mut entries = Vec::new();
+    let mut bytes = 0;
+    let mut taken = 0;
+    for key in keys.iter().take(chunk_size) {
+        if let Some(entry) = store.export_key(key) {
+            let size = entry.key.len() + entry.value.len();
+            if !entries.is_empty() && bytes + size > MAX_CHUNK_BYTES {
+                break;
+            }
+            bytes += size;
+            entries.push(entry);
+        }
+        taken += 1;
+    }
+    (entries, taken)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::Operation;
+    use std::collections::HashMap;
+
+    // a store with `count` keys whose AOF lives in a scratch file of its own
+    async fn store(name: &str, count: usize) -> Store {
+        let path = std::env::temp_dir().join(format!("lally-{}-{}.aof", name, std::process::id()));
+        let _ = tokio::fs::remove_file(&path).await;
+        let store = Store::new(&path).await.unwrap();
+        // only read on startup, the store doesn't need it anymore
+        tokio::fs::remove_file(&path).await.unwrap();
+        for i in 0..count {
+            store.add(&Operation {
+                name: String::from("ADD"),
+                level: String::from("INFO"),
+                key: format!("key-{:02}", i),
+                value: Some(format!("value-{}", i)),
+                timestamp: create_timestamp(),
+                annotations: HashMap::new(),
+            });
+        }
+        store
+    }
+
+    fn chunk_keys(chunk: &BootstrapChunk) -> Vec<String> {
+        chunk
+            .entries
+            .iter()
+            .map(|entry| entry.key.clone())
+            .collect()
+    }
+
+    #[test]
+    fn a_resumed_stream_starts_after_the_last_key() {
+        let keys: Vec<String> = ["a", "b", "c", "d"].map(String::from).to_vec();
+        assert_eq!(resume_after(&keys, ""), keys);
+        assert_eq!(resume_after(&keys, "b"), ["c", "d"]);
+        // a key removed from the seed meanwhile still gives the right place
+        assert_eq!(resume_after(&keys, "bb"), ["c", "d"]);
+        assert!(resume_after(&keys, "d").is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_broken_stream_resumes_without_gaps_or_repeats() {
+        let store = store("bootstrap-resume", 10).await;
+        let keys = store.sorted_keys(None, |_| true);
+
+        // the first stream breaks after two chunks
+        let mut received = Vec::new();
+        let mut pending = resume_after(&keys, "");
+        let mut last_key = String::new();
+        for _ in 0..2 {
+            let (chunk, rest) = next_chunk(&store, pending, 3, keys.len() as u64);
+            received.extend(chunk_keys(&chunk));
+            last_key = chunk.last_key;
+            assert!(!chunk.done);
+            pending = rest;
+        }
+        assert_eq!(last_key, "key-05");
+
+        let mut pending = resume_after(&keys, &last_key);
+        loop {
+            let (chunk, rest) = next_chunk(&store, pending, 3, keys.len() as u64);
+            received.extend(chunk_keys(&chunk));
+            pending = rest;
+            if chunk.done {
+                break;
+            }
+        }
+        assert_eq!(received, keys);
+    }
+}
@@ -0,0 +1,202 @@
+use crate::config::Config;
+use crate::lally::membership::Membership;
+use crate::lally::Lally;
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::time::{interval, Duration};
+use tracing::{error, info, warn};
+
+// how often channels are checked for a reconnect or an eviction
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// however often a peer failed, a new channel is tried at least this often
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelState {
+    // never reached since the channel was made
+    Connecting,
+    Ready,
+    // reached before, failing now and waiting for the next attempt
+    Reconnecting,
+}
+
+impl ChannelState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ChannelState::Connecting => "connecting",
+            ChannelState::Ready => "ready",
+            ChannelState::Reconnecting => "reconnecting",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ChannelHealth {
+    pub state: ChannelState,
+    // failed calls in a row
+    pub failures: u32,
+    pub last_ok: Option<Instant>,
+    pub error: Option<String>,
+    // since when nothing got through, for eviction
+    unreachable_since: Instant,
+    // when the channel is made anew, unless something gets through before
+    retry_at: Option<Instant>,
+}
+
+// how the channel to every peer is doing, as the heartbeats find out. peers that keep
+// failing get a fresh channel with a growing pause in between, and peers that haven't been
+// reached for peer_evict_timeout are removed from the cluster
+pub struct Channels {
+    reconnect_backoff: Duration,
+    evict_after: Option<Duration>,
+    peers: Mutex<HashMap<String, ChannelHealth>>,
+}
+
+impl Channels {
+    pub fn new(config: &Config) -> Self {
+        Channels {
+            reconnect_backoff: Duration::from_millis(config.reconnect_backoff().max(1)),
+            evict_after: (config.peer_evict_timeout() > 0)
+                .then(|| Duration::from_millis(config.peer_evict_timeout())),
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // a channel to a new address starts over, whatever the old one went through
+    pub fn track(&self, peer: &str) {
+        self.peers.lock().expect("channels lock poisoned").insert(
+            peer.to_string(),
+            ChannelHealth {
+                state: ChannelState::Connecting,
+                failures: 0,
+                last_ok: None,
+                error: None,
+                unreachable_since: Instant::now(),
+                retry_at: None,
+            },
+        );
+    }
+
+    // a fresh channel to the same address, the failures still count towards the backoff
+    pub fn reconnecting(&self, peer: &str) {
+        let mut peers = self.peers.lock().expect("channels lock poisoned");
+        if let Some(health) = peers.get_mut(peer) {
+            health.retry_at = None;
+        }
+    }
+
+    pub fn forget(&self, peer: &str) {
+        self.peers
+            .lock()
+            .expect("channels lock poisoned")
+            .remove(peer);
+    }
+
+    pub fn succeeded(&self, peer: &str) {
+        let mut peers = self.peers.lock().expect("channels lock poisoned");
+        let Some(health) = peers.get_mut(peer) else {
+            return;
+        };
+        if health.state != ChannelState::Ready {
+            info!(peer = %peer, "Channel is ready");
+        }
+        let now = Instant::now();
+        health.state = ChannelState::Ready;
+        health.failures = 0;
+        health.last_ok = Some(now);
+        health.error = None;
+        health.unreachable_since = now;
+        health.retry_at = None;
+    }
+
+    pub fn failed(&self, peer: &str, error: &str) {
+        let mut peers = self.peers.lock().expect("channels lock poisoned");
+        let Some(health) = peers.get_mut(peer) else {
+            return;
+        };
+        if health.state == ChannelState::Ready {
+            warn!(peer = %peer, "Channel failed: {}", error);
+            health.state = ChannelState::Reconnecting;
+            health.unreachable_since = Instant::now();
+        }
+        health.failures = health.failures.saturating_add(1);
+        health.error = Some(error.to_string());
+        if health.retry_at.is_none() {
+            let ceiling = self
+                .reconnect_backoff
+                .saturating_mul(1 << health.failures.min(16))
+                .min(MAX_RECONNECT_BACKOFF);
+            let backoff = ceiling.mul_f64(rand::thread_rng().gen_range(0.5..=1.0));
+            health.retry_at = Some(Instant::now() + backoff);
+        }
+    }
+
+    pub fn health(&self, peer: &str) -> Option<ChannelHealth> {
+        self.peers
+            .lock()
+            .expect("channels lock poisoned")
+            .get(peer)
+            .cloned()
+    }
+
+    // peers whose next attempt is due, and peers that have been out of reach for too long
+    fn due(&self) -> (Vec<String>, Vec<String>) {
+        let peers = self.peers.lock().expect("channels lock poisoned");
+        let now = Instant::now();
+        let reconnect = peers
+            .iter()
+            .filter(|(_, health)| health.retry_at.is_some_and(|at| at <= now))
+            .map(|(peer, _)| peer.clone())
+            .collect();
+        let evict = match self.evict_after {
+            Some(evict_after) => peers
+                .iter()
+                .filter(|(_, health)| {
+                    health.state != ChannelState::Ready
+                        && now.duration_since(health.unreachable_since) >= evict_after
+                })
+                .map(|(peer, _)| peer.clone())
+                .collect(),
+            None => Vec::new(),
+        };
+        (reconnect, evict)
+    }
+
+    pub async fn run(lally: Arc<Lally>) {
+        let channels = Arc::clone(&lally.channels);
+        let mut ticker = interval(CHECK_INTERVAL);
+        loop {
+            ticker.tick().await;
+            let (reconnect, evict) = channels.due();
+            for peer in reconnect {
+                channels.reconnecting(&peer);
+                if let Err(e) = lally.pool.reconnect(&peer) {
+                    error!(peer = %peer, "Failed to make a new channel: {:#}", e);
+                }
+            }
+            for peer in evict {
+                // the detector has the final word, a peer it still hears from stays
+                if !lally.detector.is_dead(&peer) {
+                    continue;
+                }
+                warn!(
+                    peer = %peer,
+                    "Peer has been unreachable for longer than {:?}, removing it from the cluster",
+                    channels.evict_after.unwrap_or_default()
+                );
+                channels.forget(&peer);
+                match Membership::remove_member(&lally, &peer, true).await {
+                    Ok(failed) if !failed.is_empty() => warn!(
+                        peer = %peer,
+                        "{} peers weren't told about the eviction, gossip will",
+                        failed.len()
+                    ),
+                    Ok(_) => {}
+                    Err(e) => error!(peer = %peer, "Failed to evict peer: {:#}", e),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use crate::cluster::services::NodeInfo;
+use crate::config::Config;
+use crate::lally::Lally;
+use anyhow::{Context, Result};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::fs::{read_to_string, rename, write};
+use tokio::time::{interval, sleep, Duration};
+use tracing::{debug, error, info, warn};
+
+// how often the peer list is checked for changes worth saving
+const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+// however many rounds of seeds failed, a node never waits longer than this for the next one
+const MAX_JOIN_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+struct KnownPeer {
+    id: String,
+    addr: String,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Eq)]
+struct Snapshot {
+    node_id: String,
+    cluster_id: String,
+    peers: Vec<KnownPeer>,
+}
+
+// what a node remembers about its cluster across restarts: which cluster it belongs to and
+// who else is in it, so it can find its way back without being pointed at a seed again
+pub struct ClusterState {
+    path: PathBuf,
+    node_id: String,
+    cluster_id: RwLock<String>,
+    seed_nodes: Vec<String>,
+    known_peers: Vec<NodeInfo>,
+    join_retries: usize,
+    join_backoff: Duration,
+    saved: Mutex<Snapshot>,
+}
+
+impl ClusterState {
+    pub async fn load(config: &Config) -> Result<Self> {
+        let path = config.cluster_state_file().to_path_buf();
+        let snapshot = match read_to_string(&path).await {
+            Ok(contents) => match serde_json::from_str::<Snapshot>(&contents) {
+                Ok(snapshot) => snapshot,
+                Err(e) => {
+                    warn!("Ignoring unreadable cluster state in {:?}: {}", path, e);
+                    Snapshot::default()
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Snapshot::default(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {:?}", path));
+            }
+        };
+        // a data directory that was used under another node id says nothing about this node
+        let snapshot = if !snapshot.node_id.is_empty() && snapshot.node_id != config.node_id() {
+            warn!(
+                "Cluster state in {:?} belongs to node {}, starting without it",
+                path, snapshot.node_id
+            );
+            Snapshot::default()
+        } else {
+            snapshot
+        };
+        if !snapshot.peers.is_empty() {
+            info!(
+                "Remembered {} peers of cluster {}",
+                snapshot.peers.len(),
+                snapshot.cluster_id
+            );
+        }
+
+        Ok(ClusterState {
+            path,
+            node_id: config.node_id().to_string(),
+            cluster_id: RwLock::new(snapshot.cluster_id.clone()),
+            seed_nodes: config.seed_nodes().to_vec(),
+            known_peers: snapshot
+                .peers
+                .iter()
+                .map(|peer| NodeInfo {
+                    id: peer.id.clone(),
+                    addr: peer.addr.clone(),
+                    protocol: None,
+                    zone: String::new(),
+                })
+                .collect(),
+            join_retries: config.join_retries(),
+            join_backoff: Duration::from_millis(config.join_backoff()),
+            saved: Mutex::new(snapshot),
+        })
+    }
+
+    // empty until the node has joined a cluster or been joined
+    pub fn cluster_id(&self) -> String {
+        self.cluster_id
+            .read()
+            .expect("cluster id lock poisoned")
+            .clone()
+    }
+
+    // the cluster a joining node is let into: a node that isn't part of one yet goes along
+    // with the joiner's, and the first node to be joined names a new one
+    pub fn accept_cluster_id(&self, joiner: &str) -> String {
+        let mut cluster_id = self.cluster_id.write().expect("cluster id lock poisoned");
+        if cluster_id.is_empty() {
+            if joiner.is_empty() {
+                *cluster_id = format!("{:016x}", rand::thread_rng().gen::<u64>());
+                info!("Starting cluster {}", cluster_id);
+            } else {
+                info!("Joined cluster {}", joiner);
+                *cluster_id = joiner.to_string();
+            }
+        }
+        cluster_id.clone()
+    }
+
+    // two fresh nodes joining each other at the same time both name a cluster, keeping the
+    // smaller id makes them settle on the same one
+    fn adopt_cluster_id(&self, id: &str) {
+        let mut cluster_id = self.cluster_id.write().expect("cluster id lock poisoned");
+        if !id.is_empty() && (cluster_id.is_empty() || id < cluster_id.as_str()) {
+            info!("Joined cluster {}", id);
+            *cluster_id = id.to_string();
+        }
+    }
+
+    // where to join through: the configured seeds first, then every peer known from before
+    // the restart, then whatever discovery finds
+    async fn seeds(&self, lally: &Lally) -> Vec<String> {
+        let local_addr = lally.pool.local_addr();
+        let mut seeds: Vec<String> = Vec::new();
+        let candidates = self
+            .seed_nodes
+            .iter()
+            .cloned()
+            .chain(self.known_peers.iter().map(|peer| peer.addr.clone()))
+            .chain(lally.discovery.peers(lally).await);
+        for addr in candidates {
+            if !addr.is_empty() && addr != local_addr && !seeds.contains(&addr) {
+                seeds.push(addr);
+            }
+        }
+        seeds
+    }
+
+    // goes through the seeds in rounds with a growing, jittered pause in between, and
+    // returns the id of the node that let us in; nobody to ask means we're the first node
+    pub async fn join(lally: &Arc<Lally>) -> Option<String> {
+        let state = &lally.cluster_state;
+        for round in 0..=state.join_retries {
+            let seeds = state.seeds(lally).await;
+            if seeds.is_empty() && round == 0 {
+                warn!("No seed node address provided; this node will act as the first node in the cluster.");
+                return None;
+            }
+            if let Some(seed) = Self::join_through(lally, &seeds).await {
+                return Some(seed);
+            }
+            if round < state.join_retries {
+                let ceiling = state
+                    .join_backoff
+                    .saturating_mul(1 << round.min(16))
+                    .min(MAX_JOIN_BACKOFF);
+                let backoff = ceiling.mul_f64(rand::thread_rng().gen_range(0.5..=1.0));
+                warn!(
+                    "Retrying the seeds in {:?} (attempt {}/{})",
+                    backoff,
+                    round + 1,
+                    state.join_retries
+                );
+                sleep(backoff).await;
+            }
+        }
+        error!("Giving up on joining, this node will act as the first node in the cluster.");
+        None
+    }
+
+    // tries the seeds in order and returns the id of the one that let us in
+    pub async fn join_through(lally: &Arc<Lally>, seeds: &[String]) -> Option<String> {
+        let state = &lally.cluster_state;
+        for addr in seeds {
+            info!("Attempting to join cluster on address: {}", addr);
+            match lally.pool.join(addr.clone(), state.cluster_id()).await {
+                Ok(joined) => {
+                    info!("Successfully joined cluster.");
+                    state.adopt_cluster_id(&joined.cluster_id);
+                    if let Err(e) = state.save(lally).await {
+                        error!("Failed to save cluster state: {:#}", e);
+                    }
+                    return Some(joined.seed);
+                }
+                Err(e) => warn!("Failed to join cluster through {}: {:#}", addr, e),
+            }
+        }
+        if !seeds.is_empty() {
+            error!(
+                "Failed to join cluster through any of {} seeds",
+                seeds.len()
+            );
+        }
+        None
+    }
+
+    async fn save(&self, lally: &Lally) -> Result<()> {
+        let mut peers: Vec<KnownPeer> = lally
+            .pool
+            .peers()
+            .into_iter()
+            .map(|peer| KnownPeer {
+                id: peer.id,
+                addr: peer.addr,
+            })
+            .collect();
+        peers.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+        let snapshot = Snapshot {
+            node_id: self.node_id.clone(),
+            cluster_id: self.cluster_id(),
+            peers,
+        };
+        if *self.saved.lock().expect("cluster state lock poisoned") == snapshot {
+            return Ok(());
+        }
+
+        // written aside and renamed over, so a crash mid-write leaves the old state intact
+        let temp = self.path.with_extension("json.tmp");
+        write(&temp, serde_json::to_vec_pretty(&snapshot)?)
+            .await
+            .with_context(|| format!("Failed to write {:?}", temp))?;
+        rename(&temp, &self.path)
+            .await
+            .with_context(|| format!("Failed to replace {:?}", self.path))?;
+        debug!("Saved {} peers to {:?}", snapshot.peers.len(), self.path);
+        *self.saved.lock().expect("cluster state lock poisoned") = snapshot;
+        Ok(())
+    }
+
+    pub async fn run(lally: Arc<Lally>) {
+        let mut ticker = interval(SAVE_INTERVAL);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = lally.cluster_state.save(&lally).await {
+                error!("Failed to save cluster state: {:#}", e);
+            }
+        }
+    }
+}
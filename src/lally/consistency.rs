@@ -0,0 +1,258 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt;
+
+// how many replicas a single request waits for, overriding the configured quorum
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "RawConsistency")]
+pub enum Consistency {
+    One,
+    Quorum,
+    All,
+    // a majority of the key's replicas in the coordinator's zone
+    LocalQuorum,
+    // a majority of the key's replicas in every zone that has some
+    EachQuorum,
+    Count(usize),
+}
+
+// what the client sends: a level name or a plain number of replicas
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawConsistency {
+    Count(usize),
+    Name(String),
+}
+
+impl TryFrom<RawConsistency> for Consistency {
+    type Error = String;
+
+    fn try_from(raw: RawConsistency) -> Result<Self, Self::Error> {
+        let name = match raw {
+            RawConsistency::Count(count) => return Ok(Consistency::Count(count)),
+            RawConsistency::Name(name) => name,
+        };
+        match name.to_ascii_uppercase().as_str() {
+            "ONE" => Ok(Consistency::One),
+            "QUORUM" => Ok(Consistency::Quorum),
+            "ALL" => Ok(Consistency::All),
+            "LOCAL_QUORUM" => Ok(Consistency::LocalQuorum),
+            "EACH_QUORUM" => Ok(Consistency::EachQuorum),
+            other => other.parse().map(Consistency::Count).map_err(|_| {
+                format!(
+                    "unknown consistency level {:?}, expected ONE, QUORUM, ALL, LOCAL_QUORUM, EACH_QUORUM or a number",
+                    name
+                )
+            }),
+        }
+    }
+}
+
+// what a request waits for: a number of replicas overall, and for the zone levels a number
+// in each zone
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Quorum {
+    pub votes: usize,
+    pub zones: BTreeMap<String, usize>,
+}
+
+impl Quorum {
+    pub fn votes(votes: usize) -> Self {
+        Quorum {
+            votes,
+            zones: BTreeMap::new(),
+        }
+    }
+
+    // what's still missing once a replica in the given zone answered
+    pub fn after_vote(&self, zone: &str) -> Quorum {
+        let mut left = self.clone();
+        left.votes = left.votes.saturating_sub(1);
+        if let Some(votes) = left.zones.get_mut(zone) {
+            *votes = votes.saturating_sub(1);
+        }
+        left
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.votes == 0 && self.zones.values().all(|votes| *votes == 0)
+    }
+
+    // whether replies from replicas in these zones are enough
+    pub fn met_by<'a>(&self, zones: impl IntoIterator<Item = &'a str>) -> bool {
+        zones
+            .into_iter()
+            .fold(self.clone(), |left, zone| left.after_vote(zone))
+            .is_empty()
+    }
+
+    // the replies it takes at the very least, as reported to clients
+    pub fn required(&self) -> usize {
+        self.votes.max(self.zones.values().sum())
+    }
+}
+
+impl Consistency {
+    // what a request needs from the key's replicas, given the zone each of them is in;
+    // levels that can't be met are refused up front instead of coming back partial
+    pub fn quorum(self, replica_zones: &[&str], local_zone: &str) -> Result<Quorum> {
+        let majority_in = |zone: &str| {
+            let replicas = replica_zones.iter().filter(|other| **other == zone).count();
+            replicas / 2 + 1
+        };
+        let replicas = replica_zones.len();
+        let required = match self {
+            Consistency::One => 1,
+            Consistency::Quorum => replicas / 2 + 1,
+            Consistency::All => replicas,
+            Consistency::Count(count) => count,
+            Consistency::LocalQuorum => {
+                if !replica_zones.contains(&local_zone) {
+                    bail!(
+                        "Consistency {} needs replicas in zone {:?}, but the key has none there",
+                        self,
+                        local_zone
+                    );
+                }
+                return Ok(Quorum {
+                    votes: 0,
+                    zones: BTreeMap::from([(local_zone.to_string(), majority_in(local_zone))]),
+                });
+            }
+            Consistency::EachQuorum => {
+                return Ok(Quorum {
+                    votes: 0,
+                    zones: replica_zones
+                        .iter()
+                        .map(|zone| (zone.to_string(), majority_in(zone)))
+                        .collect(),
+                });
+            }
+        };
+        if required == 0 {
+            bail!("Consistency {} needs at least one replica to answer", self);
+        }
+        if required > replicas {
+            bail!(
+                "Consistency {} needs {} replicas but keys only have {} in the current cluster",
+                self,
+                required,
+                replicas
+            );
+        }
+        Ok(Quorum::votes(required))
+    }
+}
+
+impl fmt::Display for Consistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Consistency::One => write!(f, "ONE"),
+            Consistency::Quorum => write!(f, "QUORUM"),
+            Consistency::All => write!(f, "ALL"),
+            Consistency::LocalQuorum => write!(f, "LOCAL_QUORUM"),
+            Consistency::EachQuorum => write!(f, "EACH_QUORUM"),
+            Consistency::Count(count) => write!(f, "{}", count),
+        }
+    }
+}
+
+// reported back the way it was asked for, names as strings and counts as numbers
+impl Serialize for Consistency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Consistency::Count(count) => serializer.serialize_u64(*count as u64),
+            level => serializer.collect_str(level),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> Result<Consistency, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    #[test]
+    fn parses_names_and_counts() {
+        assert_eq!(parse("\"one\"").unwrap(), Consistency::One);
+        assert_eq!(parse("\"QUORUM\"").unwrap(), Consistency::Quorum);
+        assert_eq!(parse("\"All\"").unwrap(), Consistency::All);
+        assert_eq!(parse("2").unwrap(), Consistency::Count(2));
+        assert_eq!(parse("\"3\"").unwrap(), Consistency::Count(3));
+        assert!(parse("\"most\"").is_err());
+    }
+
+    #[test]
+    fn serializes_the_way_it_was_asked() {
+        assert_eq!(
+            serde_json::to_string(&Consistency::Quorum).unwrap(),
+            "\"QUORUM\""
+        );
+        assert_eq!(serde_json::to_string(&Consistency::Count(2)).unwrap(), "2");
+    }
+
+    #[test]
+    fn counts_replicas() {
+        let zones = ["", "", ""];
+        assert_eq!(Consistency::One.quorum(&zones, "").unwrap().required(), 1);
+        assert_eq!(
+            Consistency::Quorum.quorum(&zones, "").unwrap().required(),
+            2
+        );
+        assert_eq!(Consistency::All.quorum(&zones, "").unwrap().required(), 3);
+        assert_eq!(
+            Consistency::Count(2).quorum(&zones, "").unwrap().required(),
+            2
+        );
+    }
+
+    #[test]
+    fn refuses_levels_that_cant_be_met() {
+        let zones = ["", ""];
+        assert!(Consistency::Count(0).quorum(&zones, "").is_err());
+        assert!(Consistency::Count(3).quorum(&zones, "").is_err());
+        assert!(Consistency::One.quorum(&[], "").is_err());
+    }
+
+    #[test]
+    fn quorum_is_met_by_enough_votes() {
+        let quorum = Consistency::Quorum.quorum(&["", "", ""], "").unwrap();
+        assert!(!quorum.met_by([]));
+        assert!(!quorum.met_by([""]));
+        assert!(quorum.met_by(["", ""]));
+        assert!(quorum.after_vote("").after_vote("").is_empty());
+    }
+
+    #[test]
+    fn local_quorum_only_counts_the_local_zone() {
+        let zones = ["east", "east", "east", "west", "west"];
+        let quorum = Consistency::LocalQuorum.quorum(&zones, "east").unwrap();
+        assert_eq!(quorum.required(), 2);
+        assert!(!quorum.met_by(["east", "west", "west"]));
+        assert!(quorum.met_by(["east", "east"]));
+        assert!(quorum.met_by(["west", "east", "east"]));
+    }
+
+    #[test]
+    fn local_quorum_needs_local_replicas() {
+        assert!(Consistency::LocalQuorum
+            .quorum(&["east", "west"], "north")
+            .is_err());
+    }
+
+    #[test]
+    fn each_quorum_needs_a_majority_everywhere() {
+        let zones = ["east", "east", "east", "west", "west", "north"];
+        let quorum = Consistency::EachQuorum.quorum(&zones, "east").unwrap();
+        assert_eq!(quorum.required(), 5);
+        assert!(!quorum.met_by(["east", "east", "west", "west"]));
+        assert!(!quorum.met_by(["east", "west", "west", "north"]));
+        assert!(quorum.met_by(["east", "east", "west", "west", "north"]));
+        // extra replies from a zone that's done don't make up for another
+        assert!(!quorum.met_by(["east", "east", "east", "west", "west"]));
+    }
+}
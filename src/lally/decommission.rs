@@ -0,0 +1,229 @@
+use crate::cluster::services::KvData;
+use crate::config::Config;
+use crate::lally::handoff::HintedHandoff;
+use crate::lally::Lally;
+use crate::utils::timestamp::{create_timestamp, timestamp_to_rfc3339};
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use tokio::time::{sleep, Duration, Instant};
+use tracing::{error, info, warn};
+
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+
+// pause between attempts at delivering the hints that are still pending
+const HINT_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DecommissionState {
+    #[default]
+    Idle,
+    Draining,
+    HandingOff,
+    Leaving,
+    Done,
+    Failed,
+}
+
+#[derive(Clone, Default, Serialize)]
+pub struct DecommissionProgress {
+    pub state: DecommissionState,
+    pub trigger: Option<String>,
+    pub started_at: Option<String>,
+    pub finished_at: Option<String>,
+    pub hints_pending: usize,
+    pub keys_scanned: usize,
+    pub keys_to_send: usize,
+    pub keys_sent: usize,
+    pub failed_batches: usize,
+    pub error: Option<String>,
+}
+
+// takes a node out of the cluster without losing what only it holds: client and replica
+// writes are refused, pending hints are delivered, every key is copied to the nodes that own it once
+// this one is gone, and only when they all acknowledged it does the node leave and exit
+pub struct Decommission {
+    batch_size: usize,
+    hint_timeout: Duration,
+    draining: AtomicBool,
+    progress: RwLock<DecommissionProgress>,
+}
+
+impl Decommission {
+    pub fn new(config: &Config) -> Self {
+        Decommission {
+            batch_size: config.rebalance_batch_size().max(1),
+            hint_timeout: Duration::from_millis(config.decommission_hint_timeout()),
+            draining: AtomicBool::new(false),
+            progress: RwLock::new(DecommissionProgress::default()),
+        }
+    }
+
+    // client and replica writes are refused while this is set, so the export the handoff
+    // takes stays complete
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
+
+    pub fn progress(&self) -> DecommissionProgress {
+        self.progress
+            .read()
+            .expect("decommission progress lock poisoned")
+            .clone()
+    }
+
+    fn update_progress(&self, update: impl FnOnce(&mut DecommissionProgress)) {
+        let mut progress = self
+            .progress
+            .write()
+            .expect("decommission progress lock poisoned");
+        update(&mut progress);
+    }
+
+    // false when a decommission is already running
+    pub fn start(lally: &Arc<Lally>, trigger: &str) -> bool {
+        if lally.decommission.draining.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        lally.decommission.update_progress(|progress| {
+            *progress = DecommissionProgress {
+                state: DecommissionState::Draining,
+                trigger: Some(trigger.to_string()),
+                started_at: Some(timestamp_to_rfc3339(&create_timestamp())),
+                ..Default::default()
+            };
+        });
+        tokio::spawn(Self::run(Arc::clone(lally)));
+        true
+    }
+
+    // SIGUSR1 decommissions the node, for orchestrators that can only send signals
+    #[cfg(unix)]
+    pub async fn on_signal(lally: Arc<Lally>) {
+        let mut usr1 = match signal(SignalKind::user_defined1()) {
+            Ok(usr1) => usr1,
+            Err(e) => {
+                error!("Failed to listen for SIGUSR1: {}", e);
+                return;
+            }
+        };
+        while usr1.recv().await.is_some() {
+            info!("Received SIGUSR1 signal");
+            if !Self::start(&lally, "signal") {
+                warn!("Decommission already running");
+            }
+        }
+    }
+
+    async fn run(lally: Arc<Lally>) {
+        let decommission = Arc::clone(&lally.decommission);
+        info!("Decommissioning this node, client writes are refused from now on");
+
+        if let Err(e) = decommission.hand_off(&lally).await {
+            error!(
+                "Decommission failed, the node stays in the cluster and takes writes again: {:#}",
+                e
+            );
+            decommission.update_progress(|progress| {
+                progress.state = DecommissionState::Failed;
+                progress.error = Some(format!("{:#}", e));
+                progress.finished_at = Some(timestamp_to_rfc3339(&create_timestamp()));
+            });
+            decommission.draining.store(false, Ordering::Release);
+            return;
+        }
+
+        decommission.update_progress(|progress| progress.state = DecommissionState::Leaving);
+        lally.pool.leave().await;
+        decommission.update_progress(|progress| {
+            progress.state = DecommissionState::Done;
+            progress.finished_at = Some(timestamp_to_rfc3339(&create_timestamp()));
+        });
+        let progress = decommission.progress();
+        info!(
+            "Decommission finished: {} keys handed off, exiting Lally",
+            progress.keys_sent
+        );
+        std::process::exit(0);
+    }
+
+    async fn hand_off(&self, lally: &Arc<Lally>) -> Result<()> {
+        // the hints are writes no other node knows to deliver, leaving with them loses them
+        let deadline = Instant::now() + self.hint_timeout;
+        loop {
+            let pending = HintedHandoff::flush(lally).await;
+            self.update_progress(|progress| progress.hints_pending = pending);
+            if pending == 0 {
+                break;
+            }
+            if Instant::now() >= deadline {
+                bail!(
+                    "{} hints couldn't be delivered within decommission_hint_timeout",
+                    pending
+                );
+            }
+            sleep(HINT_RETRY_INTERVAL).await;
+        }
+
+        self.update_progress(|progress| progress.state = DecommissionState::HandingOff);
+        let (mut ring, local) = lally.pool.ring_snapshot();
+        ring.remove(&local);
+        if ring.len() == 0 {
+            bail!("There is no other node to hand the data to");
+        }
+
+        // every owner gets every key, there's no telling which of them the others missed
+        let replication_factor = lally.pool.configured_replication_factor();
+        let entries = lally.store.export_store();
+        self.update_progress(|progress| progress.keys_scanned = entries.len());
+        let mut outgoing: HashMap<String, Vec<KvData>> = HashMap::new();
+        let mut orphaned = 0;
+        for entry in entries {
+            let owners: Vec<String> = ring
+                .preference_list(&entry.key, replication_factor)
+                .into_iter()
+                .filter(|owner| !lally.detector.is_dead(owner))
+                .collect();
+            if owners.is_empty() {
+                orphaned += 1;
+                continue;
+            }
+            for owner in owners {
+                outgoing.entry(owner).or_default().push(entry.clone());
+            }
+        }
+        if orphaned > 0 {
+            bail!("{} keys have no live owner to be handed to", orphaned);
+        }
+        let keys_to_send: usize = outgoing.values().map(Vec::len).sum();
+        self.update_progress(|progress| progress.keys_to_send = keys_to_send);
+        info!(
+            "Handing off {} keys to {} nodes",
+            keys_to_send,
+            outgoing.len()
+        );
+
+        let mut batch: u64 = 0;
+        for (owner, entries) in outgoing {
+            for chunk in entries.chunks(self.batch_size) {
+                batch += 1;
+                match lally.pool.transfer(&owner, chunk.to_vec(), batch).await {
+                    Ok(_) => self.update_progress(|progress| progress.keys_sent += chunk.len()),
+                    Err(e) => {
+                        error!("{:#}", e);
+                        self.update_progress(|progress| progress.failed_batches += 1);
+                    }
+                }
+            }
+        }
+        let failed_batches = self.progress().failed_batches;
+        if failed_batches > 0 {
+            bail!("{} batches weren't acknowledged", failed_batches);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,299 @@
+use crate::config::Config;
+use crate::lally::Lally;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::task::JoinSet;
+use tokio::time::{interval, timeout, Duration};
+use tracing::{debug, info, warn};
+
+// how many heartbeat intervals are kept to estimate the arrival distribution
+const WINDOW_SIZE: usize = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerState {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+struct Heartbeats {
+    intervals: VecDeque<f64>,
+    last: Instant,
+    state: PeerState,
+    // set when the cluster agreed the peer is dead, the next heartbeat clears it
+    convicted: bool,
+}
+
+// phi-accrual failure detector: instead of a binary timeout, every peer gets a suspicion
+// level that grows the longer a heartbeat is overdue compared to how they usually arrive
+pub struct FailureDetector {
+    heartbeat_interval: Duration,
+    suspect_threshold: f64,
+    dead_threshold: f64,
+    peers: Mutex<HashMap<String, Heartbeats>>,
+}
+
+impl FailureDetector {
+    pub fn new(config: &Config) -> Self {
+        FailureDetector {
+            heartbeat_interval: Duration::from_millis(config.heartbeat_interval().max(1)),
+            suspect_threshold: config.phi_suspect_threshold(),
+            dead_threshold: config.phi_dead_threshold(),
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn register(&self, peer: &str) {
+        let mut peers = self.peers.lock().expect("detector lock poisoned");
+        peers.entry(peer.to_string()).or_insert_with(|| Heartbeats {
+            intervals: VecDeque::with_capacity(WINDOW_SIZE),
+            last: Instant::now(),
+            state: PeerState::Alive,
+            convicted: false,
+        });
+    }
+
+    pub fn forget(&self, peer: &str) {
+        self.peers
+            .lock()
+            .expect("detector lock poisoned")
+            .remove(peer);
+    }
+
+    pub fn heartbeat(&self, peer: &str) {
+        let mut peers = self.peers.lock().expect("detector lock poisoned");
+        let Some(heartbeats) = peers.get_mut(peer) else {
+            return;
+        };
+        let now = Instant::now();
+        if heartbeats.intervals.len() == WINDOW_SIZE {
+            heartbeats.intervals.pop_front();
+        }
+        heartbeats
+            .intervals
+            .push_back(now.duration_since(heartbeats.last).as_secs_f64() * 1000.0);
+        heartbeats.last = now;
+        heartbeats.convicted = false;
+    }
+
+    pub fn convict(&self, peer: &str) {
+        let mut peers = self.peers.lock().expect("detector lock poisoned");
+        if let Some(heartbeats) = peers.get_mut(peer) {
+            heartbeats.convicted = true;
+        }
+    }
+
+    fn phi_of(&self, heartbeats: &Heartbeats) -> f64 {
+        let expected = self.heartbeat_interval.as_secs_f64() * 1000.0;
+        // until there is some history, assume heartbeats arrive exactly on schedule
+        let (mean, variance) = if heartbeats.intervals.is_empty() {
+            (expected, 0.0)
+        } else {
+            let count = heartbeats.intervals.len() as f64;
+            let mean = heartbeats.intervals.iter().sum::<f64>() / count;
+            let variance = heartbeats
+                .intervals
+                .iter()
+                .map(|i| (i - mean).powi(2))
+                .sum::<f64>()
+                / count;
+            (mean, variance)
+        };
+        // a floor on the deviation keeps a perfectly regular peer from being convicted
+        // the moment a single heartbeat runs late
+        let std_dev = variance.sqrt().max(expected / 2.0);
+        let elapsed = heartbeats.last.elapsed().as_secs_f64() * 1000.0;
+
+        // logistic approximation of the normal cdf
+        let y = (elapsed - mean) / std_dev;
+        let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+        let phi = if elapsed > mean {
+            -(e / (1.0 + e)).log10()
+        } else {
+            -(1.0 - 1.0 / (1.0 + e)).log10()
+        };
+        phi.max(0.0)
+    }
+
+    fn state_for(&self, phi: f64) -> PeerState {
+        if phi >= self.dead_threshold {
+            PeerState::Dead
+        } else if phi >= self.suspect_threshold {
+            PeerState::Suspect
+        } else {
+            PeerState::Alive
+        }
+    }
+
+    pub fn phi(&self, peer: &str) -> f64 {
+        let peers = self.peers.lock().expect("detector lock poisoned");
+        peers.get(peer).map_or(0.0, |h| self.phi_of(h))
+    }
+
+    pub fn state(&self, peer: &str) -> PeerState {
+        let peers = self.peers.lock().expect("detector lock poisoned");
+        match peers.get(peer) {
+            Some(heartbeats) if heartbeats.convicted => PeerState::Dead,
+            Some(heartbeats) => self.state_for(self.phi_of(heartbeats)),
+            None => PeerState::Alive,
+        }
+    }
+
+    // how long ago the peer's last heartbeat arrived
+    pub fn last_contact(&self, peer: &str) -> Option<Duration> {
+        let peers = self.peers.lock().expect("detector lock poisoned");
+        peers.get(peer).map(|heartbeats| heartbeats.last.elapsed())
+    }
+
+    pub fn is_dead(&self, peer: &str) -> bool {
+        self.state(peer) == PeerState::Dead
+    }
+
+    // re-evaluates every peer, logging the ones that changed state since the last check
+    fn evaluate(&self) {
+        let mut peers = self.peers.lock().expect("detector lock poisoned");
+        for (peer, heartbeats) in peers.iter_mut() {
+            let phi = self.phi_of(heartbeats);
+            let state = if heartbeats.convicted {
+                PeerState::Dead
+            } else {
+                self.state_for(phi)
+            };
+            if state != heartbeats.state {
+                match state {
+                    PeerState::Alive => info!(peer = %peer, "Peer is alive again"),
+                    PeerState::Suspect => warn!(peer = %peer, phi = phi, "Peer is suspected"),
+                    PeerState::Dead => warn!(peer = %peer, phi = phi, "Peer is considered dead"),
+                }
+                heartbeats.state = state;
+            }
+        }
+    }
+
+    pub async fn run(lally: Arc<Lally>) {
+        let detector = Arc::clone(&lally.detector);
+        let mut ticker = interval(detector.heartbeat_interval);
+        loop {
+            ticker.tick().await;
+            let mut pings = JoinSet::new();
+            for peer in lally.pool.get_ids() {
+                let lally = Arc::clone(&lally);
+                let deadline = detector.heartbeat_interval;
+                pings.spawn(async move {
+                    match timeout(deadline, lally.pool.ping(&peer)).await {
+                        Ok(Ok(())) => lally.detector.heartbeat(&peer),
+                        Ok(Err(e)) => debug!(peer = %peer, "Ping failed: {:#}", e),
+                        Err(_) => {
+                            lally.channels.failed(&peer, "Ping timed out");
+                            debug!(peer = %peer, "Ping timed out")
+                        }
+                    }
+                });
+            }
+            pings.join_all().await;
+            detector.evaluate();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detector() -> FailureDetector {
+        FailureDetector {
+            heartbeat_interval: Duration::from_millis(1000),
+            suspect_threshold: 5.0,
+            dead_threshold: 8.0,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // a peer whose last heartbeat came `elapsed_ms` ago, after the given intervals
+    fn heartbeats(intervals: &[f64], elapsed_ms: u64) -> Heartbeats {
+        Heartbeats {
+            intervals: intervals.iter().copied().collect(),
+            last: Instant::now() - Duration::from_millis(elapsed_ms),
+            state: PeerState::Alive,
+            convicted: false,
+        }
+    }
+
+    #[test]
+    fn phi_is_low_right_after_a_heartbeat() {
+        let detector = detector();
+        assert!(detector.phi_of(&heartbeats(&[], 0)) < 0.1);
+        assert!(detector.phi_of(&heartbeats(&[1000.0; 10], 0)) < 0.1);
+    }
+
+    #[test]
+    fn phi_is_a_coin_flip_at_the_mean() {
+        let phi = detector().phi_of(&heartbeats(&[], 1000));
+        assert!((phi - 0.5f64.log10().abs()).abs() < 0.05, "{}", phi);
+    }
+
+    #[test]
+    fn phi_grows_the_longer_a_heartbeat_is_overdue() {
+        let detector = detector();
+        let mut last = 0.0;
+        for elapsed in [500, 1000, 1500, 2000, 3000, 4000] {
+            let phi = detector.phi_of(&heartbeats(&[1000.0; 10], elapsed));
+            assert!(phi > last, "phi {} after {}ms", phi, elapsed);
+            last = phi;
+        }
+    }
+
+    #[test]
+    fn irregular_peers_get_more_slack() {
+        let detector = detector();
+        let regular = detector.phi_of(&heartbeats(&[1000.0; 10], 3000));
+        let irregular = detector.phi_of(&heartbeats(&[200.0, 1800.0, 300.0, 1700.0, 4000.0], 3000));
+        assert!(irregular < regular, "{} >= {}", irregular, regular);
+    }
+
+    #[test]
+    fn states_follow_the_thresholds() {
+        let detector = detector();
+        assert_eq!(detector.state_for(1.0), PeerState::Alive);
+        assert_eq!(detector.state_for(5.0), PeerState::Suspect);
+        assert_eq!(detector.state_for(8.0), PeerState::Dead);
+
+        detector
+            .peers
+            .lock()
+            .unwrap()
+            .insert("late".to_string(), heartbeats(&[1000.0; 10], 3200));
+        assert_eq!(detector.state("late"), PeerState::Suspect);
+        detector
+            .peers
+            .lock()
+            .unwrap()
+            .insert("gone".to_string(), heartbeats(&[1000.0; 10], 4000));
+        assert_eq!(detector.state("gone"), PeerState::Dead);
+        assert_eq!(detector.state("unknown"), PeerState::Alive);
+    }
+
+    #[test]
+    fn convictions_last_until_the_next_heartbeat() {
+        let detector = detector();
+        detector.register("peer");
+        detector.convict("peer");
+        assert!(detector.is_dead("peer"));
+        detector.heartbeat("peer");
+        assert_eq!(detector.state("peer"), PeerState::Alive);
+    }
+
+    #[test]
+    fn keeps_a_bounded_window() {
+        let detector = detector();
+        detector.register("peer");
+        for _ in 0..WINDOW_SIZE + 10 {
+            detector.heartbeat("peer");
+        }
+        let peers = detector.peers.lock().unwrap();
+        assert_eq!(peers["peer"].intervals.len(), WINDOW_SIZE);
+    }
+}
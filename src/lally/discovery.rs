@@ -0,0 +1,244 @@
+use crate::config::{Config, DiscoveryRecord};
+use crate::lally::bootstrap::Bootstrap;
+use crate::lally::cluster_state::ClusterState;
+use crate::lally::Lally;
+use anyhow::{Context, Result};
+use hickory_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use hickory_resolver::TokioAsyncResolver;
+use std::collections::HashSet;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::net::lookup_host;
+use tokio::time::{interval_at, Duration, Instant};
+use tracing::{debug, info, warn};
+
+type Resolving<'a> = Pin<Box<dyn Future<Output = Result<Vec<String>>> + Send + 'a>>;
+
+// turns a name into the grpc addresses of the nodes behind it
+pub trait Resolver: Send + Sync {
+    fn resolve<'a>(&'a self, name: &'a str) -> Resolving<'a>;
+}
+
+// A records carry no port, every node is expected to listen on the same one
+pub struct DnsA {
+    resolver: TokioAsyncResolver,
+    port: u16,
+}
+
+impl Resolver for DnsA {
+    fn resolve<'a>(&'a self, name: &'a str) -> Resolving<'a> {
+        Box::pin(async move {
+            let lookup = self.resolver.lookup_ip(name).await?;
+            Ok(lookup
+                .iter()
+                .map(|ip| SocketAddr::new(ip, self.port).to_string())
+                .collect())
+        })
+    }
+}
+
+// SRV records name a host and port per node, the hosts are resolved in turn
+pub struct DnsSrv {
+    resolver: TokioAsyncResolver,
+}
+
+impl Resolver for DnsSrv {
+    fn resolve<'a>(&'a self, name: &'a str) -> Resolving<'a> {
+        Box::pin(async move {
+            let lookup = self.resolver.srv_lookup(name).await?;
+            let mut addrs = Vec::new();
+            for srv in lookup.iter() {
+                match self.resolver.lookup_ip(srv.target().clone()).await {
+                    Ok(ips) => addrs.extend(
+                        ips.iter()
+                            .map(|ip| SocketAddr::new(ip, srv.port()).to_string()),
+                    ),
+                    Err(e) => warn!("Failed to resolve SRV target {}: {}", srv.target(), e),
+                }
+            }
+            Ok(addrs)
+        })
+    }
+}
+
+// the system resolver unless a specific dns server is configured
+fn dns_resolver(config: &Config) -> Result<TokioAsyncResolver> {
+    let Some(server) = config.discovery_dns_server() else {
+        return TokioAsyncResolver::tokio_from_system_conf()
+            .context("Failed to read the system DNS configuration");
+    };
+    let server: SocketAddr = server
+        .parse()
+        .with_context(|| format!("Invalid discovery_dns_server {:?}", server))?;
+    let name_servers = NameServerConfigGroup::from_ips_clear(&[server.ip()], server.port(), true);
+    Ok(TokioAsyncResolver::tokio(
+        ResolverConfig::from_parts(None, Vec::new(), name_servers),
+        ResolverOpts::default(),
+    ))
+}
+
+// every socket address a peer address stands for, a hostname gives the ones it resolves to
+async fn socket_addrs(addr: &str) -> Vec<SocketAddr> {
+    match lookup_host(addr).await {
+        Ok(addrs) => addrs.collect(),
+        Err(e) => {
+            debug!("Failed to resolve {}: {}", addr, e);
+            Vec::new()
+        }
+    }
+}
+
+// finds peers by resolving a dns name, like the headless service of a statefulset, and
+// joins the ones this node doesn't know yet every discovery_interval
+pub struct Discovery {
+    // the name and what resolves it, nothing without discovery_dns
+    lookup: Option<(String, Arc<dyn Resolver>)>,
+    interval: Duration,
+}
+
+impl Discovery {
+    pub fn new(config: &Config) -> Result<Self> {
+        if config.discovery_dns().is_none() {
+            return Ok(Discovery {
+                lookup: None,
+                interval: Duration::from_millis(config.discovery_interval().max(1)),
+            });
+        }
+        let resolver: Arc<dyn Resolver> = match config.discovery_record() {
+            DiscoveryRecord::A => Arc::new(DnsA {
+                resolver: dns_resolver(config)?,
+                port: config.discovery_port(),
+            }),
+            DiscoveryRecord::Srv => Arc::new(DnsSrv {
+                resolver: dns_resolver(config)?,
+            }),
+        };
+        Ok(Self::with_resolver(config, resolver))
+    }
+
+    // for looking peers up somewhere other than dns
+    pub fn with_resolver(config: &Config, resolver: Arc<dyn Resolver>) -> Self {
+        Discovery {
+            lookup: config
+                .discovery_dns()
+                .map(|name| (name.to_string(), resolver)),
+            interval: Duration::from_millis(config.discovery_interval().max(1)),
+        }
+    }
+
+    // addresses the name currently points at, without our own
+    pub async fn peers(&self, lally: &Lally) -> Vec<String> {
+        self.discover(&[lally.pool.local_addr()]).await
+    }
+
+    // addresses the name points at that aren't one of `known`, compared by socket address
+    // so a node known by its hostname isn't found again under its ip
+    async fn discover(&self, known: &[String]) -> Vec<String> {
+        let Some((name, resolver)) = &self.lookup else {
+            return Vec::new();
+        };
+        let addrs = match resolver.resolve(name).await {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                warn!("Failed to discover peers through {}: {:#}", name, e);
+                return Vec::new();
+            }
+        };
+        let mut known_addrs = HashSet::new();
+        for addr in known.iter().filter(|addr| !addr.is_empty()) {
+            known_addrs.extend(socket_addrs(addr).await);
+        }
+        let mut peers = Vec::new();
+        for addr in addrs {
+            if known.contains(&addr)
+                || socket_addrs(&addr)
+                    .await
+                    .iter()
+                    .any(|addr| known_addrs.contains(addr))
+            {
+                continue;
+            }
+            peers.push(addr);
+        }
+        peers.sort_unstable();
+        peers.dedup();
+        debug!("Discovered {} peers through {}", peers.len(), name);
+        peers
+    }
+
+    pub async fn run(lally: Arc<Lally>) {
+        let discovery = Arc::clone(&lally.discovery);
+        if discovery.lookup.is_none() {
+            return;
+        }
+        // startup already joined through whatever the name resolved to back then
+        let mut ticker = interval_at(Instant::now() + discovery.interval, discovery.interval);
+        loop {
+            ticker.tick().await;
+            let peers = lally.pool.peers();
+            let was_alone = peers.is_empty();
+            let mut known: Vec<String> = peers.into_iter().map(|peer| peer.addr).collect();
+            known.push(lally.pool.local_addr());
+            for addr in discovery.discover(&known).await {
+                info!("Discovered new peer at {}", addr);
+                if let Some(seed) = ClusterState::join_through(&lally, &[addr]).await {
+                    // a node that started out alone copies the cluster's keys like any joiner
+                    if was_alone {
+                        tokio::spawn(Bootstrap::run(Arc::clone(&lally), seed));
+                    }
+                    // the other addresses are likely in the cluster we just joined, the
+                    // next round sees whether they are
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // answers with whatever it was given, no dns involved
+    struct Stub(Vec<&'static str>);
+
+    impl Resolver for Stub {
+        fn resolve<'a>(&'a self, _name: &'a str) -> Resolving<'a> {
+            Box::pin(async move { Ok(self.0.iter().map(|addr| addr.to_string()).collect()) })
+        }
+    }
+
+    fn discovery(addrs: Vec<&'static str>) -> Discovery {
+        let config: Config = serde_yaml::from_str("discovery_dns: lally.test").unwrap();
+        Discovery::with_resolver(&config, Arc::new(Stub(addrs)))
+    }
+
+    #[tokio::test]
+    async fn finds_unknown_peers_once() {
+        let discovery = discovery(vec![
+            "127.0.0.1:50073",
+            "127.0.0.1:50072",
+            "127.0.0.1:50073",
+        ]);
+        assert_eq!(
+            discovery.discover(&[]).await,
+            vec!["127.0.0.1:50072", "127.0.0.1:50073"]
+        );
+    }
+
+    #[tokio::test]
+    async fn skips_known_peers_by_socket_address() {
+        let discovery = discovery(vec!["127.0.0.1:50071", "127.0.0.1:50072"]);
+        let known = vec!["localhost:50071".to_string(), String::new()];
+        assert_eq!(discovery.discover(&known).await, vec!["127.0.0.1:50072"]);
+    }
+
+    #[tokio::test]
+    async fn finds_nothing_without_a_name() {
+        let config = Config::default();
+        let discovery = Discovery::with_resolver(&config, Arc::new(Stub(vec!["127.0.0.1:50072"])));
+        assert!(discovery.discover(&[]).await.is_empty());
+    }
+}
@@ -0,0 +1,510 @@
+use crate::cluster::services::KvOperation;
+use crate::config::Config;
+use crate::lally::Lally;
+use crate::utils::timestamp::{create_timestamp, timestamp_from_rfc3339, timestamp_to_rfc3339};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use tokio::fs::{create_dir_all, read_dir, read_to_string, remove_file, rename, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tokio::task::JoinSet;
+use tokio::time::{interval, Duration};
+use tracing::{debug, error, info, warn};
+
+// a replica write that couldn't be delivered, kept on disk until the peer is back
+#[derive(Serialize, Deserialize)]
+struct Hint {
+    peer: String,
+    name: String,
+    level: String,
+    key: String,
+    value: Option<String>,
+    timestamp: String,
+    expires_at: i64,
+}
+
+impl Hint {
+    fn to_kv_operation(&self) -> Result<KvOperation> {
+        Ok(KvOperation {
+            name: self.name.clone(),
+            level: self.level.clone(),
+            key: self.key.clone(),
+            value: self.value.clone(),
+            timestamp: Some(
+                timestamp_from_rfc3339(&self.timestamp).context("Invalid hint timestamp")?,
+            ),
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct HandoffMetrics {
+    pub backlog: HashMap<String, usize>,
+    pub total_backlog: usize,
+    pub stored: u64,
+    pub replayed: u64,
+    pub expired: u64,
+}
+
+pub struct HintedHandoff {
+    dir: PathBuf,
+    ttl: Duration,
+    replay_interval: Duration,
+    // pending hints per peer, the lock also serializes every write to the hint files
+    backlog: Mutex<HashMap<String, usize>>,
+    // peers whose hints are being replayed right now
+    replaying: StdMutex<HashSet<String>>,
+    stored: AtomicU64,
+    replayed: AtomicU64,
+    expired: AtomicU64,
+}
+
+fn hint_file_name(peer: &str) -> String {
+    let sanitized: String = peer
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("{}.hints", sanitized)
+}
+
+async fn read_hints(path: &Path) -> Result<Vec<Hint>> {
+    let contents = read_to_string(path)
+        .await
+        .context("Failed to read hint file")?;
+    Ok(contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str::<Hint>(line) {
+            Ok(hint) => Some(hint),
+            Err(e) => {
+                error!("Skipping corrupt hint in {:?}: {}", path, e);
+                None
+            }
+        })
+        .collect())
+}
+
+async fn append_hints(path: &Path, hints: &[Hint]) -> Result<()> {
+    let mut buffer = String::new();
+    for hint in hints {
+        buffer.push_str(&serde_json::to_string(hint)?);
+        buffer.push('\n');
+    }
+    let mut file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .await
+        .context("Failed to open hint file")?;
+    file.write_all(buffer.as_bytes()).await?;
+    file.flush().await?;
+    Ok(())
+}
+
+// puts hints back in front of the ones stored since, so a peer still gets the writes to a
+// key oldest first. the merged file replaces the old one in one rename
+async fn prepend_hints(path: &Path, hints: &[Hint]) -> Result<()> {
+    if hints.is_empty() {
+        return Ok(());
+    }
+    let newer = if path.exists() {
+        read_hints(path).await?
+    } else {
+        Vec::new()
+    };
+    let merging = path.with_extension("merging");
+    if merging.exists() {
+        remove_file(&merging).await?;
+    }
+    append_hints(&merging, hints).await?;
+    append_hints(&merging, &newer).await?;
+    rename(&merging, path)
+        .await
+        .context("Failed to replace hint file")?;
+    Ok(())
+}
+
+// moves the hints of a replay that didn't finish back into the peer's hint file
+async fn restore(replaying: &Path) -> Result<()> {
+    let hints = read_hints(replaying).await?;
+    prepend_hints(&replaying.with_extension("hints"), &hints).await?;
+    remove_file(replaying).await?;
+    Ok(())
+}
+
+// picks up whatever was left over from the last run, including a replay that got cut
+// short; a half written merge is redone from the replaying file it came from
+async fn recover(dir: &Path) -> Result<HashMap<String, usize>> {
+    let mut entries = read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == "merging") {
+            remove_file(&path).await?;
+        }
+    }
+    let mut entries = read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == "replaying") {
+            restore(&path).await?;
+        }
+    }
+
+    let mut backlog = HashMap::new();
+    let mut entries = read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "hints") {
+            continue;
+        }
+        let hints = read_hints(&path).await?;
+        if let Some(hint) = hints.first() {
+            info!("Found {} pending hints for {}", hints.len(), hint.peer);
+            backlog.insert(hint.peer.clone(), hints.len());
+        }
+    }
+    Ok(backlog)
+}
+
+struct Replayed {
+    delivered: u64,
+    expired: u64,
+    remaining: Vec<Hint>,
+}
+
+// hands the hints over oldest first and keeps everything from the first failure on, so the
+// peer never sees a newer write to a key before an older one
+async fn deliver_in_order<F, Fut>(
+    peer: &str,
+    hints: Vec<Hint>,
+    now: i64,
+    mut deliver: F,
+) -> Replayed
+where
+    F: FnMut(KvOperation) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let mut replayed = Replayed {
+        delivered: 0,
+        expired: 0,
+        remaining: Vec::new(),
+    };
+    let mut reachable = true;
+    for hint in hints {
+        if hint.expires_at < now {
+            replayed.expired += 1;
+            continue;
+        }
+        if !reachable {
+            replayed.remaining.push(hint);
+            continue;
+        }
+        let operation = match hint.to_kv_operation() {
+            Ok(operation) => operation,
+            Err(e) => {
+                error!(peer = %peer, key = %hint.key, "Dropping unreadable hint: {:#}", e);
+                continue;
+            }
+        };
+        match deliver(operation).await {
+            Ok(()) => replayed.delivered += 1,
+            Err(e) => {
+                debug!(peer = %peer, "Peer still unreachable: {:#}", e);
+                reachable = false;
+                replayed.remaining.push(hint);
+            }
+        }
+    }
+    replayed
+}
+
+impl HintedHandoff {
+    pub async fn new(config: &Config) -> Result<Self> {
+        let dir = config.hints_dir().to_path_buf();
+        create_dir_all(&dir)
+            .await
+            .context("Failed to create hints directory")?;
+        let backlog = recover(&dir).await?;
+
+        Ok(HintedHandoff {
+            dir,
+            ttl: Duration::from_secs(config.hint_ttl()),
+            replay_interval: Duration::from_millis(config.hint_replay_interval()),
+            backlog: Mutex::new(backlog),
+            replaying: StdMutex::new(HashSet::new()),
+            stored: AtomicU64::new(0),
+            replayed: AtomicU64::new(0),
+            expired: AtomicU64::new(0),
+        })
+    }
+
+    pub async fn store(&self, peer: &str, operation: &KvOperation) {
+        let Some(timestamp) = operation.timestamp.as_ref() else {
+            error!("Refusing to store a hint without a timestamp");
+            return;
+        };
+        let hint = Hint {
+            peer: peer.to_string(),
+            name: operation.name.clone(),
+            level: operation.level.clone(),
+            key: operation.key.clone(),
+            value: operation.value.clone(),
+            timestamp: timestamp_to_rfc3339(timestamp),
+            expires_at: create_timestamp().seconds + self.ttl.as_secs() as i64,
+        };
+
+        let mut backlog = self.backlog.lock().await;
+        match append_hints(&self.dir.join(hint_file_name(peer)), &[hint]).await {
+            Ok(()) => {
+                *backlog.entry(peer.to_string()).or_default() += 1;
+                self.stored.fetch_add(1, Ordering::Relaxed);
+                debug!(peer = %peer, key = %operation.key, "Stored hint for unreachable replica");
+            }
+            Err(e) => error!(peer = %peer, "Failed to store hint: {:#}", e),
+        }
+    }
+
+    pub async fn metrics(&self) -> HandoffMetrics {
+        let backlog = self.backlog.lock().await.clone();
+        HandoffMetrics {
+            total_backlog: backlog.values().sum(),
+            backlog,
+            stored: self.stored.load(Ordering::Relaxed),
+            replayed: self.replayed.load(Ordering::Relaxed),
+            expired: self.expired.load(Ordering::Relaxed),
+        }
+    }
+
+    pub async fn run(lally: Arc<Lally>) {
+        let handoff = Arc::clone(&lally.handoff);
+        let mut interval = interval(handoff.replay_interval);
+        loop {
+            interval.tick().await;
+            Self::flush(&lally).await;
+        }
+    }
+
+    // tries every peer with pending hints once and returns how many are still pending. peers
+    // are replayed side by side, so one that hangs doesn't hold up the others
+    pub async fn flush(lally: &Arc<Lally>) -> usize {
+        let handoff = &lally.handoff;
+        let peers: Vec<String> = handoff
+            .backlog
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, pending)| **pending > 0)
+            .map(|(peer, _)| peer.clone())
+            .collect();
+        let mut replays = JoinSet::new();
+        for peer in peers {
+            // the interval and a decommission can both be flushing
+            if !handoff
+                .replaying
+                .lock()
+                .expect("replaying lock poisoned")
+                .insert(peer.clone())
+            {
+                continue;
+            }
+            let lally = Arc::clone(lally);
+            replays.spawn(async move {
+                if let Err(e) = lally.handoff.replay(&lally, &peer).await {
+                    error!(peer = %peer, "Failed to replay hints: {:#}", e);
+                }
+                lally
+                    .handoff
+                    .replaying
+                    .lock()
+                    .expect("replaying lock poisoned")
+                    .remove(&peer);
+            });
+        }
+        while let Some(result) = replays.join_next().await {
+            if let Err(e) = result {
+                error!("Task panicked: {:?}", e);
+            }
+        }
+        handoff.backlog.lock().await.values().sum()
+    }
+
+    // delivers the hints of a peer in order, stopping at the first failure since that
+    // means the peer is still unreachable; whatever is left goes back on disk ahead of the
+    // hints stored meanwhile. if that fails, the replaying file stays and is restored by the
+    // next replay
+    async fn replay(&self, lally: &Lally, peer: &str) -> Result<()> {
+        let path = self.dir.join(hint_file_name(peer));
+        let replaying = path.with_extension("replaying");
+
+        // move the file aside so new hints can keep coming in while this one drains
+        let hints = {
+            let mut backlog = self.backlog.lock().await;
+            if replaying.exists() {
+                restore(&replaying)
+                    .await
+                    .context("Failed to restore hints of an earlier replay")?;
+            }
+            if !path.exists() {
+                backlog.remove(peer);
+                return Ok(());
+            }
+            rename(&path, &replaying).await?;
+            match read_hints(&replaying).await {
+                Ok(hints) => {
+                    backlog.insert(peer.to_string(), 0);
+                    hints
+                }
+                Err(e) => {
+                    // nothing was stored while the lock was held, so the name is still free
+                    rename(&replaying, &path).await?;
+                    return Err(e);
+                }
+            }
+        };
+
+        let Replayed {
+            delivered,
+            expired,
+            remaining,
+        } = deliver_in_order(peer, hints, create_timestamp().seconds, |operation| {
+            lally.pool.deliver_hint(peer, operation)
+        })
+        .await;
+
+        let mut backlog = self.backlog.lock().await;
+        *backlog.entry(peer.to_string()).or_default() += remaining.len();
+        prepend_hints(&path, &remaining).await?;
+        remove_file(&replaying).await?;
+
+        self.replayed.fetch_add(delivered, Ordering::Relaxed);
+        self.expired.fetch_add(expired, Ordering::Relaxed);
+        if delivered > 0 {
+            info!(peer = %peer, "Replayed {} hints", delivered);
+        }
+        if expired > 0 {
+            warn!(peer = %peer, "Dropped {} expired hints", expired);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    fn hint(key: &str, expires_at: i64) -> Hint {
+        Hint {
+            peer: String::from("peer"),
+            name: String::from("ADD"),
+            level: String::from("INFO"),
+            key: key.to_string(),
+            value: Some(String::from("value")),
+            timestamp: timestamp_to_rfc3339(&create_timestamp()),
+            expires_at,
+        }
+    }
+
+    fn keys(hints: &[Hint]) -> Vec<&str> {
+        hints.iter().map(|hint| hint.key.as_str()).collect()
+    }
+
+    // an empty directory of its own for every test
+    async fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lally-{}-{}", name, std::process::id()));
+        if dir.exists() {
+            tokio::fs::remove_dir_all(&dir).await.unwrap();
+        }
+        create_dir_all(&dir).await.unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn hints_are_delivered_oldest_first() {
+        let hints = vec![hint("a", 100), hint("b", 100), hint("c", 100)];
+        let mut sent = Vec::new();
+        let replayed = deliver_in_order("peer", hints, 0, |operation| {
+            sent.push(operation.key);
+            async { Ok(()) }
+        })
+        .await;
+        assert_eq!(sent, ["a", "b", "c"]);
+        assert_eq!(replayed.delivered, 3);
+        assert!(replayed.remaining.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_failed_delivery_keeps_it_and_everything_after_it() {
+        let hints = vec![hint("a", 100), hint("b", 100), hint("c", 100)];
+        let mut attempts = Vec::new();
+        let replayed = deliver_in_order("peer", hints, 0, |operation| {
+            let fail = operation.key == "b";
+            attempts.push(operation.key);
+            async move {
+                if fail {
+                    Err(anyhow!("unreachable"))
+                } else {
+                    Ok(())
+                }
+            }
+        })
+        .await;
+        // c isn't tried once b failed, it would overtake b
+        assert_eq!(attempts, ["a", "b"]);
+        assert_eq!(replayed.delivered, 1);
+        assert_eq!(keys(&replayed.remaining), ["b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn expired_hints_are_dropped() {
+        let hints = vec![hint("a", 5), hint("b", 20)];
+        let replayed = deliver_in_order("peer", hints, 10, |_| async { Ok(()) }).await;
+        assert_eq!(replayed.expired, 1);
+        assert_eq!(replayed.delivered, 1);
+    }
+
+    #[tokio::test]
+    async fn restored_hints_go_ahead_of_newer_ones() {
+        let dir = scratch("handoff-restore").await;
+        let path = dir.join(hint_file_name("peer"));
+        let replaying = path.with_extension("replaying");
+        append_hints(&replaying, &[hint("a", 100), hint("b", 100)])
+            .await
+            .unwrap();
+        // stored while the replay was running
+        append_hints(&path, &[hint("c", 100)]).await.unwrap();
+
+        restore(&replaying).await.unwrap();
+
+        assert!(!replaying.exists());
+        assert_eq!(keys(&read_hints(&path).await.unwrap()), ["a", "b", "c"]);
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_replay_cut_short_is_restored_on_startup() {
+        let dir = scratch("handoff-startup").await;
+        let path = dir.join(hint_file_name("peer"));
+        append_hints(&path.with_extension("replaying"), &[hint("a", i64::MAX)])
+            .await
+            .unwrap();
+        append_hints(&path, &[hint("b", i64::MAX)]).await.unwrap();
+        // a merge that was interrupted is redone from the replaying file
+        append_hints(&path.with_extension("merging"), &[hint("a", i64::MAX)])
+            .await
+            .unwrap();
+
+        let backlog = recover(&dir).await.unwrap();
+
+        assert_eq!(keys(&read_hints(&path).await.unwrap()), ["a", "b"]);
+        assert!(!path.with_extension("replaying").exists());
+        assert!(!path.with_extension("merging").exists());
+        assert_eq!(backlog.get("peer"), Some(&2));
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -1,5 +1,6 @@
 use crate::hooks::Hook;
 use crate::utils::Operation;
+use anyhow::Result;
 use std::sync::Arc;
 use std::sync::RwLock;
 use tracing::{debug, info, span, Level};
@@ -29,4 +30,16 @@ impl Hooks {
             hook.invoke(operation);
         }
     }
+
+    pub fn intercept_all(&self, operation: &mut Operation) -> Result<()> {
+        let hooks = self.hooks.read().expect("hooks lock poisoned");
+        let trace_span = span!(Level::DEBUG, "HOOKS");
+        let _enter = trace_span.enter();
+
+        debug!(key = %operation.key, "Intercepting the {} operation", operation.name);
+        for hook in hooks.iter() {
+            hook.intercept(operation)?;
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,567 @@
+use crate::cluster::services::{MemberStatus, MemberUpdate, NodeInfo};
+use crate::config::Config;
+use crate::lally::detector::PeerState;
+use crate::lally::Lally;
+use crate::utils::timestamp::create_timestamp;
+use anyhow::{bail, Result};
+use rand::seq::SliceRandom;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::task::JoinSet;
+use tokio::time::{interval, timeout, Duration};
+use tracing::{debug, error, info, warn};
+
+// ordered by precedence, on equal incarnations the later state wins
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MemberState {
+    Alive,
+    Suspect,
+    Dead,
+    Left,
+}
+
+impl From<MemberStatus> for MemberState {
+    fn from(status: MemberStatus) -> Self {
+        match status {
+            MemberStatus::Alive => MemberState::Alive,
+            MemberStatus::Suspect => MemberState::Suspect,
+            MemberStatus::Dead => MemberState::Dead,
+            MemberStatus::Left => MemberState::Left,
+        }
+    }
+}
+
+impl From<MemberState> for MemberStatus {
+    fn from(state: MemberState) -> Self {
+        match state {
+            MemberState::Alive => MemberStatus::Alive,
+            MemberState::Suspect => MemberStatus::Suspect,
+            MemberState::Dead => MemberStatus::Dead,
+            MemberState::Left => MemberStatus::Left,
+        }
+    }
+}
+
+struct Member {
+    addr: String,
+    incarnation: u64,
+    state: MemberState,
+    since: Instant,
+}
+
+// what a merged update means for the rest of lally
+enum MembershipEvent {
+    Joined(NodeInfo),
+    Died(String),
+    Left(String),
+}
+
+// SWIM-style membership: every node keeps a view of the cluster where each member has an
+// incarnation number, views are exchanged with random peers and merged, and a node that
+// hears it's being suspected refutes it by bumping its own incarnation. leaving is final, a
+// node that hears it was removed stops serving instead of refuting it
+pub struct Membership {
+    gossip_interval: Duration,
+    ping_timeout: Duration,
+    indirect_probes: usize,
+    suspect_timeout: Duration,
+    incarnation: AtomicU64,
+    removed: AtomicBool,
+    members: Mutex<HashMap<String, Member>>,
+}
+
+impl Membership {
+    pub fn new(config: &Config) -> Self {
+        Membership {
+            gossip_interval: Duration::from_millis(config.gossip_interval().max(1)),
+            // a peer that's up but doesn't answer would hold up the whole round otherwise,
+            // keepalive only notices connections that died
+            ping_timeout: Duration::from_millis(config.heartbeat_interval().max(1)),
+            indirect_probes: config.indirect_probes(),
+            suspect_timeout: Duration::from_millis(config.suspect_timeout()),
+            // starting from the clock means a restarted node always outranks what the
+            // cluster remembers about its previous life
+            incarnation: AtomicU64::new(create_timestamp().seconds.max(0) as u64),
+            removed: AtomicBool::new(false),
+            members: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn ping_timeout(&self) -> Duration {
+        self.ping_timeout
+    }
+
+    pub fn incarnation(&self) -> u64 {
+        self.incarnation.load(Ordering::Relaxed)
+    }
+
+    // whether the cluster removed this node, it takes a restart to join again
+    pub fn is_removed(&self) -> bool {
+        self.removed.load(Ordering::Relaxed)
+    }
+
+    pub fn state(&self, id: &str) -> Option<MemberState> {
+        let members = self.members.lock().expect("membership lock poisoned");
+        members.get(id).map(|member| member.state)
+    }
+
+    // the full view, including this node, as sent to other nodes
+    pub fn digest(&self, lally: &Lally) -> Vec<MemberUpdate> {
+        let local = lally.pool.local_node();
+        let peers: HashMap<String, NodeInfo> = lally
+            .pool
+            .peers()
+            .into_iter()
+            .map(|peer| (peer.id.clone(), peer))
+            .collect();
+        let mut members = self.members.lock().expect("membership lock poisoned");
+
+        // anything that made it into the pool some other way (a join, an add_node) is alive
+        for peer in peers.values() {
+            members.entry(peer.id.clone()).or_insert_with(|| Member {
+                addr: peer.addr.clone(),
+                incarnation: 0,
+                state: MemberState::Alive,
+                since: Instant::now(),
+            });
+        }
+
+        let mut digest: Vec<MemberUpdate> = members
+            .iter()
+            .filter(|(id, _)| **id != local.id)
+            .map(|(id, member)| {
+                let peer = peers.get(id);
+                MemberUpdate {
+                    id: id.clone(),
+                    addr: member.addr.clone(),
+                    incarnation: member.incarnation,
+                    status: MemberStatus::from(member.state).into(),
+                    protocol: peer.and_then(|peer| peer.protocol.clone()),
+                    zone: peer.map(|peer| peer.zone.clone()).unwrap_or_default(),
+                }
+            })
+            .collect();
+        digest.push(MemberUpdate {
+            id: local.id,
+            addr: local.addr,
+            incarnation: self.incarnation(),
+            status: MemberStatus::Alive.into(),
+            protocol: local.protocol,
+            zone: local.zone,
+        });
+        digest
+    }
+
+    fn record(
+        &self,
+        members: &mut HashMap<String, Member>,
+        id: &str,
+        addr: &str,
+        incarnation: u64,
+        state: MemberState,
+    ) -> Option<MembershipEvent> {
+        let previous = members
+            .get(id)
+            .map(|m| (m.incarnation, m.state, m.addr.clone()));
+        let newer = match previous {
+            None => true,
+            Some((known_incarnation, known_state, _)) => {
+                incarnation > known_incarnation
+                    || (incarnation == known_incarnation && state > known_state)
+            }
+        };
+        if !newer {
+            return None;
+        }
+        members.insert(
+            id.to_string(),
+            Member {
+                addr: addr.to_string(),
+                incarnation,
+                state,
+                since: Instant::now(),
+            },
+        );
+
+        let was = previous.as_ref().map(|(_, state, _)| *state);
+        // a node coming back under a new address needs a new connection too
+        let moved = previous.is_some_and(|(_, _, known_addr)| known_addr != addr);
+        match state {
+            MemberState::Alive | MemberState::Suspect
+                if moved || !matches!(was, Some(MemberState::Alive | MemberState::Suspect)) =>
+            {
+                Some(MembershipEvent::Joined(NodeInfo {
+                    id: id.to_string(),
+                    addr: addr.to_string(),
+                    protocol: None,
+                    zone: String::new(),
+                }))
+            }
+            MemberState::Dead if was != Some(MemberState::Dead) => {
+                Some(MembershipEvent::Died(id.to_string()))
+            }
+            MemberState::Left if was != Some(MemberState::Left) => {
+                Some(MembershipEvent::Left(id.to_string()))
+            }
+            _ => None,
+        }
+    }
+
+    pub async fn merge(&self, lally: &Lally, updates: Vec<MemberUpdate>) {
+        let local = lally.pool.local_id();
+        let events: Vec<MembershipEvent> = {
+            let mut members = self.members.lock().expect("membership lock poisoned");
+            updates
+                .into_iter()
+                .filter_map(|update| {
+                    let state = MemberState::from(update.status());
+                    if update.id == local {
+                        self.hear_about_self(state, update.incarnation);
+                        return None;
+                    }
+                    // a departed node may be gossiped without an address, a live one can't
+                    let reachable = state >= MemberState::Dead || !update.addr.is_empty();
+                    if update.id.is_empty() || !reachable {
+                        return None;
+                    }
+                    let event = self.record(
+                        &mut members,
+                        &update.id,
+                        &update.addr,
+                        update.incarnation,
+                        state,
+                    );
+                    // the member comes with the version and zone it has, so it doesn't count
+                    // as featureless and zoneless until our first ping gets through
+                    match event {
+                        Some(MembershipEvent::Joined(node)) => {
+                            Some(MembershipEvent::Joined(NodeInfo {
+                                protocol: update.protocol,
+                                zone: update.zone,
+                                ..node
+                            }))
+                        }
+                        event => event,
+                    }
+                })
+                .collect()
+        };
+        self.apply(lally, events).await;
+    }
+
+    fn hear_about_self(&self, state: MemberState, incarnation: u64) {
+        if state == MemberState::Left {
+            if !self.removed.swap(true, Ordering::Relaxed) {
+                error!("This node was removed from the cluster, it stops serving until it's restarted and joins again");
+            }
+            return;
+        }
+        // somebody thinks we're in trouble, outrank the rumour
+        if state != MemberState::Alive && !self.is_removed() && incarnation >= self.incarnation() {
+            let refuted = incarnation + 1;
+            self.incarnation.store(refuted, Ordering::Relaxed);
+            info!("Refuting {:?} rumour with incarnation {}", state, refuted);
+        }
+    }
+
+    async fn apply(&self, lally: &Lally, events: Vec<MembershipEvent>) {
+        for event in events {
+            match event {
+                MembershipEvent::Joined(node) => {
+                    info!(node = %node.id, addr = %node.addr, "Learned about member through gossip");
+                    // older nodes don't gossip versions or zones, the first ping tells us then
+                    if let Err(e) = lally.pool.conn_make(&node) {
+                        error!(node = %node.id, "Failed to connect to gossiped member: {:#}", e);
+                    }
+                }
+                MembershipEvent::Died(id) => {
+                    warn!(node = %id, "Member declared dead");
+                    lally.detector.convict(&id);
+                }
+                MembershipEvent::Left(id) => {
+                    info!(node = %id, "Member left the cluster");
+                    let _ = lally.pool.remove(&id);
+                }
+            }
+        }
+    }
+
+    // takes a node out of the whole cluster on an admin's word, for nodes that are gone for
+    // good; returns the peers that couldn't be told, they hear about it through gossip
+    pub async fn remove_member(
+        lally: &Lally,
+        id: &str,
+        rereplicate: bool,
+    ) -> Result<Vec<(String, String)>> {
+        if !lally.pool.get_ids().iter().any(|peer| peer == id) {
+            bail!("Node {} is not part of the cluster", id);
+        }
+        info!(node = %id, rereplicate, "Removing member from the cluster");
+        if !rereplicate {
+            lally.rebalancer.hold_next();
+        }
+        lally.membership.mark_left(id);
+        lally.pool.remove(id)?;
+        Ok(lally.pool.evict(id, !rereplicate).await)
+    }
+
+    // recorded at the current time, which outranks every incarnation the node had so far
+    // since those start at its boot time; only a restart, starting later, outranks it again
+    pub fn mark_left(&self, id: &str) {
+        let mut members = self.members.lock().expect("membership lock poisoned");
+        let (addr, incarnation) = members
+            .get(id)
+            .map_or((String::new(), 0), |m| (m.addr.clone(), m.incarnation));
+        let incarnation = incarnation.max(create_timestamp().seconds.max(0) as u64);
+        self.record(&mut members, id, &addr, incarnation, MemberState::Left);
+    }
+
+    fn suspect(&self, id: &str) {
+        let mut members = self.members.lock().expect("membership lock poisoned");
+        let Some((addr, incarnation)) = members
+            .get(id)
+            .filter(|m| m.state == MemberState::Alive)
+            .map(|m| (m.addr.clone(), m.incarnation))
+        else {
+            return;
+        };
+        warn!(node = %id, "Suspecting member after failed direct and indirect probes");
+        self.record(&mut members, id, &addr, incarnation, MemberState::Suspect);
+    }
+
+    // suspects that didn't refute within the timeout are declared dead
+    fn expire_suspects(&self) -> Vec<MembershipEvent> {
+        let mut members = self.members.lock().expect("membership lock poisoned");
+        let expired: Vec<(String, String, u64)> = members
+            .iter()
+            .filter(|(_, m)| {
+                m.state == MemberState::Suspect && m.since.elapsed() >= self.suspect_timeout
+            })
+            .map(|(id, m)| (id.clone(), m.addr.clone(), m.incarnation))
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|(id, addr, incarnation)| {
+                self.record(&mut members, &id, &addr, incarnation, MemberState::Dead)
+            })
+            .collect()
+    }
+
+    fn alive_peers(&self, lally: &Lally) -> Vec<String> {
+        let members = self.members.lock().expect("membership lock poisoned");
+        lally
+            .pool
+            .get_ids()
+            .into_iter()
+            .filter(|id| {
+                members
+                    .get(id)
+                    .is_none_or(|m| m.state == MemberState::Alive)
+            })
+            .collect()
+    }
+
+    // asks a few other members to ping the target for us, so a broken link between the
+    // two of us alone doesn't get the target suspected
+    async fn probe_indirectly(&self, lally: &Arc<Lally>, target: &str) -> bool {
+        let helpers: Vec<String> = {
+            let candidates: Vec<String> = self
+                .alive_peers(lally)
+                .into_iter()
+                .filter(|id| id != target)
+                .collect();
+            candidates
+                .choose_multiple(&mut rand::thread_rng(), self.indirect_probes)
+                .cloned()
+                .collect()
+        };
+        // the helper gets a ping timeout of its own for the target, and one more to answer us
+        let deadline = self.ping_timeout * 2;
+        let mut probes = JoinSet::new();
+        for helper in helpers {
+            let lally = Arc::clone(lally);
+            let target = target.to_string();
+            probes.spawn(
+                async move { timeout(deadline, lally.pool.ping_req(&helper, &target)).await },
+            );
+        }
+        while let Some(result) = probes.join_next().await {
+            if let Ok(Ok(Ok(true))) = result {
+                return true;
+            }
+        }
+        false
+    }
+
+    pub async fn run(lally: Arc<Lally>) {
+        let membership = Arc::clone(&lally.membership);
+        let mut ticker = interval(membership.gossip_interval);
+        loop {
+            ticker.tick().await;
+            // nothing to gossip about until there's a peer, and peers need our address first;
+            // a removed node keeps quiet so it doesn't talk its way back in
+            if membership.is_removed()
+                || lally.pool.get_ids().is_empty()
+                || lally.pool.local_addr().is_empty()
+            {
+                continue;
+            }
+
+            // probe the peers the failure detector has doubts about
+            for peer in membership.alive_peers(&lally) {
+                if lally.detector.state(&peer) == PeerState::Alive {
+                    continue;
+                }
+                if membership.probe_indirectly(&lally, &peer).await {
+                    debug!(peer = %peer, "Indirect probe succeeded");
+                    lally.detector.heartbeat(&peer);
+                } else {
+                    membership.suspect(&peer);
+                }
+            }
+            let expired = membership.expire_suspects();
+            membership.apply(&lally, expired).await;
+
+            // push our view to a random peer and pull theirs
+            let target = membership
+                .alive_peers(&lally)
+                .choose(&mut rand::thread_rng())
+                .cloned();
+            if let Some(target) = target {
+                let digest = membership.digest(&lally);
+                match timeout(
+                    membership.ping_timeout,
+                    lally.pool.exchange_gossip(&target, digest),
+                )
+                .await
+                {
+                    Ok(Ok(theirs)) => membership.merge(&lally, theirs).await,
+                    Ok(Err(e)) => debug!(peer = %target, "Gossip exchange failed: {:#}", e),
+                    Err(_) => debug!(peer = %target, "Gossip exchange timed out"),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn membership() -> Membership {
+        Membership::new(&Config::default())
+    }
+
+    fn record(
+        membership: &Membership,
+        id: &str,
+        addr: &str,
+        incarnation: u64,
+        state: MemberState,
+    ) -> Option<MembershipEvent> {
+        let mut members = membership.members.lock().unwrap();
+        membership.record(&mut members, id, addr, incarnation, state)
+    }
+
+    #[test]
+    fn a_new_member_joins() {
+        let membership = membership();
+        let event = record(&membership, "b", "10.0.0.2:50071", 1, MemberState::Alive);
+        assert!(
+            matches!(event, Some(MembershipEvent::Joined(node)) if node.addr == "10.0.0.2:50071")
+        );
+        assert_eq!(membership.state("b"), Some(MemberState::Alive));
+    }
+
+    #[test]
+    fn a_worse_state_wins_on_the_same_incarnation() {
+        let membership = membership();
+        record(&membership, "b", "b:1", 3, MemberState::Alive);
+        assert!(record(&membership, "b", "b:1", 3, MemberState::Suspect).is_none());
+        assert_eq!(membership.state("b"), Some(MemberState::Suspect));
+
+        // an alive rumour of the same age doesn't clear the suspicion
+        assert!(record(&membership, "b", "b:1", 3, MemberState::Alive).is_none());
+        assert_eq!(membership.state("b"), Some(MemberState::Suspect));
+
+        let event = record(&membership, "b", "b:1", 3, MemberState::Dead);
+        assert!(matches!(event, Some(MembershipEvent::Died(id)) if id == "b"));
+    }
+
+    #[test]
+    fn a_newer_incarnation_wins_over_any_state() {
+        let membership = membership();
+        record(&membership, "b", "b:1", 3, MemberState::Dead);
+        assert!(record(&membership, "b", "b:1", 2, MemberState::Alive).is_none());
+        assert_eq!(membership.state("b"), Some(MemberState::Dead));
+
+        let event = record(&membership, "b", "b:1", 4, MemberState::Alive);
+        assert!(matches!(event, Some(MembershipEvent::Joined(_))));
+        assert_eq!(membership.state("b"), Some(MemberState::Alive));
+    }
+
+    #[test]
+    fn a_member_under_a_new_address_joins_again() {
+        let membership = membership();
+        record(&membership, "b", "b:1", 3, MemberState::Alive);
+        assert!(record(&membership, "b", "b:1", 4, MemberState::Alive).is_none());
+        let event = record(&membership, "b", "b:2", 5, MemberState::Alive);
+        assert!(matches!(event, Some(MembershipEvent::Joined(node)) if node.addr == "b:2"));
+    }
+
+    #[test]
+    fn a_left_member_stays_gone_until_it_restarts() {
+        let membership = membership();
+        record(&membership, "b", "b:1", 3, MemberState::Alive);
+        membership.mark_left("b");
+        assert_eq!(membership.state("b"), Some(MemberState::Left));
+
+        // what the node gossiped before it was removed doesn't bring it back
+        assert!(record(&membership, "b", "b:1", 4, MemberState::Alive).is_none());
+        assert_eq!(membership.state("b"), Some(MemberState::Left));
+
+        let restarted = create_timestamp().seconds as u64 + 60;
+        let event = record(&membership, "b", "b:1", restarted, MemberState::Alive);
+        assert!(matches!(event, Some(MembershipEvent::Joined(_))));
+    }
+
+    #[test]
+    fn a_rumour_about_us_is_refuted_with_a_higher_incarnation() {
+        let membership = membership();
+        let incarnation = membership.incarnation();
+
+        membership.hear_about_self(MemberState::Suspect, incarnation);
+        assert_eq!(membership.incarnation(), incarnation + 1);
+
+        membership.hear_about_self(MemberState::Dead, incarnation + 5);
+        assert_eq!(membership.incarnation(), incarnation + 6);
+    }
+
+    #[test]
+    fn stale_or_harmless_rumours_about_us_are_ignored() {
+        let membership = membership();
+        let incarnation = membership.incarnation();
+
+        membership.hear_about_self(MemberState::Suspect, incarnation - 1);
+        membership.hear_about_self(MemberState::Alive, incarnation + 5);
+        assert_eq!(membership.incarnation(), incarnation);
+    }
+
+    #[test]
+    fn leaving_is_never_refuted() {
+        let membership = membership();
+        let incarnation = membership.incarnation();
+
+        membership.hear_about_self(MemberState::Left, incarnation + 5);
+        assert!(membership.is_removed());
+        assert_eq!(membership.incarnation(), incarnation);
+
+        // once removed, suspicion isn't argued with either
+        membership.hear_about_self(MemberState::Suspect, incarnation + 5);
+        assert_eq!(membership.incarnation(), incarnation);
+    }
+}
@@ -1,45 +1,609 @@
+use crate::auth::{Auth, Scope, Signed};
+use crate::cluster::services::anti_entropy_client::AntiEntropyClient;
 use crate::cluster::services::cluster_management_client::ClusterManagementClient;
 use crate::cluster::services::kv_store_client::KvStoreClient;
+use crate::cluster::services::rebalance_client::RebalanceClient;
 use crate::cluster::services::{
-    AddKvResponse, AddNodeRequest, GetKvResponse, KvData, KvOperation, NoContentRequest,
-    RemoveKvResponse,
+    AddKvResponse, AddNodeRequest, BootstrapChunk, BootstrapRequest, GetKvResponse, GossipRequest,
+    JoinRequest, KvData, KvOperation, MemberUpdate, MerkleNodesRequest, NoContentRequest, NodeInfo,
+    NodeStats, PingReqRequest, Protocol, RemoveKvResponse, RemoveNodeRequest, SyncRangeRequest,
+    TransferRequest,
 };
+use crate::config::{Config, QuorumMode};
+use crate::lally::channels::Channels;
+use crate::lally::consistency::Quorum;
+use crate::lally::detector::FailureDetector;
+use crate::lally::handoff::HintedHandoff;
+use crate::lally::protocol::{self, Feature};
+use crate::lally::replication::{ReplicaStream, ReplicationStreams, StreamMetrics};
+use crate::lally::retry::{CallError, Retry};
+use crate::lally::ring::HashRing;
+use crate::tls::Tls;
 use crate::utils::Operation;
 use anyhow::{anyhow, Context, Result};
 use papaya::HashMap;
+use prost_types::Timestamp;
 use rapidhash::fast::RandomState;
+use serde::Serialize;
+use std::collections::HashMap as StdHashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use tokio::sync::Notify;
 use tokio::task::JoinSet;
+use tokio::time::{timeout, Duration};
 use tonic::transport::{Channel, Uri};
-use tonic::Request;
-use tracing::{debug, error, info, span, Level};
+use tonic::{Code, Request, Streaming};
+use tower::service_fn;
+use tracing::{debug, error, info, span, warn, Level};
 
-type PoolMap = HashMap<String, Channel, RandomState>;
+// a peer is keyed by its node id, the address is just where it can be reached right now
+#[derive(Clone)]
+struct Peer {
+    addr: String,
+    channel: Channel,
+    // what the peer speaks, as it last told us
+    protocol: Protocol,
+}
+
+type PoolMap = HashMap<String, Peer, RandomState>;
+
+// who let a node in and which cluster it turned out to be
+pub struct Joined {
+    pub seed: String,
+    pub cluster_id: String,
+}
+
+// what the replicas of a request answered, the ones that didn't are split by whether they
+// ran out of time or failed outright
+pub struct Replies<T> {
+    pub responses: Vec<T>,
+    // the replicas behind the responses
+    pub acked: Vec<String>,
+    pub timed_out: Vec<String>,
+    pub failed: Vec<String>,
+}
+
+impl<T> Default for Replies<T> {
+    fn default() -> Self {
+        Replies {
+            responses: Vec::new(),
+            acked: Vec::new(),
+            timed_out: Vec::new(),
+            failed: Vec::new(),
+        }
+    }
+}
+
+// the replicas of a read that were still answering when the read had its quorum, read
+// repair wants to see what they have all the same
+pub struct LateReplies(JoinSet<(String, Result<GetKvResponse, CallError>)>);
+
+impl LateReplies {
+    fn of(futures_set: JoinSet<(String, Result<GetKvResponse, CallError>)>) -> Option<Self> {
+        (!futures_set.is_empty()).then_some(LateReplies(futures_set))
+    }
+
+    pub async fn collect(mut self) -> Vec<(String, GetKvResponse)> {
+        let mut responses = Vec::new();
+        while let Some(result) = self.0.join_next().await {
+            match result {
+                Ok((ip, Ok(response))) => responses.push((ip, response)),
+                Ok((ip, Err(e))) => debug!("Late read from {} failed: {}", ip, e),
+                Err(e) => error!("Task panicked: {:?}", e),
+            }
+        }
+        responses
+    }
+}
+
+impl<T> Replies<T> {
+    fn missed(&mut self, id: String, error: CallError) {
+        match error {
+            CallError::TimedOut => self.timed_out.push(id),
+            CallError::Failed(_) => self.failed.push(id),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ReplicationMetrics {
+    pub in_flight: u64,
+    pub completed: u64,
+    pub timed_out: u64,
+    pub failed: u64,
+}
+
+// writes still replicating after the client got its ack, and how they ended
+#[derive(Default)]
+struct Background {
+    in_flight: AtomicU64,
+    completed: AtomicU64,
+    timed_out: AtomicU64,
+    failed: AtomicU64,
+}
+
+// where a key lives: whether this node is one of its replicas, which peers are, and the
+// zone of each of them
+pub struct Placement {
+    pub local: bool,
+    pub peers: Vec<String>,
+    pub zones: StdHashMap<String, String>,
+}
+
+impl Placement {
+    pub fn zone(&self, id: &str) -> &str {
+        self.zones.get(id).map(String::as_str).unwrap_or_default()
+    }
+}
 
 pub struct Pool {
     pool: PoolMap,
+    ring: RwLock<HashRing>,
+    local_id: String,
+    // empty until it's either configured or learned from the first join
+    local_addr: RwLock<String>,
+    grpc_port: u16,
+    replication_factor: usize,
+    read_quorum: usize,
+    write_quorum: usize,
+    quorum_mode: QuorumMode,
+    membership_changes: Notify,
+    handoff: Arc<HintedHandoff>,
+    detector: Arc<FailureDetector>,
+    channels: Arc<Channels>,
+    tls: Option<Arc<Tls>>,
+    auth: Arc<Auth>,
+    retry: Retry,
+    streams: ReplicationStreams,
+    connect_timeout: Duration,
+    keepalive_interval: Duration,
+    keepalive_timeout: Duration,
+    get_timeout: Duration,
+    add_timeout: Duration,
+    remove_timeout: Duration,
+    background: Arc<Background>,
 }
 
-impl Default for Pool {
-    fn default() -> Self {
+impl Pool {
+    pub fn new(
+        config: &Config,
+        handoff: Arc<HintedHandoff>,
+        detector: Arc<FailureDetector>,
+        channels: Arc<Channels>,
+        tls: Option<Arc<Tls>>,
+        auth: Arc<Auth>,
+    ) -> Self {
+        let mut ring = HashRing::new(config.virtual_nodes());
+        ring.add(config.node_id());
+        ring.set_zone(config.node_id(), config.zone());
         Pool {
             pool: HashMap::builder().hasher(RandomState::default()).build(),
+            ring: RwLock::new(ring),
+            local_id: config.node_id().to_string(),
+            local_addr: RwLock::new(config.advertise_addr().unwrap_or_default().to_string()),
+            grpc_port: config.grpc_port(),
+            replication_factor: config.replication_factor(),
+            read_quorum: config.read_quorum(),
+            write_quorum: config.write_quorum(),
+            quorum_mode: config.quorum_mode(),
+            membership_changes: Notify::new(),
+            handoff,
+            detector,
+            channels,
+            tls,
+            auth,
+            retry: Retry::new(config),
+            streams: ReplicationStreams::new(config),
+            connect_timeout: Duration::from_millis(config.connect_timeout()),
+            keepalive_interval: Duration::from_millis(config.keepalive_interval().max(1)),
+            keepalive_timeout: Duration::from_millis(config.keepalive_timeout().max(1)),
+            get_timeout: Duration::from_millis(config.get_timeout()),
+            add_timeout: Duration::from_millis(config.add_timeout()),
+            remove_timeout: Duration::from_millis(config.remove_timeout()),
+            background: Arc::new(Background::default()),
         }
     }
-}
 
-impl Pool {
-    pub fn get_addrs(&self) -> Vec<String> {
+    pub fn get_ids(&self) -> Vec<String> {
         self.pool.pin().iter().map(|(k, _)| k.clone()).collect()
     }
 
-    pub fn remove(&self, ip: &String) -> Result<String> {
-        match self.pool.pin().remove(ip) {
+    // every peer as (id, address)
+    pub fn peers(&self) -> Vec<NodeInfo> {
+        let ring = self.ring.read().expect("ring lock poisoned");
+        self.pool
+            .pin()
+            .iter()
+            .map(|(id, peer)| NodeInfo {
+                id: id.clone(),
+                addr: peer.addr.clone(),
+                protocol: Some(peer.protocol.clone()),
+                zone: ring.zone(id).to_string(),
+            })
+            .collect()
+    }
+
+    pub fn local_id(&self) -> &str {
+        &self.local_id
+    }
+
+    pub fn local_zone(&self) -> String {
+        self.zone_of(&self.local_id)
+    }
+
+    pub fn zone_of(&self, id: &str) -> String {
+        let ring = self.ring.read().expect("ring lock poisoned");
+        ring.zone(id).to_string()
+    }
+
+    pub fn local_addr(&self) -> String {
+        self.local_addr
+            .read()
+            .expect("local addr lock poisoned")
+            .clone()
+    }
+
+    pub fn local_node(&self) -> NodeInfo {
+        NodeInfo {
+            id: self.local_id.clone(),
+            addr: self.local_addr(),
+            protocol: Some(protocol::local()),
+            zone: self.local_zone(),
+        }
+    }
+
+    // only used when no address was configured, the first one learned sticks
+    pub fn learn_local_addr(&self, addr: &str) {
+        let mut local_addr = self.local_addr.write().expect("local addr lock poisoned");
+        if local_addr.is_empty() && !addr.is_empty() {
+            info!("Local node is known to the cluster as {}", addr);
+            *local_addr = addr.to_string();
+        }
+    }
+
+    // a copy of the ring together with the id the local node has on it
+    pub fn ring_snapshot(&self) -> (HashRing, String) {
+        let ring = self.ring.read().expect("ring lock poisoned");
+        (ring.clone(), self.local_id.clone())
+    }
+
+    // read quorum, write quorum and how they're applied, as configured
+    pub fn configured_quorums(&self) -> (usize, usize, QuorumMode) {
+        (self.read_quorum, self.write_quorum, self.quorum_mode)
+    }
+
+    // a feature is only used once every peer says it supports it
+    pub fn supports(&self, feature: Feature) -> bool {
+        self.pool
+            .pin()
+            .values()
+            .all(|peer| protocol::supports(&peer.protocol, feature))
+    }
+
+    // the features the whole cluster can use right now
+    pub fn cluster_features(&self) -> Vec<&'static str> {
+        Feature::ALL
+            .iter()
+            .filter(|feature| self.supports(**feature))
+            .map(|feature| feature.name())
+            .collect()
+    }
+
+    // keeps what a peer said it speaks, a peer that no longer fits is an error
+    fn learn_protocol(&self, id: &str, protocol: Protocol) -> Result<()> {
+        protocol::check(&protocol).with_context(|| format!("Node {} is incompatible", id))?;
+        let pin = self.pool.pin();
+        let _ = pin.update(id.to_string(), |peer| {
+            let mut peer = peer.clone();
+            peer.protocol = protocol.clone();
+            peer
+        });
+        let zone_aware = self.supports(Feature::ZonePlacement);
+        self.update_ring(|ring| ring.set_zone_aware(zone_aware));
+        Ok(())
+    }
+
+    // a peer that moved to another zone changes where keys live
+    fn learn_zone(&self, id: &str, zone: &str) {
+        self.update_ring(|ring| {
+            if !ring.contains(id) || !ring.set_zone(id, zone) {
+                return false;
+            }
+            info!(node = %id, "Node is in zone {:?}", zone);
+            true
+        });
+    }
+
+    // applies a change to the ring, and lets whoever waits for membership changes know
+    // when the placement changed
+    fn update_ring(&self, update: impl FnOnce(&mut HashRing) -> bool) {
+        let changed = update(&mut self.ring.write().expect("ring lock poisoned"));
+        if changed {
+            self.membership_changes.notify_one();
+            self.check_quorum();
+        }
+    }
+
+    pub fn configured_replication_factor(&self) -> usize {
+        self.replication_factor
+    }
+
+    // resolves once the set of nodes on the ring has changed since the last call
+    pub async fn membership_changed(&self) {
+        self.membership_changes.notified().await
+    }
+
+    // the replication factor can't exceed the number of nodes that exist
+    pub fn replication_factor(&self) -> usize {
+        let ring = self.ring.read().expect("ring lock poisoned");
+        self.replication_factor.min(ring.len())
+    }
+
+    // this node plus every peer the failure detector hasn't given up on
+    pub fn live_nodes(&self) -> usize {
+        1 + self
+            .get_ids()
+            .iter()
+            .filter(|id| !self.detector.is_dead(id))
+            .count()
+    }
+
+    pub fn read_quorum(&self) -> Result<usize> {
+        self.quorum(self.read_quorum)
+    }
+
+    pub fn write_quorum(&self) -> Result<usize> {
+        self.quorum(self.write_quorum)
+    }
+
+    // in majority mode the quorum moves with the live cluster, a fixed one that no longer
+    // fits the cluster is an error rather than a partial result waiting to happen
+    fn quorum(&self, configured: usize) -> Result<usize> {
+        match self.quorum_mode {
+            QuorumMode::Majority => {
+                let replicas = self.replication_factor.min(self.live_nodes());
+                Ok(replicas / 2 + 1)
+            }
+            QuorumMode::Fixed => {
+                let replicas = self.replication_factor();
+                if configured > replicas {
+                    return Err(anyhow!(
+                        "Quorum of {} can't be met, keys only have {} replicas in the current cluster",
+                        configured,
+                        replicas
+                    ));
+                }
+                Ok(configured)
+            }
+        }
+    }
+
+    // called whenever nodes come or go, so a quorum that stopped fitting shows up in the logs
+    // before the requests start failing
+    fn check_quorum(&self) {
+        if self.quorum_mode == QuorumMode::Majority {
+            return;
+        }
+        let replicas = self.replication_factor();
+        let quorum = self.read_quorum.max(self.write_quorum);
+        if quorum > replicas {
+            warn!(
+                "Cluster is down to {} replicas per key, a quorum of {} can't be met until more nodes join",
+                replicas, quorum
+            );
+        }
+    }
+
+    pub fn placement(&self, key: &str) -> Placement {
+        // one guard for both, a ring change in between would leave replicas without a zone
+        let (replicas, zones) = {
+            let ring = self.ring.read().expect("ring lock poisoned");
+            let replicas = ring.preference_list(key, self.replication_factor);
+            let zones = replicas
+                .iter()
+                .map(|node| (node.clone(), ring.zone(node).to_string()))
+                .collect();
+            (replicas, zones)
+        };
+        let local = replicas.contains(&self.local_id);
+        let peers = replicas
+            .into_iter()
+            .filter(|node| *node != self.local_id)
+            .collect();
+        Placement {
+            local,
+            peers,
+            zones,
+        }
+    }
+
+    pub fn replication_metrics(&self) -> ReplicationMetrics {
+        ReplicationMetrics {
+            in_flight: self.background.in_flight.load(Ordering::Relaxed),
+            completed: self.background.completed.load(Ordering::Relaxed),
+            timed_out: self.background.timed_out.load(Ordering::Relaxed),
+            failed: self.background.failed.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn stream_metrics(&self) -> StreamMetrics {
+        self.streams.metrics()
+    }
+
+    // the client has its ack, but the replicas that haven't answered still need the write;
+    // dropping the set would abort them. a replica that misses its deadline was already
+    // hinted by its own task
+    fn finish_in_background<T: Send + 'static>(
+        &self,
+        key: &str,
+        mut futures_set: JoinSet<(String, Result<T, CallError>)>,
+    ) {
+        if futures_set.is_empty() {
+            return;
+        }
+        let background = Arc::clone(&self.background);
+        let key = key.to_string();
+        background
+            .in_flight
+            .fetch_add(futures_set.len() as u64, Ordering::Relaxed);
+        tokio::spawn(async move {
+            while let Some(result) = futures_set.join_next().await {
+                background.in_flight.fetch_sub(1, Ordering::Relaxed);
+                let counter = match result {
+                    Ok((_, Ok(_))) => &background.completed,
+                    // a remove that found nothing to remove still reached the replica
+                    Ok((_, Err(CallError::Failed(status))))
+                        if status.code() == Code::InvalidArgument =>
+                    {
+                        &background.completed
+                    }
+                    Ok((ip, Err(CallError::TimedOut))) => {
+                        warn!(key = %key, "Replica {} timed out after the quorum, left to the hint", ip);
+                        &background.timed_out
+                    }
+                    Ok((ip, Err(e))) => {
+                        warn!(key = %key, "Replica {} failed after the quorum, left to the hint: {}", ip, e);
+                        &background.failed
+                    }
+                    Err(e) => {
+                        error!("Task panicked: {:?}", e);
+                        &background.failed
+                    }
+                };
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+    }
+
+    // puts a peer that's already in the pool on the ring, in its zone if we know it
+    fn track(&self, id: &str, zone: &str) {
+        self.detector.register(id);
+        self.channels.track(id);
+        self.streams.forget(id);
+        let zone_aware = self.supports(Feature::ZonePlacement);
+        self.update_ring(|ring| {
+            let added = ring.add(id);
+            let moved = !zone.is_empty() && ring.set_zone(id, zone);
+            ring.set_zone_aware(zone_aware) | added | moved
+        });
+    }
+
+    fn untrack(&self, id: &str) {
+        self.detector.forget(id);
+        self.channels.forget(id);
+        self.streams.forget(id);
+        let zone_aware = self.supports(Feature::ZonePlacement);
+        self.update_ring(|ring| ring.remove(id) | ring.set_zone_aware(zone_aware));
+    }
+
+    // whether the replicas that answered are enough, counted by the zone each of them is in
+    pub fn quorum_met(&self, quorum: &Quorum, acked: &[String]) -> bool {
+        let ring = self.ring.read().expect("ring lock poisoned");
+        quorum.met_by(acked.iter().map(|id| ring.zone(id)))
+    }
+
+    // splits replicas into the ones worth contacting and the ones the detector gave up on
+    fn live_replicas(&self, replicas: &[String]) -> (Vec<String>, Vec<String>) {
+        replicas
+            .iter()
+            .cloned()
+            .partition(|replica| !self.detector.is_dead(replica))
+    }
+
+    fn channels_for(&self, ids: &[String]) -> Vec<(String, Channel)> {
+        let pin = self.pool.pin();
+        ids.iter()
+            .filter_map(|id| match pin.get(id) {
+                Some(peer) => Some((id.clone(), peer.channel.clone())),
+                None => {
+                    error!(node = %id, "Replica is not in the pool, skipping it");
+                    None
+                }
+            })
+            .collect()
+    }
+
+    fn channel(&self, id: &str) -> Result<Channel> {
+        self.pool
+            .pin()
+            .get(id)
+            .map(|peer| peer.channel.clone())
+            .ok_or_else(|| anyhow!("Node {} is not in the pool", id))
+    }
+
+    fn cluster_client(&self, channel: Channel) -> ClusterManagementClient<Signed> {
+        ClusterManagementClient::new(self.auth.sign_channel(channel, Scope::Cluster))
+    }
+
+    fn kv_client(&self, channel: Channel) -> KvStoreClient<Signed> {
+        KvStoreClient::new(self.auth.sign_channel(channel, Scope::Kv))
+    }
+
+    // writes go over the replica's stream once every node has one, older nodes only know
+    // a call per write
+    fn replica_stream(&self, id: &str, channel: Channel) -> Option<ReplicaStream> {
+        self.supports(Feature::ReplicationStream)
+            .then(|| self.streams.get(id, || self.kv_client(channel)))
+    }
+
+    fn rebalance_client(&self, channel: Channel) -> RebalanceClient<Signed> {
+        RebalanceClient::new(self.auth.sign_channel(channel, Scope::Cluster))
+    }
+
+    fn anti_entropy_client(&self, channel: Channel) -> AntiEntropyClient<Signed> {
+        AntiEntropyClient::new(self.auth.sign_channel(channel, Scope::Cluster))
+    }
+
+    // channels connect on first use and reconnect on their own, and the keepalives notice a
+    // peer that went away without closing the connection
+    fn dial(&self, addr: &str) -> Result<Channel> {
+        let client_uri = format!("http://{}", addr)
+            .parse::<Uri>()
+            .with_context(|| format!("Failed to parse the address {}", addr))?;
+        let endpoint = Channel::builder(client_uri)
+            .connect_timeout(self.connect_timeout)
+            .http2_keep_alive_interval(self.keepalive_interval)
+            .keep_alive_timeout(self.keepalive_timeout)
+            .keep_alive_while_idle(true);
+        Ok(match &self.tls {
+            // the tls handshake is done by our own connector, so the uri stays plain http
+            Some(tls) => {
+                let tls = Arc::clone(tls);
+                endpoint.connect_with_connector_lazy(service_fn(move |uri: Uri| {
+                    let tls = Arc::clone(&tls);
+                    async move { tls.connect(uri).await }
+                }))
+            }
+            None => endpoint.connect_lazy(),
+        })
+    }
+
+    // swaps the channel of a peer that keeps failing for a new one to the same address
+    pub fn reconnect(&self, id: &str) -> Result<()> {
+        let pin = self.pool.pin();
+        let peer = pin
+            .get(id)
+            .ok_or_else(|| anyhow!("Node {} is not in the pool", id))?;
+        let channel = self.dial(&peer.addr)?;
+        info!(node = %id, addr = %peer.addr, "Reconnecting to node");
+        let _ = pin.update(id.to_string(), |peer| {
+            let mut peer = peer.clone();
+            peer.channel = channel.clone();
+            peer
+        });
+        self.streams.forget(id);
+        Ok(())
+    }
+
+    pub fn remove(&self, id: &str) -> Result<String> {
+        match self.pool.pin().remove(id) {
             Some(_) => {
-                info!(ip = %ip, "Node removed successfully");
+                self.untrack(id);
+                info!(node = %id, "Node removed successfully");
                 Ok("Removed Node".to_string())
             }
             None => {
-                error!(ip = %ip, "Node does not exist, removal failed");
+                error!(node = %id, "Node does not exist, removal failed");
                 Err(anyhow!("Node doesn't exist"))
             }
         }
@@ -51,21 +615,24 @@ impl Pool {
             .pool
             .pin()
             .iter()
-            .map(|(k, v)| (k.clone(), v.clone()))
+            .map(|(k, v)| (k.clone(), v.channel.clone()))
             .collect();
 
         let mut futures_set = JoinSet::new();
-        for (ip, channel) in entries {
-            let trace_span = span!(Level::INFO, "remove_node", ip = %ip);
+        for (id, channel) in entries {
+            let trace_span = span!(Level::INFO, "remove_node", node = %id);
             let _enter = trace_span.enter();
-            let request = Request::new(NoContentRequest {});
+            let request = Request::new(RemoveNodeRequest {
+                id: self.local_id.clone(),
+                skip_rereplication: false,
+            });
+            let mut conn = self.cluster_client(channel);
             futures_set.spawn(async move {
-                let mut conn = ClusterManagementClient::new(channel);
                 match conn.remove_node(request).await {
                     Ok(msg) => {
-                        info!(ip = %ip, "Successfully removed node: {}", msg.into_inner().message)
+                        info!(node = %id, "Successfully removed node: {}", msg.into_inner().message)
                     }
-                    Err(e) => error!(ip = %ip, "Error removing node: {}", e),
+                    Err(e) => error!(node = %id, "Error removing node: {}", e),
                 }
             });
         }
@@ -73,116 +640,172 @@ impl Pool {
         info!("Completed leaving the cluster");
     }
 
-    pub async fn conn_make(&self, addr: &String) -> Result<Channel> {
-        let trace_span = span!(Level::DEBUG, "conn_make", addr = %addr);
-        let _enter = trace_span.enter();
+    // tells every other peer to drop a node, returns the peers that couldn't be told and why
+    pub async fn evict(&self, id: &str, skip_rereplication: bool) -> Vec<(String, String)> {
+        let entries: Vec<(String, Channel)> = self
+            .pool
+            .pin()
+            .iter()
+            .filter(|(peer, _)| peer.as_str() != id)
+            .map(|(k, v)| (k.clone(), v.channel.clone()))
+            .collect();
 
-        if let Some(channel) = self.pool.pin().get(addr) {
-            return Ok(channel.clone());
+        let mut futures_set = JoinSet::new();
+        for (peer, channel) in entries {
+            let request = Request::new(RemoveNodeRequest {
+                id: id.to_string(),
+                skip_rereplication,
+            });
+            let mut conn = self.cluster_client(channel);
+            futures_set.spawn(async move {
+                match conn.remove_node(request).await {
+                    Ok(_) => None,
+                    Err(e) => {
+                        error!(node = %peer, "Failed to tell peer about the removal: {}", e);
+                        Some((peer, e.message().to_string()))
+                    }
+                }
+            });
         }
+        futures_set.join_all().await.into_iter().flatten().collect()
+    }
 
-        info!(
-            "Attempting to make a connection to node at address: {}",
-            addr
-        );
+    // connects to a node unless it's already in the pool under the same address; a node
+    // that shows up with a new address gets a fresh channel. a node that says what it speaks
+    // is refused when that doesn't fit, one that doesn't keeps what we knew about it
+    pub fn conn_make(&self, node: &NodeInfo) -> Result<Channel> {
+        let (id, addr) = (node.id.as_str(), node.addr.as_str());
+        let trace_span = span!(Level::DEBUG, "conn_make", node = %id, addr = %addr);
+        let _enter = trace_span.enter();
 
-        let client_uri = format!("http://{}", addr)
-            .parse::<Uri>()
-            .context("Failed to parse the client URI")?;
+        if id == self.local_id {
+            return Err(anyhow!("Refusing to connect to the local node"));
+        }
+        if let Some(protocol) = &node.protocol {
+            protocol::check(protocol).with_context(|| format!("Node {} is incompatible", id))?;
+        }
+        if let Some(peer) = self.pool.pin().get(id) {
+            if peer.addr == addr {
+                if let Some(protocol) = &node.protocol {
+                    self.learn_protocol(id, protocol.clone())?;
+                }
+                if !node.zone.is_empty() {
+                    self.learn_zone(id, &node.zone);
+                }
+                return Ok(peer.channel.clone());
+            }
+        }
 
-        match Channel::builder(client_uri).connect().await {
+        info!("Making a channel to node {} at {}", id, addr);
+
+        match self.dial(addr) {
             Ok(channel) => {
-                info!("Successfully connected to {}", addr);
-                // If a concurrent caller raced us, keep whichever channel landed first.
+                let protocol = node.protocol.clone().unwrap_or_else(|| {
+                    self.pool
+                        .pin()
+                        .get(id)
+                        .map(|peer| peer.protocol.clone())
+                        .unwrap_or_default()
+                });
+                let peer = Peer {
+                    addr: addr.to_string(),
+                    channel,
+                    protocol,
+                };
+                // If a concurrent caller raced us to the same address, keep whichever channel landed first.
                 let pin = self.pool.pin();
-                let stored = pin.get_or_insert_with(addr.clone(), || channel.clone());
-                Ok(stored.clone())
+                let channel = match pin.get(id) {
+                    Some(current) if current.addr == addr => current.channel.clone(),
+                    _ => {
+                        let channel = peer.channel.clone();
+                        pin.insert(id.to_string(), peer);
+                        channel
+                    }
+                };
+                self.track(id, &node.zone);
+                Ok(channel)
             }
             Err(e) => {
-                error!("Failed to connect to {}: {}", addr, e);
-                Err(anyhow::anyhow!("Failed to connect to the channel").context(e))
+                error!("{:#}", e);
+                Err(e)
             }
         }
     }
 
-    pub async fn bulk_conn_make(&self, ip_addrs: &[String]) {
-        let trace_span = span!(Level::INFO, "bulk_conn_make", num_ips = ip_addrs.len());
+    // peers that can't be reached yet are kept all the same, their channels connect once
+    // the peer is up
+    pub fn bulk_conn_make(&self, nodes: &[NodeInfo]) {
+        let trace_span = span!(Level::INFO, "bulk_conn_make", num_nodes = nodes.len());
         let _enter = trace_span.enter();
 
-        info!(
-            "Starting bulk connection setup for {} IP addresses.",
-            ip_addrs.len()
-        );
-        let mut futures_set = JoinSet::new();
-        for ip in ip_addrs.iter() {
-            let ip = ip.clone();
-            let client_uri = format!("http://{}", ip);
-            futures_set.spawn(async move {
-                let client_uri = match client_uri.parse::<Uri>() {
-                    Ok(uri) => uri,
-                    Err(err) => {
-                        error!("Failed to parse URI for IP {}: {}", ip, err);
-                        return None;
-                    }
-                };
-                info!("Attempting to connect to IP: {}", ip);
-                match Channel::builder(client_uri).connect().await {
-                    Ok(channel) => {
-                        info!("Successfully connected to IP: {}", ip);
-                        Some((ip, channel))
-                    }
-                    Err(err) => {
-                        error!("Failed to connect to {}: {}", ip, err);
-                        None
-                    }
+        info!("Starting bulk connection setup for {} nodes.", nodes.len());
+        for node in nodes.iter().filter(|node| node.id != self.local_id) {
+            let protocol = node.protocol.clone().unwrap_or_default();
+            if let Err(e) = protocol::check(&protocol) {
+                error!(node = %node.id, "Skipping incompatible node: {:#}", e);
+                continue;
+            }
+            let channel = match self.dial(&node.addr) {
+                Ok(channel) => channel,
+                Err(e) => {
+                    error!(node = %node.id, "{:#}", e);
+                    continue;
                 }
-            });
-        }
-
-        let results = futures_set.join_all().await;
-        let pin = self.pool.pin();
-        for (ip, channel) in results.into_iter().flatten() {
-            pin.insert(ip, channel);
+            };
+            self.pool.pin().insert(
+                node.id.clone(),
+                Peer {
+                    addr: node.addr.clone(),
+                    channel,
+                    protocol,
+                },
+            );
+            self.track(&node.id, &node.zone);
         }
         info!("Finished processing bulk connection setup.");
     }
 
-    pub async fn gossip(&self, addr: String) {
-        let trace_span = span!(Level::INFO, "gossip", addr = addr.clone());
+    pub async fn gossip(&self, node: NodeInfo) {
+        let trace_span = span!(Level::INFO, "gossip", node = %node.id);
         let _enter = trace_span.enter();
 
         info!(
-            "Starting gossip to add node with address: {} to cluster",
-            addr
+            "Starting gossip to add node {} at {} to cluster",
+            node.id, node.addr
         );
 
-        let channels: Vec<Channel> = self.pool.pin().iter().map(|(_, v)| v.clone()).collect();
+        let channels: Vec<Channel> = self
+            .pool
+            .pin()
+            .iter()
+            .map(|(_, v)| v.channel.clone())
+            .collect();
         let mut futures_set = JoinSet::new();
         for channel in channels {
-            let request = Request::new(AddNodeRequest { ip: addr.clone() });
-            let addr = addr.clone();
+            let request = Request::new(AddNodeRequest {
+                node: Some(node.clone()),
+            });
+            let id = node.id.clone();
+            let mut conn = self.cluster_client(channel);
             futures_set.spawn(async move {
-                debug!("Gossiping to node with address: {}", addr);
-                let mut conn = ClusterManagementClient::new(channel);
+                debug!("Gossiping node {}", id);
                 match conn.add_node(request).await {
                     Ok(msg) => {
-                        info!("Successfully added node: {}", addr);
+                        info!("Successfully added node: {}", id);
                         debug!("{}", msg.into_inner().message);
                     }
                     Err(e) => {
-                        error!("Failed to gossip to {}: {}", addr, e);
+                        error!("Failed to gossip node {}: {}", id, e);
                     }
                 }
             });
         }
         futures_set.join_all().await;
-        info!(
-            "Completed gossip to add node with address: {} to cluster",
-            addr
-        );
+        info!("Completed gossip to add node {} to cluster", node.id);
     }
 
-    pub async fn join(&self, addr: String) -> Result<Vec<KvData>> {
+    // joins through the seed and returns its node id, the store is bootstrapped separately
+    pub async fn join(&self, addr: String, cluster_id: String) -> Result<Joined> {
         let trace_span = span!(Level::INFO, "join", addr = addr.clone());
         let _enter = trace_span.enter();
 
@@ -191,12 +814,15 @@ impl Pool {
             addr
         );
         let seed_node_channel = self
-            .conn_make(&addr)
-            .await
+            .dial(&addr)
             .context("failed to make connection to seed node")?;
 
-        let request = Request::new(NoContentRequest {});
-        let mut seed_node = ClusterManagementClient::new(seed_node_channel);
+        let request = Request::new(JoinRequest {
+            node: Some(self.local_node()),
+            grpc_port: u32::from(self.grpc_port),
+            cluster_id,
+        });
+        let mut seed_node = self.cluster_client(seed_node_channel.clone());
         let response = match seed_node.join(request).await {
             Ok(res) => {
                 info!(
@@ -211,15 +837,35 @@ impl Pool {
             }
         };
         let message = response.into_inner();
-        self.bulk_conn_make(&message.addresses).await;
-        Ok(message.store_data)
+        self.learn_local_addr(&message.address);
+        let seed = message.seed.context("Seed node didn't tell us its id")?;
+        // an older seed let us in without checking, the cluster may still be too old for us
+        let seed_protocol = seed.protocol.clone().unwrap_or_default();
+        protocol::check(&seed_protocol)
+            .with_context(|| format!("Seed node {} is incompatible", seed.id))?;
+        // the address we dialed is known to work, whatever the seed thinks it's called
+        self.pool.pin().insert(
+            seed.id.clone(),
+            Peer {
+                addr,
+                channel: seed_node_channel,
+                protocol: seed_protocol,
+            },
+        );
+        self.track(&seed.id, &seed.zone);
+        self.bulk_conn_make(&message.nodes);
+        Ok(Joined {
+            seed: seed.id,
+            cluster_id: message.cluster_id,
+        })
     }
 
     pub async fn get_kv(
         &self,
         operation: &Operation,
-        needed_quorum_votes: usize,
-    ) -> Vec<(String, GetKvResponse)> {
+        replicas: &[String],
+        quorum: &Quorum,
+    ) -> (Replies<(String, GetKvResponse)>, Option<LateReplies>) {
         debug!(
             "Initiating GET operation for key: {} in the cluster",
             operation.key
@@ -233,57 +879,65 @@ impl Pool {
             key: operation.key.clone(),
         };
 
-        let entries: Vec<(String, Channel)> = self
-            .pool
-            .pin()
-            .iter()
-            .map(|(k, v)| (k.clone(), v.clone()))
-            .collect();
+        let (live, dead) = self.live_replicas(replicas);
+        if !dead.is_empty() {
+            debug!("Skipping dead replicas: {:?}", dead);
+        }
+        let entries = self.channels_for(&live);
 
-        debug!("Needed quorum votes: {}", needed_quorum_votes);
+        debug!("Needed quorum: {:?}", quorum);
         let mut futures_set = JoinSet::new();
         for (ip, channel) in entries {
-            let request = Request::new(kv_operation.clone());
+            let conn = self.kv_client(channel);
+            let operation = kv_operation.clone();
+            let (retry, deadline) = (self.retry, self.get_timeout);
             futures_set.spawn(async move {
-                let mut conn = KvStoreClient::new(channel);
-                match conn.get_kv(request).await {
-                    Ok(response) => {
-                        debug!("Successfully retrieved key from {}: {:?}", ip, response);
-                        Ok((ip, response.into_inner()))
-                    }
-                    Err(e) => {
-                        error!("Error retrieving key from {}: {}", ip, e);
-                        Err(e.to_string())
-                    }
-                }
+                // reads change nothing, so they're safe to retry
+                let result = retry
+                    .call(deadline, true, |remaining| {
+                        let mut conn = conn.clone();
+                        let mut request = Request::new(operation.clone());
+                        request.set_timeout(remaining);
+                        async move { conn.get_kv(request).await }
+                    })
+                    .await;
+                (ip, result)
             });
         }
-        let mut responses = Vec::new();
+        // the local vote was all the quorum needed, the peers only matter for read repair
+        if quorum.is_empty() {
+            return (Replies::default(), LateReplies::of(futures_set));
+        }
+        let mut replies = Replies::default();
         while let Some(result) = futures_set.join_next().await {
             match result {
-                Ok(Ok(response)) => {
-                    responses.push(response);
-                    if responses.len() == needed_quorum_votes {
-                        debug!("Reached quorum with {} votes", responses.len());
-                        return responses;
+                Ok((ip, Ok(response))) => {
+                    debug!("Successfully retrieved key from {}: {:?}", ip, response);
+                    replies.acked.push(ip.clone());
+                    replies.responses.push((ip, response));
+                    if self.quorum_met(quorum, &replies.acked) {
+                        debug!("Reached quorum with {} votes", replies.responses.len());
+                        return (replies, LateReplies::of(futures_set));
                     }
                 }
-                Ok(Err(e)) => {
-                    error!("Failed request: {}", e);
+                Ok((ip, Err(e))) => {
+                    error!("Error retrieving key from {}: {}", ip, e);
+                    replies.missed(ip, e);
                 }
                 Err(e) => {
                     error!("Task panicked: {:?}", e);
                 }
             }
         }
-        responses
+        (replies, None)
     }
 
     pub async fn remove_kv(
         &self,
         operation: &Operation,
-        needed_quorum_votes: usize,
-    ) -> Vec<RemoveKvResponse> {
+        replicas: &[String],
+        quorum: &Quorum,
+    ) -> Replies<RemoveKvResponse> {
         debug!(
             "Initiating REMOVE operation for key: {} in the cluster",
             operation.key
@@ -297,47 +951,89 @@ impl Pool {
             key: operation.key.clone(),
         };
 
-        let channels: Vec<Channel> = self.pool.pin().iter().map(|(_, v)| v.clone()).collect();
+        let (live, dead) = self.live_replicas(replicas);
+        for peer in dead {
+            debug!("Replica {} is dead, storing a hint instead", peer);
+            self.handoff.store(&peer, &kv_operation).await;
+        }
+        let entries = self.channels_for(&live);
 
-        debug!("Needed quorum votes: {}", needed_quorum_votes);
+        debug!("Needed quorum: {:?}", quorum);
 
         let mut futures_set = JoinSet::new();
-        for channel in channels {
-            let request = Request::new(kv_operation.clone());
+        for (ip, channel) in entries {
+            let handoff = Arc::clone(&self.handoff);
+            let operation = kv_operation.clone();
+            let stream = self.replica_stream(&ip, channel.clone());
+            let conn = self.kv_client(channel);
+            let (retry, deadline) = (self.retry, self.remove_timeout);
             futures_set.spawn(async move {
-                let mut conn = KvStoreClient::new(channel);
-                match conn.remove_kv(request).await {
-                    Ok(response) => Ok(response.into_inner()),
-                    Err(e) => Err(e.to_string()),
+                // not retried, a replica that missed it gets the hint instead
+                let result = match stream {
+                    Some(stream) => {
+                        stream
+                            .write(operation.clone(), true, deadline)
+                            .await
+                            .map(|()| RemoveKvResponse {
+                                message: "key-value pair removed".to_string(),
+                                is_removed: true,
+                            })
+                    }
+                    None => {
+                        retry
+                            .call(deadline, false, |remaining| {
+                                let mut conn = conn.clone();
+                                let mut request = Request::new(operation.clone());
+                                request.set_timeout(remaining);
+                                async move { conn.remove_kv(request).await }
+                            })
+                            .await
+                    }
+                };
+                match &result {
+                    // invalid argument means the replica had nothing to remove, it was reached
+                    Err(CallError::Failed(status)) if status.code() == Code::InvalidArgument => {}
+                    Err(_) => handoff.store(&ip, &operation).await,
+                    Ok(_) => {}
                 }
+                (ip, result)
             });
         }
-        let mut responses = Vec::new();
+        // the local vote was all the quorum needed, the peers are caught up in the background
+        if quorum.is_empty() {
+            self.finish_in_background(&operation.key, futures_set);
+            return Replies::default();
+        }
+        let mut replies = Replies::default();
         while let Some(result) = futures_set.join_next().await {
             match result {
-                Ok(Ok(response)) => {
-                    responses.push(response);
-                    if responses.len() == needed_quorum_votes {
-                        debug!("Quorum reached with {} responses.", responses.len());
-                        return responses;
+                Ok((ip, Ok(response))) => {
+                    replies.acked.push(ip);
+                    replies.responses.push(response);
+                    if self.quorum_met(quorum, &replies.acked) {
+                        debug!("Quorum reached with {} responses.", replies.responses.len());
+                        self.finish_in_background(&operation.key, futures_set);
+                        return replies;
                     }
                 }
-                Ok(Err(e)) => {
-                    error!("Error during REMOVE request: {}", e);
+                Ok((ip, Err(e))) => {
+                    error!("Error during REMOVE request to {}: {}", ip, e);
+                    replies.missed(ip, e);
                 }
                 Err(e) => {
                     error!("Error in task execution: {:?}", e);
                 }
             }
         }
-        responses
+        replies
     }
 
     pub async fn add_kv(
         &self,
         operation: &Operation,
-        needed_quorum_votes: usize,
-    ) -> Vec<AddKvResponse> {
+        replicas: &[String],
+        quorum: &Quorum,
+    ) -> Replies<AddKvResponse> {
         debug!(
             "Initiating ADD operation for key: {} in the cluster",
             operation.key
@@ -351,54 +1047,83 @@ impl Pool {
             key: operation.key.clone(),
         };
 
-        let entries: Vec<(String, Channel)> = self
-            .pool
-            .pin()
-            .iter()
-            .map(|(k, v)| (k.clone(), v.clone()))
-            .collect();
+        let (live, dead) = self.live_replicas(replicas);
+        for peer in dead {
+            debug!("Replica {} is dead, storing a hint instead", peer);
+            self.handoff.store(&peer, &kv_operation).await;
+        }
+        let entries = self.channels_for(&live);
 
-        debug!("Needed quorum votes: {}", needed_quorum_votes);
+        debug!("Needed quorum: {:?}", quorum);
         let mut futures_set = JoinSet::new();
         for (ip, channel) in entries {
-            let request = Request::new(kv_operation.clone());
+            let handoff = Arc::clone(&self.handoff);
+            let operation = kv_operation.clone();
+            let stream = self.replica_stream(&ip, channel.clone());
+            let conn = self.kv_client(channel);
+            let (retry, deadline) = (self.retry, self.add_timeout);
             futures_set.spawn(async move {
                 debug!("Sending ADD request to IP: {}", ip);
-                let mut conn = KvStoreClient::new(channel);
-                match conn.add_kv(request).await {
-                    Ok(response) => {
-                        debug!("Successfully added key to {}: {:?}", ip, response);
-                        Ok(response.into_inner())
+                // not retried, a replica that missed it gets the hint instead; replicas keep
+                // the newest write, so a hint landing late can't undo a newer one
+                let result = match stream {
+                    Some(stream) => {
+                        stream
+                            .write(operation.clone(), false, deadline)
+                            .await
+                            .map(|()| AddKvResponse {
+                                message: "key-value pair added".to_string(),
+                            })
                     }
-                    Err(e) => {
-                        error!("Error adding key to {}: {}", ip, e);
-                        Err(e.to_string())
+                    None => {
+                        retry
+                            .call(deadline, false, |remaining| {
+                                let mut conn = conn.clone();
+                                let mut request = Request::new(operation.clone());
+                                request.set_timeout(remaining);
+                                async move { conn.add_kv(request).await }
+                            })
+                            .await
                     }
+                };
+                if result.is_err() {
+                    handoff.store(&ip, &operation).await;
                 }
+                (ip, result)
             });
         }
-        let mut responses = Vec::new();
+        // the local vote was all the quorum needed, the peers are caught up in the background
+        if quorum.is_empty() {
+            self.finish_in_background(&operation.key, futures_set);
+            return Replies::default();
+        }
+        let mut replies = Replies::default();
         while let Some(result) = futures_set.join_next().await {
             match result {
-                Ok(Ok(response)) => {
-                    responses.push(response);
-                    if responses.len() == needed_quorum_votes {
-                        debug!("Quorum reachd with {} votes", responses.len());
-                        return responses;
+                Ok((ip, Ok(response))) => {
+                    debug!("Successfully added key to {}: {:?}", ip, response);
+                    replies.acked.push(ip);
+                    replies.responses.push(response);
+                    if self.quorum_met(quorum, &replies.acked) {
+                        debug!("Quorum reachd with {} votes", replies.responses.len());
+                        self.finish_in_background(&operation.key, futures_set);
+                        return replies;
                     }
                 }
-                Ok(Err(e)) => {
-                    error!("Failed request: {}", e);
+                Ok((ip, Err(e))) => {
+                    error!("Error adding key to {}: {}", ip, e);
+                    replies.missed(ip, e);
                 }
                 Err(e) => {
                     error!("Task panicked: {:?}", e);
                 }
             }
         }
-        responses
+        replies
     }
 
-    pub async fn solo_add_kv(&self, operation: &Operation, ip: &String) {
+    // a single replica outside of any quorum, as used by read repair
+    pub async fn solo_add_kv(&self, operation: &Operation, id: &str) -> Result<()> {
         let request = KvOperation {
             name: operation.name.clone(),
             level: operation.level.clone(),
@@ -406,28 +1131,21 @@ impl Pool {
             timestamp: Some(operation.timestamp),
             key: operation.key.clone(),
         };
-        match self.conn_make(ip).await {
-            Ok(channel) => {
-                let mut conn = KvStoreClient::new(channel);
-                match conn.add_kv(Request::new(request)).await {
-                    Ok(response) => {
-                        debug!(
-                            "Successfully added key: {} with response: {:?}",
-                            operation.key, response
-                        );
-                    }
-                    Err(e) => {
-                        error!("Error adding key {}: {}", operation.key, e);
-                    }
-                }
-            }
-            Err(e) => {
-                error!("Failed to create connection to {}: {}", ip, e);
-            }
-        }
+        let conn = self.kv_client(self.channel(id)?);
+        self.retry
+            .call(self.add_timeout, false, |remaining| {
+                let mut conn = conn.clone();
+                let mut request = Request::new(request.clone());
+                request.set_timeout(remaining);
+                async move { conn.add_kv(request).await }
+            })
+            .await
+            .map_err(|e| anyhow!("Failed to add key {} on {}: {}", operation.key, id, e))?;
+        debug!("Successfully added key {} on {}", operation.key, id);
+        Ok(())
     }
 
-    pub async fn solo_remove_kv(&self, operation: &Operation, ip: &String) {
+    pub async fn solo_remove_kv(&self, operation: &Operation, id: &str) -> Result<()> {
         let request = KvOperation {
             name: operation.name.clone(),
             level: operation.level.clone(),
@@ -435,24 +1153,193 @@ impl Pool {
             timestamp: Some(operation.timestamp),
             key: operation.key.clone(),
         };
-        match self.conn_make(ip).await {
-            Ok(channel) => {
-                let mut conn = KvStoreClient::new(channel);
-                match conn.remove_kv(Request::new(request)).await {
-                    Ok(response) => {
-                        debug!(
-                            "Successfully removed key: {} with response: {:?}",
-                            operation.key, response
-                        );
-                    }
-                    Err(e) => {
-                        error!("Error removing key {}: {}", operation.key, e);
-                    }
-                }
-            }
+        let conn = self.kv_client(self.channel(id)?);
+        let result = self
+            .retry
+            .call(self.remove_timeout, false, |remaining| {
+                let mut conn = conn.clone();
+                let mut request = Request::new(request.clone());
+                request.set_timeout(remaining);
+                async move { conn.remove_kv(request).await }
+            })
+            .await;
+        match result {
+            Ok(_) => {}
+            // already gone on the replica, which is what the removal wanted
+            Err(CallError::Failed(status)) if status.code() == Code::InvalidArgument => {}
             Err(e) => {
-                error!("Failed to create connection to {}: {}", ip, e);
+                return Err(anyhow!(
+                    "Failed to remove key {} on {}: {}",
+                    operation.key,
+                    id,
+                    e
+                ))
             }
         }
+        debug!("Successfully removed key {} on {}", operation.key, id);
+        Ok(())
+    }
+
+    pub async fn transfer(&self, id: &str, entries: Vec<KvData>, batch: u64) -> Result<u64> {
+        let channel = self.channel(id).context("failed to reach the new owner")?;
+        let mut conn = self.rebalance_client(channel);
+        let response = conn
+            .transfer(Request::new(TransferRequest { entries, batch }))
+            .await
+            .map_err(|e| anyhow!("Transfer of batch {} to {} failed: {}", batch, id, e))?;
+        Ok(response.into_inner().accepted)
+    }
+
+    // replays a stored hint, an error means the peer is still unreachable
+    pub async fn deliver_hint(&self, peer: &str, operation: KvOperation) -> Result<()> {
+        let channel = self
+            .channel(peer)
+            .context("failed to reach the hinted peer")?;
+        let mut conn = self.kv_client(channel);
+        // a peer that takes the connection but never answers mustn't hold up the replay
+        let (remove, deadline) = if operation.name == "REMOVE" {
+            (true, self.remove_timeout)
+        } else {
+            (false, self.add_timeout)
+        };
+        let mut request = Request::new(operation);
+        request.set_timeout(deadline);
+        let call = async {
+            if remove {
+                conn.remove_kv(request).await.map(|_| ())
+            } else {
+                conn.add_kv(request).await.map(|_| ())
+            }
+        };
+        match timeout(deadline, call).await {
+            Ok(Ok(())) => Ok(()),
+            // the peer already has nothing left to remove, which is what the hint wanted
+            Ok(Err(e)) if e.code() == Code::InvalidArgument => Ok(()),
+            Ok(Err(e)) => Err(anyhow!("Failed to deliver hint to {}: {}", peer, e)),
+            Err(_) => Err(anyhow!("Delivering hint to {} timed out", peer)),
+        }
+    }
+
+    pub async fn merkle_nodes(
+        &self,
+        id: &str,
+        local: &str,
+        level: u32,
+        indices: &[u32],
+    ) -> Result<Vec<u64>> {
+        let channel = self.channel(id)?;
+        let mut conn = self.anti_entropy_client(channel);
+        let response = conn
+            .merkle_nodes(Request::new(MerkleNodesRequest {
+                peer: local.to_string(),
+                level,
+                indices: indices.to_vec(),
+            }))
+            .await
+            .map_err(|e| anyhow!("Failed to fetch merkle nodes from {}: {}", id, e))?;
+        Ok(response.into_inner().hashes)
+    }
+
+    pub async fn sync_range(
+        &self,
+        id: &str,
+        local: &str,
+        buckets: &[u32],
+        entries: Vec<KvData>,
+    ) -> Result<Vec<KvData>> {
+        let channel = self.channel(id)?;
+        let mut conn = self.anti_entropy_client(channel);
+        let response = conn
+            .sync_range(Request::new(SyncRangeRequest {
+                peer: local.to_string(),
+                buckets: buckets.to_vec(),
+                entries,
+            }))
+            .await
+            .map_err(|e| anyhow!("Failed to sync ranges with {}: {}", id, e))?;
+        Ok(response.into_inner().entries)
+    }
+
+    pub async fn bootstrap(
+        &self,
+        id: &str,
+        after_key: String,
+        chunk_size: usize,
+        since: Option<Timestamp>,
+    ) -> Result<Streaming<BootstrapChunk>> {
+        let channel = self.channel(id)?;
+        let mut conn = self.cluster_client(channel);
+        let response = conn
+            .bootstrap(Request::new(BootstrapRequest {
+                after_key,
+                chunk_size: u32::try_from(chunk_size).unwrap_or(u32::MAX),
+                since,
+                node_id: self.local_id.clone(),
+            }))
+            .await
+            .map_err(|e| anyhow!("Failed to start bootstrap from {}: {}", id, e))?;
+        Ok(response.into_inner())
+    }
+
+    pub async fn stats(&self, id: &str) -> Result<NodeStats> {
+        let channel = self.channel(id)?;
+        let mut conn = self.cluster_client(channel);
+        let mut request = Request::new(NoContentRequest {});
+        request.set_timeout(self.get_timeout);
+        let response = timeout(self.get_timeout, conn.stats(request))
+            .await
+            .map_err(|_| anyhow!("Timed out asking {} for its stats", id))?
+            .map_err(|e| anyhow!("Failed to get stats from {}: {}", id, e))?;
+        Ok(response.into_inner())
+    }
+
+    // a peer that restarted on another version or in another zone says so in its pong, one
+    // that stopped fitting fails the ping until the detector gives up on it
+    pub async fn ping(&self, id: &str) -> Result<()> {
+        let channel = self.channel(id)?;
+        let mut conn = self.cluster_client(channel);
+        let response = match conn.ping(Request::new(NoContentRequest {})).await {
+            Ok(response) => {
+                self.channels.succeeded(id);
+                response.into_inner()
+            }
+            Err(e) => {
+                self.channels.failed(id, e.message());
+                return Err(anyhow!("Failed to ping {}: {}", id, e));
+            }
+        };
+        self.learn_zone(id, &response.zone);
+        self.learn_protocol(id, response.protocol.unwrap_or_default())
+    }
+
+    // sends our view of the membership and gets the peer's back
+    pub async fn exchange_gossip(
+        &self,
+        id: &str,
+        members: Vec<MemberUpdate>,
+    ) -> Result<Vec<MemberUpdate>> {
+        let channel = self.channel(id)?;
+        let mut conn = self.cluster_client(channel);
+        let response = conn
+            .gossip(Request::new(GossipRequest {
+                from: self.local_id.clone(),
+                members,
+            }))
+            .await
+            .map_err(|e| anyhow!("Failed to gossip with {}: {}", id, e))?;
+        Ok(response.into_inner().members)
+    }
+
+    // asks another node to ping the target on our behalf
+    pub async fn ping_req(&self, id: &str, target: &str) -> Result<bool> {
+        let channel = self.channel(id)?;
+        let mut conn = self.cluster_client(channel);
+        let response = conn
+            .ping_req(Request::new(PingReqRequest {
+                target: target.to_string(),
+            }))
+            .await
+            .map_err(|e| anyhow!("Failed to ask {} to probe {}: {}", id, target, e))?;
+        Ok(response.into_inner().reachable)
     }
 }
@@ -0,0 +1,127 @@
+use crate::cluster::services::Protocol;
+use anyhow::{bail, Result};
+use std::fmt;
+
+// bumped whenever a node would misread what an older one sends, or the other way around
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// the oldest version this node still works with; nodes from before versions were exchanged
+// show up as 0, and only lack the features
+pub const MIN_PROTOCOL_VERSION: u32 = 0;
+
+// optional behaviour that's only used once every member supports it, so a cluster halfway
+// through a rolling upgrade keeps speaking what the oldest node understands
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Feature {
+    // removals that leave the removed node's keys where they are
+    SkipRereplication,
+    // replicas spread across zones, every node has to place them the same way
+    ZonePlacement,
+    // writes to a replica go over one long-lived, batched stream instead of a call each
+    ReplicationStream,
+}
+
+impl Feature {
+    pub const ALL: &'static [Feature] = &[
+        Feature::SkipRereplication,
+        Feature::ZonePlacement,
+        Feature::ReplicationStream,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::SkipRereplication => "skip_rereplication",
+            Feature::ZonePlacement => "zone_placement",
+            Feature::ReplicationStream => "replication_stream",
+        }
+    }
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+// what this node tells the others it speaks
+pub fn local() -> Protocol {
+    Protocol {
+        version: PROTOCOL_VERSION,
+        min_version: MIN_PROTOCOL_VERSION,
+        features: Feature::ALL
+            .iter()
+            .map(|feature| feature.name().to_string())
+            .collect(),
+    }
+}
+
+// both sides have to be new enough for each other, the minimum is still 0 but won't stay that
+#[allow(clippy::absurd_extreme_comparisons)]
+pub fn check(peer: &Protocol) -> Result<()> {
+    if peer.version < MIN_PROTOCOL_VERSION {
+        bail!(
+            "Peer speaks protocol {}, this node needs at least {}",
+            peer.version,
+            MIN_PROTOCOL_VERSION
+        );
+    }
+    if PROTOCOL_VERSION < peer.min_version {
+        bail!(
+            "Peer needs protocol {} or newer, this node speaks {}",
+            peer.min_version,
+            PROTOCOL_VERSION
+        );
+    }
+    Ok(())
+}
+
+pub fn supports(protocol: &Protocol, feature: Feature) -> bool {
+    protocol
+        .features
+        .iter()
+        .any(|supported| supported == feature.name())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn protocol(version: u32, min_version: u32) -> Protocol {
+        Protocol {
+            version,
+            min_version,
+            features: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn a_node_works_with_itself() {
+        assert!(check(&local()).is_ok());
+    }
+
+    #[test]
+    fn peers_within_the_supported_range_are_accepted() {
+        // a node from before versions were exchanged
+        assert!(check(&protocol(0, 0)).is_ok());
+        assert!(check(&protocol(MIN_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION)).is_ok());
+        // a newer node that still speaks our version
+        assert!(check(&protocol(PROTOCOL_VERSION + 1, PROTOCOL_VERSION)).is_ok());
+    }
+
+    #[test]
+    fn a_peer_that_needs_a_newer_protocol_is_refused() {
+        let error = check(&protocol(PROTOCOL_VERSION + 2, PROTOCOL_VERSION + 1))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("needs protocol"), "{}", error);
+    }
+
+    #[test]
+    fn features_are_only_supported_when_advertised() {
+        let local = local();
+        for feature in Feature::ALL {
+            assert!(supports(&local, *feature), "{}", feature);
+        }
+        assert!(!supports(&protocol(0, 0), Feature::ReplicationStream));
+    }
+}
@@ -0,0 +1,291 @@
+use crate::cluster::services::GetKvResponse;
+use crate::config::{Config, ReadRepairMode};
+use crate::lally::consistency::Quorum;
+use crate::lally::Lally;
+use crate::utils::timestamp::{compare_timestamps, create_timestamp};
+use crate::utils::Operation;
+use prost_types::Timestamp;
+use rand::Rng;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinSet;
+use tracing::{debug, error, warn};
+
+// the replica id read repair uses for this node
+pub const LOCAL: &str = "local";
+
+#[derive(Serialize)]
+pub struct ReadRepairMetrics {
+    pub checks: u64,
+    pub repaired: u64,
+    pub failed: u64,
+    pub deduplicated: u64,
+    pub full_reads: u64,
+}
+
+// the newest answer among the replicas that responded to a read
+pub fn newest(responses: &[(String, GetKvResponse)]) -> Option<&GetKvResponse> {
+    responses
+        .iter()
+        .filter(|(_, response)| response.timestamp.is_some())
+        .max_by(|(_, a), (_, b)| {
+            compare_timestamps(
+                a.timestamp.as_ref().expect("filtered on timestamp"),
+                b.timestamp.as_ref().expect("filtered on timestamp"),
+            )
+        })
+        .map(|(_, response)| response)
+}
+
+// replicas that answered with something older than the newest answer, and the
+// operation that brings them up to date
+fn stale_replicas(
+    key: &str,
+    responses: &[(String, GetKvResponse)],
+) -> Option<(GetKvResponse, Vec<String>)> {
+    let latest = newest(responses)?.clone();
+    // a removal only has to reach the replicas that still hold a value, the others already
+    // answer the way it does
+    let stale: Vec<String> = responses
+        .iter()
+        .filter(|(_, response)| response.timestamp != latest.timestamp)
+        .filter(|(_, response)| latest.value.is_some() || response.value.is_some())
+        .map(|(id, _)| id.clone())
+        .collect();
+    if stale.is_empty() {
+        return None;
+    }
+    debug!(key = %key, "Read repair needed for {:?}", stale);
+    Some((latest, stale))
+}
+
+fn repair_operation(key: &str, latest: &GetKvResponse) -> Operation {
+    Operation {
+        key: key.to_string(),
+        value: latest.value.clone(),
+        // a newest answer without a value means the key was removed last
+        name: String::from(if latest.value.is_some() {
+            "ADD"
+        } else {
+            "REMOVE"
+        }),
+        timestamp: latest.timestamp.expect("newest answer has a timestamp"),
+        level: String::from("INFO"),
+        annotations: HashMap::new(),
+    }
+}
+
+// keeps a key marked as being repaired with a write until it's dropped
+struct Claim<'a> {
+    keys: &'a Mutex<HashMap<String, Timestamp>>,
+    key: String,
+    timestamp: Timestamp,
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        let mut keys = self.keys.lock().expect("read repair lock poisoned");
+        // a newer repair that took the key over releases it itself
+        if keys.get(&self.key) == Some(&self.timestamp) {
+            keys.remove(&self.key);
+        }
+    }
+}
+
+// brings replicas that answered a read with old data up to date, either before the read
+// answers or right after, and now and then reads every replica of a key in the background
+// so the ones outside the read quorum get fixed too
+pub struct ReadRepair {
+    mode: ReadRepairMode,
+    chance: f64,
+    in_flight: Mutex<HashMap<String, Timestamp>>,
+    checks: AtomicU64,
+    repaired: AtomicU64,
+    failed: AtomicU64,
+    deduplicated: AtomicU64,
+    full_reads: AtomicU64,
+}
+
+impl ReadRepair {
+    pub fn new(config: &Config) -> Self {
+        ReadRepair {
+            mode: config.read_repair_mode(),
+            chance: config.read_repair_chance().clamp(0.0, 1.0),
+            in_flight: Mutex::new(HashMap::new()),
+            checks: AtomicU64::new(0),
+            repaired: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            deduplicated: AtomicU64::new(0),
+            full_reads: AtomicU64::new(0),
+        }
+    }
+
+    pub fn metrics(&self) -> ReadRepairMetrics {
+        ReadRepairMetrics {
+            checks: self.checks.load(Ordering::Relaxed),
+            repaired: self.repaired.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            deduplicated: self.deduplicated.load(Ordering::Relaxed),
+            full_reads: self.full_reads.load(Ordering::Relaxed),
+        }
+    }
+
+    // a repair for a key that's already being repaired with the same or a newer write would
+    // just send writes that lose again, a newer one still goes out
+    fn claim(&self, key: &str, timestamp: Timestamp) -> Option<Claim<'_>> {
+        let mut keys = self.in_flight.lock().expect("read repair lock poisoned");
+        if let Some(running) = keys.get(key) {
+            if compare_timestamps(&timestamp, running) != std::cmp::Ordering::Greater {
+                self.deduplicated.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        }
+        keys.insert(key.to_string(), timestamp);
+        Some(Claim {
+            keys: &self.in_flight,
+            key: key.to_string(),
+            timestamp,
+        })
+    }
+
+    // checks the answers of a read, in blocking mode the stale replicas are fixed before
+    // this returns
+    pub async fn repair(lally: &Arc<Lally>, key: &str, responses: &[(String, GetKvResponse)]) {
+        let read_repair = &lally.read_repair;
+        read_repair.checks.fetch_add(1, Ordering::Relaxed);
+        let Some((latest, stale)) = stale_replicas(key, responses) else {
+            return;
+        };
+        match read_repair.mode {
+            ReadRepairMode::Blocking => Self::fix(Arc::clone(lally), key, latest, stale).await,
+            ReadRepairMode::Async => {
+                let lally = Arc::clone(lally);
+                let key = key.to_string();
+                tokio::spawn(async move { Self::fix(lally, &key, latest, stale).await });
+            }
+        }
+    }
+
+    // checks the replies that came in after the read answered. the replicas the read heard
+    // from were checked then, they're only fixed again when a late reply was newer still
+    pub async fn repair_late(
+        lally: &Arc<Lally>,
+        key: &str,
+        answered: &[(String, GetKvResponse)],
+        late: Vec<(String, GetKvResponse)>,
+    ) {
+        if late.is_empty() {
+            return;
+        }
+        lally.read_repair.checks.fetch_add(1, Ordering::Relaxed);
+        let seen = newest(answered).map(|response| response.timestamp);
+        let mut responses = answered.to_vec();
+        responses.extend(late.iter().cloned());
+        let Some((latest, mut stale)) = stale_replicas(key, &responses) else {
+            return;
+        };
+        if seen == Some(latest.timestamp) {
+            stale.retain(|id| late.iter().any(|(late, _)| late == id));
+        }
+        if !stale.is_empty() {
+            Self::fix(Arc::clone(lally), key, latest, stale).await;
+        }
+    }
+
+    // with read_repair_chance, reads every replica of the key in the background when the
+    // read itself didn't hear from all of them
+    pub fn maybe_read_all(lally: &Arc<Lally>, key: &str, answered: usize) {
+        let read_repair = &lally.read_repair;
+        let placement = lally.pool.placement(key);
+        let replicas = placement.peers.len() + usize::from(placement.local);
+        if answered >= replicas || !rand::thread_rng().gen_bool(read_repair.chance) {
+            return;
+        }
+        read_repair.full_reads.fetch_add(1, Ordering::Relaxed);
+
+        let lally = Arc::clone(lally);
+        let key = key.to_string();
+        tokio::spawn(async move {
+            let operation = Operation {
+                key: key.clone(),
+                value: None,
+                name: String::from("GET"),
+                timestamp: create_timestamp(),
+                level: String::from("INFO"),
+                annotations: HashMap::new(),
+            };
+            let mut responses = lally
+                .pool
+                .get_kv(
+                    &operation,
+                    &placement.peers,
+                    &Quorum::votes(placement.peers.len()),
+                )
+                .await
+                .0
+                .responses;
+            if placement.local {
+                let local = lally.store.get(&operation);
+                responses.push((
+                    LOCAL.to_string(),
+                    GetKvResponse {
+                        value: local.value,
+                        timestamp: local.timestamp,
+                    },
+                ));
+            }
+            lally.read_repair.checks.fetch_add(1, Ordering::Relaxed);
+            if let Some((latest, stale)) = stale_replicas(&key, &responses) {
+                Self::fix(Arc::clone(&lally), &key, latest, stale).await;
+            }
+        });
+    }
+
+    async fn fix(lally: Arc<Lally>, key: &str, latest: GetKvResponse, stale: Vec<String>) {
+        let timestamp = latest.timestamp.expect("newest answer has a timestamp");
+        let Some(_claim) = lally.read_repair.claim(key, timestamp) else {
+            debug!(key = %key, "Read repair with a write as new already running, skipping");
+            return;
+        };
+
+        let mut repairs = JoinSet::new();
+        for id in stale {
+            let lally = Arc::clone(&lally);
+            let operation = repair_operation(key, &latest);
+            repairs.spawn(async move {
+                if id == LOCAL {
+                    // logged like any other write, so the repair survives a restart
+                    lally.hooks.invoke_all(&operation);
+                    match operation.value {
+                        Some(_) => lally.store.add(&operation),
+                        None => lally.store.remove(&operation),
+                    };
+                    return Ok(());
+                }
+                match operation.value {
+                    Some(_) => lally.pool.solo_add_kv(&operation, &id).await,
+                    None => lally.pool.solo_remove_kv(&operation, &id).await,
+                }
+            });
+        }
+
+        let read_repair = &lally.read_repair;
+        while let Some(result) = repairs.join_next().await {
+            match result {
+                Ok(Ok(())) => {
+                    read_repair.repaired.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(Err(e)) => {
+                    warn!(key = %key, "Read repair failed: {:#}", e);
+                    read_repair.failed.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    error!("Task panicked: {:?}", e);
+                    read_repair.failed.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,356 @@
+use crate::cluster::services::KvData;
+use crate::config::Config;
+use crate::lally::ring::HashRing;
+use crate::lally::Lally;
+use crate::utils::timestamp::{create_timestamp, timestamp_to_rfc3339};
+use crate::utils::Operation;
+use prost_types::Timestamp;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use tokio::time::{sleep, Duration};
+use tracing::{debug, error, info};
+
+// lets a burst of joins and leaves settle before any data is moved around
+const SETTLE_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Default, Serialize)]
+pub struct RebalanceProgress {
+    pub running: bool,
+    pub runs: u64,
+    pub started_at: Option<String>,
+    pub finished_at: Option<String>,
+    pub keys_scanned: usize,
+    pub keys_to_move: usize,
+    pub keys_sent: usize,
+    pub keys_dropped: usize,
+    pub failed_batches: usize,
+}
+
+pub struct Rebalancer {
+    batch_size: usize,
+    batch_interval: Duration,
+    // set when a removal asked for the keys to stay where they are
+    hold: AtomicBool,
+    progress: RwLock<RebalanceProgress>,
+}
+
+// keys this node hands off for good, they are only dropped once every new owner has acked them
+struct Handoff {
+    timestamp: Timestamp,
+    pending_acks: usize,
+    failed: bool,
+}
+
+impl Handoff {
+    // the key can be dropped once every new owner has it
+    fn acked(&self) -> bool {
+        !self.failed && self.pending_acks == 0
+    }
+}
+
+// the nodes a local key is sent to after the ring changed, and whether this node still owns it
+fn targets(
+    old_ring: &HashRing,
+    ring: &HashRing,
+    local: &str,
+    key: &str,
+    replication_factor: usize,
+) -> (Vec<String>, bool) {
+    let old_replicas = old_ring.preference_list(key, replication_factor);
+    let new_replicas = ring.preference_list(key, replication_factor);
+
+    let owns_now = new_replicas.iter().any(|node| node == local);
+    if !owns_now {
+        // not an owner anymore, every current owner gets a copy before we let go
+        return (new_replicas, false);
+    }
+    // only the first surviving old replica streams to the gained owners, so the rest of the
+    // old replicas don't send the same key over and over
+    let sender = old_replicas.iter().find(|node| ring.contains(node));
+    if sender.map(String::as_str) != Some(local) {
+        return (Vec::new(), true);
+    }
+    let gained = new_replicas
+        .into_iter()
+        .filter(|node| !old_replicas.contains(node))
+        .collect();
+    (gained, true)
+}
+
+impl Rebalancer {
+    pub fn new(config: &Config) -> Self {
+        Rebalancer {
+            batch_size: config.rebalance_batch_size().max(1),
+            batch_interval: Duration::from_millis(config.rebalance_batch_interval()),
+            hold: AtomicBool::new(false),
+            progress: RwLock::new(RebalanceProgress::default()),
+        }
+    }
+
+    pub fn progress(&self) -> RebalanceProgress {
+        self.progress
+            .read()
+            .expect("rebalance progress lock poisoned")
+            .clone()
+    }
+
+    fn update_progress(&self, update: impl FnOnce(&mut RebalanceProgress)) {
+        let mut progress = self
+            .progress
+            .write()
+            .expect("rebalance progress lock poisoned");
+        update(&mut progress);
+    }
+
+    // the next membership change updates the ring without moving any keys, changes that
+    // arrive within the same settle delay are held along with it
+    pub fn hold_next(&self) {
+        self.hold.store(true, Ordering::Release);
+    }
+
+    pub async fn run(lally: Arc<Lally>) {
+        let (mut last_ring, _) = lally.pool.ring_snapshot();
+        loop {
+            lally.pool.membership_changed().await;
+            sleep(SETTLE_DELAY).await;
+
+            let (ring, local) = lally.pool.ring_snapshot();
+            if lally.rebalancer.hold.swap(false, Ordering::AcqRel) {
+                info!("Membership changed, keys are left where they are as requested");
+            } else {
+                lally
+                    .rebalancer
+                    .rebalance(&lally, &last_ring, &ring, &local)
+                    .await;
+            }
+            last_ring = ring;
+        }
+    }
+
+    // works out which of the local keys have new owners, streams them over in throttled
+    // batches, and drops the keys this node no longer replicates once they are acknowledged
+    async fn rebalance(&self, lally: &Lally, old_ring: &HashRing, ring: &HashRing, local: &str) {
+        let replication_factor = lally.pool.configured_replication_factor();
+        let entries = lally.store.export_store();
+        self.update_progress(|progress| {
+            *progress = RebalanceProgress {
+                running: true,
+                runs: progress.runs + 1,
+                started_at: Some(timestamp_to_rfc3339(&create_timestamp())),
+                keys_scanned: entries.len(),
+                ..Default::default()
+            };
+        });
+
+        let mut outgoing: HashMap<String, Vec<KvData>> = HashMap::new();
+        let mut handoffs: HashMap<String, Handoff> = HashMap::new();
+        for entry in entries {
+            let (targets, owns_now) =
+                targets(old_ring, ring, local, &entry.key, replication_factor);
+            if targets.is_empty() {
+                continue;
+            }
+
+            if !owns_now {
+                if let Some(timestamp) = entry.timestamp {
+                    handoffs.insert(
+                        entry.key.clone(),
+                        Handoff {
+                            timestamp,
+                            pending_acks: targets.len(),
+                            failed: false,
+                        },
+                    );
+                }
+            }
+            for target in targets {
+                outgoing.entry(target).or_default().push(entry.clone());
+            }
+        }
+
+        let keys_to_move: usize = outgoing.values().map(Vec::len).sum();
+        self.update_progress(|progress| progress.keys_to_move = keys_to_move);
+        if keys_to_move == 0 {
+            debug!("Membership changed, but no keys need to move");
+        } else {
+            info!(
+                "Moving {} keys to {} nodes after a membership change",
+                keys_to_move,
+                outgoing.len()
+            );
+        }
+
+        let mut batch: u64 = 0;
+        for (target, entries) in outgoing {
+            for chunk in entries.chunks(self.batch_size) {
+                batch += 1;
+                let keys: Vec<String> = chunk.iter().map(|e| e.key.clone()).collect();
+                match lally.pool.transfer(&target, chunk.to_vec(), batch).await {
+                    Ok(accepted) => {
+                        debug!("Batch {} of {} keys acked by {}", batch, accepted, target);
+                        for key in keys.iter() {
+                            if let Some(handoff) = handoffs.get_mut(key) {
+                                handoff.pending_acks -= 1;
+                            }
+                        }
+                        self.update_progress(|progress| progress.keys_sent += keys.len());
+                    }
+                    Err(e) => {
+                        error!("{:#}", e);
+                        for key in keys.iter() {
+                            if let Some(handoff) = handoffs.get_mut(key) {
+                                handoff.failed = true;
+                            }
+                        }
+                        self.update_progress(|progress| progress.failed_batches += 1);
+                    }
+                }
+                let progress = self.progress();
+                info!(
+                    "Rebalance progress: {}/{} keys sent, {} failed batches",
+                    progress.keys_sent, progress.keys_to_move, progress.failed_batches
+                );
+                sleep(self.batch_interval).await;
+            }
+        }
+
+        let mut dropped = 0;
+        for (key, handoff) in handoffs {
+            if !handoff.acked() {
+                continue;
+            }
+            if lally.store.drop_key(&key, &handoff.timestamp) {
+                lally.hooks.invoke_all(&Operation {
+                    name: String::from("DROP"),
+                    level: String::from("INFO"),
+                    key,
+                    value: None,
+                    timestamp: handoff.timestamp,
+                    annotations: HashMap::new(),
+                });
+                dropped += 1;
+            }
+        }
+
+        self.update_progress(|progress| {
+            progress.running = false;
+            progress.keys_dropped = dropped;
+            progress.finished_at = Some(timestamp_to_rfc3339(&create_timestamp()));
+        });
+        let progress = self.progress();
+        info!(
+            "Rebalance finished: {} keys sent, {} dropped locally, {} failed batches",
+            progress.keys_sent, progress.keys_dropped, progress.failed_batches
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ring(nodes: &[&str]) -> HashRing {
+        let mut ring = HashRing::new(16);
+        for node in nodes {
+            ring.add(node);
+        }
+        ring
+    }
+
+    fn keys() -> impl Iterator<Item = String> {
+        (0..500).map(|i| format!("key-{}", i))
+    }
+
+    #[test]
+    fn owned_keys_stay_put_when_the_ring_is_unchanged() {
+        let ring = ring(&["a", "b", "c"]);
+        for key in keys() {
+            let owners = ring.preference_list(&key, 2);
+            let (targets, owns) = targets(&ring, &ring, "a", &key, 2);
+            if owners.contains(&"a".to_string()) {
+                assert!(owns);
+                assert!(targets.is_empty(), "{} moved to {:?}", key, targets);
+            } else {
+                // a stray copy still goes back to the owners before it's dropped
+                assert!(!owns);
+                assert_eq!(targets, owners);
+            }
+        }
+    }
+
+    #[test]
+    fn a_key_this_node_lost_goes_to_every_new_owner() {
+        let old = ring(&["a", "b"]);
+        let new = ring(&["a", "b", "c"]);
+        let mut lost = 0;
+        for key in keys() {
+            let (targets, owns) = targets(&old, &new, "a", &key, 1);
+            if old.preference_list(&key, 1) == ["a"] && new.preference_list(&key, 1) != ["a"] {
+                assert!(!owns);
+                assert_eq!(targets, new.preference_list(&key, 1));
+                lost += 1;
+            }
+        }
+        assert!(lost > 0);
+    }
+
+    #[test]
+    fn only_the_first_surviving_old_replica_sends_to_a_gained_owner() {
+        let old = ring(&["a", "b", "c"]);
+        let new = ring(&["a", "b", "c", "d"]);
+        let mut sent = 0;
+        for key in keys() {
+            let old_replicas = old.preference_list(&key, 2);
+            let new_replicas = new.preference_list(&key, 2);
+            if !new_replicas.contains(&"a".to_string()) {
+                continue;
+            }
+            let (targets, owns) = targets(&old, &new, "a", &key, 2);
+            assert!(owns);
+            if old_replicas[0] == "a" {
+                let gained: Vec<String> = new_replicas
+                    .into_iter()
+                    .filter(|node| !old_replicas.contains(node))
+                    .collect();
+                assert_eq!(targets, gained);
+                sent += targets.len();
+            } else {
+                assert!(targets.is_empty(), "{} sent by a second replica", key);
+            }
+        }
+        assert!(sent > 0);
+    }
+
+    #[test]
+    fn a_removed_replica_is_not_counted_as_the_sender() {
+        let old = ring(&["a", "b", "c"]);
+        let new = ring(&["a", "b"]);
+        let mut checked = 0;
+        for key in keys() {
+            let old_replicas = old.preference_list(&key, 2);
+            if old_replicas[0] != "c" || !old_replicas.contains(&"a".to_string()) {
+                continue;
+            }
+            // c is gone, so a is the first survivor and streams to b, which gained the key
+            let (targets, owns) = targets(&old, &new, "a", &key, 2);
+            assert!(owns);
+            assert_eq!(targets, ["b"]);
+            checked += 1;
+        }
+        assert!(checked > 0);
+    }
+
+    #[test]
+    fn a_key_is_only_dropped_once_every_owner_acked_it() {
+        let handoff = |pending_acks, failed| Handoff {
+            timestamp: Timestamp::default(),
+            pending_acks,
+            failed,
+        };
+        assert!(handoff(0, false).acked());
+        assert!(!handoff(1, false).acked());
+        assert!(!handoff(0, true).acked());
+    }
+}
@@ -0,0 +1,440 @@
+use crate::auth::Signed;
+use crate::cluster::services::kv_store_client::KvStoreClient;
+use crate::cluster::services::{KvOperation, ReplicateBatch, ReplicateEntry, ReplicateResult};
+use crate::config::Config;
+use crate::lally::retry::CallError;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{sleep_until, timeout, Duration, Instant};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tonic::{Code, Status};
+use tracing::{debug, warn};
+
+type Client = KvStoreClient<Signed>;
+
+// a write waiting for its replica, the reply goes to whoever waits on the quorum
+struct Pending {
+    operation: KvOperation,
+    remove: bool,
+    reply: oneshot::Sender<Result<(), CallError>>,
+}
+
+#[derive(Serialize)]
+pub struct StreamMetrics {
+    pub open: u64,
+    pub batches: u64,
+    pub writes: u64,
+    pub broken: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    open: AtomicU64,
+    batches: AtomicU64,
+    writes: AtomicU64,
+    broken: AtomicU64,
+}
+
+#[derive(Clone, Copy)]
+struct Batching {
+    size: usize,
+    linger: Duration,
+    window: usize,
+}
+
+// one long-lived replication stream per peer instead of a call per write. writes queue up
+// per peer, whatever queued up while the last batch went out goes as the next one, and
+// acks come back by sequence number, so batches are sent without waiting for the ones before
+pub struct ReplicationStreams {
+    batching: Batching,
+    peers: Mutex<HashMap<String, mpsc::Sender<Pending>>>,
+    counters: Arc<Counters>,
+}
+
+// the way into one peer's stream
+#[derive(Clone)]
+pub struct ReplicaStream {
+    queue: mpsc::Sender<Pending>,
+}
+
+impl ReplicaStream {
+    // waits for this write's own ack, the writes batched with it don't matter
+    pub async fn write(
+        &self,
+        operation: KvOperation,
+        remove: bool,
+        deadline: Duration,
+    ) -> Result<(), CallError> {
+        let (reply, acked) = oneshot::channel();
+        let pending = Pending {
+            operation,
+            remove,
+            reply,
+        };
+        let write = async {
+            self.queue.send(pending).await.map_err(|_| closed())?;
+            acked.await.map_err(|_| closed())?
+        };
+        timeout(deadline, write)
+            .await
+            .unwrap_or(Err(CallError::TimedOut))
+    }
+}
+
+fn closed() -> CallError {
+    CallError::Failed(Box::new(Status::unavailable(
+        "Replication stream was closed",
+    )))
+}
+
+fn outcome(result: ReplicateResult) -> Result<(), CallError> {
+    match Code::from_i32(result.code) {
+        Code::Ok => Ok(()),
+        code => Err(CallError::Failed(Box::new(Status::new(
+            code,
+            result.message,
+        )))),
+    }
+}
+
+impl ReplicationStreams {
+    pub fn new(config: &Config) -> Self {
+        ReplicationStreams {
+            batching: Batching {
+                size: config.replication_batch_size().max(1),
+                linger: Duration::from_millis(config.replication_linger()),
+                window: config.replication_window().max(1),
+            },
+            peers: Mutex::new(HashMap::new()),
+            counters: Arc::new(Counters::default()),
+        }
+    }
+
+    // the peer's stream, started on first use with a client made by the caller
+    pub fn get(&self, peer: &str, client: impl FnOnce() -> Client) -> ReplicaStream {
+        let mut peers = self.peers.lock().expect("streams lock poisoned");
+        if let Some(queue) = peers.get(peer).filter(|queue| !queue.is_closed()) {
+            return ReplicaStream {
+                queue: queue.clone(),
+            };
+        }
+        let (queue, writes) = mpsc::channel(self.batching.window);
+        tokio::spawn(drive(
+            peer.to_string(),
+            client(),
+            writes,
+            self.batching,
+            Arc::clone(&self.counters),
+        ));
+        peers.insert(peer.to_string(), queue.clone());
+        ReplicaStream { queue }
+    }
+
+    // the peer's channel changed or it's gone, the old stream finishes what it has in flight
+    // and stops
+    pub fn forget(&self, peer: &str) {
+        self.peers
+            .lock()
+            .expect("streams lock poisoned")
+            .remove(peer);
+    }
+
+    pub fn metrics(&self) -> StreamMetrics {
+        StreamMetrics {
+            open: self.counters.open.load(Ordering::Relaxed),
+            batches: self.counters.batches.load(Ordering::Relaxed),
+            writes: self.counters.writes.load(Ordering::Relaxed),
+            broken: self.counters.broken.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// the writes sent on a peer's stream that wait for their ack, by sequence number. numbers
+// keep counting up across reopened streams, so a late ack can't answer the wrong write
+#[derive(Default)]
+struct InFlight {
+    seq: u64,
+    replies: HashMap<u64, oneshot::Sender<Result<(), CallError>>>,
+}
+
+impl InFlight {
+    fn len(&self) -> usize {
+        self.replies.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.replies.is_empty()
+    }
+
+    // numbers the writes of a batch, the ones whose caller gave up already and hinted the
+    // write aren't sent
+    fn number(&mut self, batch: Vec<Pending>) -> Vec<ReplicateEntry> {
+        let mut entries = Vec::with_capacity(batch.len());
+        for write in batch {
+            if write.reply.is_closed() {
+                continue;
+            }
+            self.seq += 1;
+            self.replies.insert(self.seq, write.reply);
+            entries.push(ReplicateEntry {
+                seq: self.seq,
+                operation: Some(write.operation),
+                remove: write.remove,
+            });
+        }
+        entries
+    }
+
+    // answers every write the ack has a result for, results for writes nobody waits on are
+    // ignored
+    fn ack(&mut self, results: Vec<ReplicateResult>) {
+        for result in results {
+            if let Some(reply) = self.replies.remove(&result.seq) {
+                let _ = reply.send(outcome(result));
+            }
+        }
+    }
+
+    fn fail_all(&mut self, status: &Status) {
+        for (_, reply) in self.replies.drain() {
+            let _ = reply.send(Err(CallError::Failed(Box::new(status.clone()))));
+        }
+    }
+}
+
+// takes the writes queued behind the first one, up to a batch, waiting up to linger for more
+async fn collect(
+    first: Pending,
+    writes: &mut mpsc::Receiver<Pending>,
+    limit: usize,
+    linger: Duration,
+) -> (Vec<Pending>, bool) {
+    let mut batch = vec![first];
+    let until = Instant::now() + linger;
+    while batch.len() < limit {
+        match writes.try_recv() {
+            Ok(write) => batch.push(write),
+            Err(mpsc::error::TryRecvError::Disconnected) => return (batch, true),
+            Err(mpsc::error::TryRecvError::Empty) if linger.is_zero() => break,
+            Err(mpsc::error::TryRecvError::Empty) => {
+                tokio::select! {
+                    write = writes.recv() => match write {
+                        Some(write) => batch.push(write),
+                        None => return (batch, true),
+                    },
+                    _ = sleep_until(until) => break,
+                }
+            }
+        }
+    }
+    (batch, false)
+}
+
+async fn drive(
+    peer: String,
+    mut client: Client,
+    mut writes: mpsc::Receiver<Pending>,
+    batching: Batching,
+    counters: Arc<Counters>,
+) {
+    let mut in_flight = InFlight::default();
+    loop {
+        // streams are opened for the first write and again for the first one after they broke
+        let Some(first) = writes.recv().await else {
+            return;
+        };
+        let (batches, outbound) = mpsc::unbounded_channel();
+        let mut acks = match client
+            .replicate(UnboundedReceiverStream::new(outbound))
+            .await
+        {
+            Ok(response) => response.into_inner(),
+            Err(status) => {
+                debug!(peer = %peer, "Failed to open a replication stream: {}", status);
+                let _ = first.reply.send(Err(CallError::Failed(Box::new(status))));
+                continue;
+            }
+        };
+        counters.open.fetch_add(1, Ordering::Relaxed);
+        debug!(peer = %peer, "Opened a replication stream");
+
+        let mut ready = Some(first);
+        let mut closed = false;
+        let broken = loop {
+            if let Some(first) = ready.take() {
+                let limit = batching.window.saturating_sub(in_flight.len()).max(1);
+                let (batch, disconnected) = collect(
+                    first,
+                    &mut writes,
+                    limit.min(batching.size),
+                    batching.linger,
+                )
+                .await;
+                closed |= disconnected;
+                let entries = in_flight.number(batch);
+                if !entries.is_empty() {
+                    counters.batches.fetch_add(1, Ordering::Relaxed);
+                    counters
+                        .writes
+                        .fetch_add(entries.len() as u64, Ordering::Relaxed);
+                    if batches.send(ReplicateBatch { entries }).is_err() {
+                        break Status::unavailable("Replication stream was closed");
+                    }
+                }
+            }
+            if closed && in_flight.is_empty() {
+                counters.open.fetch_sub(1, Ordering::Relaxed);
+                debug!(peer = %peer, "Closed the replication stream");
+                return;
+            }
+            tokio::select! {
+                biased;
+                ack = acks.message() => match ack {
+                    Ok(Some(ack)) => in_flight.ack(ack.results),
+                    Ok(None) => break Status::unavailable("Replica closed the replication stream"),
+                    Err(status) => break status,
+                },
+                write = writes.recv(), if !closed && in_flight.len() < batching.window => match write {
+                    Some(write) => ready = Some(write),
+                    None => closed = true,
+                },
+            }
+        };
+
+        counters.open.fetch_sub(1, Ordering::Relaxed);
+        counters.broken.fetch_add(1, Ordering::Relaxed);
+        warn!(
+            peer = %peer,
+            "Replication stream broke with {} writes in flight: {}",
+            in_flight.len(),
+            broken.message()
+        );
+        in_flight.fail_all(&broken);
+        if closed {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(key: &str) -> (Pending, oneshot::Receiver<Result<(), CallError>>) {
+        let (reply, acked) = oneshot::channel();
+        let pending = Pending {
+            operation: KvOperation {
+                name: String::from("ADD"),
+                level: String::from("INFO"),
+                key: key.to_string(),
+                value: Some(String::from("value")),
+                timestamp: None,
+            },
+            remove: false,
+            reply,
+        };
+        (pending, acked)
+    }
+
+    fn result(seq: u64, code: Code) -> ReplicateResult {
+        ReplicateResult {
+            seq,
+            code: code as i32,
+            message: String::new(),
+        }
+    }
+
+    #[test]
+    fn writes_are_numbered_across_batches() {
+        let mut in_flight = InFlight::default();
+        let (a, _a) = write("a");
+        let (b, _b) = write("b");
+        let (c, _c) = write("c");
+        let first = in_flight.number(vec![a, b]);
+        let second = in_flight.number(vec![c]);
+        let seqs: Vec<u64> = first.iter().chain(second.iter()).map(|e| e.seq).collect();
+        assert_eq!(seqs, [1, 2, 3]);
+        assert_eq!(in_flight.len(), 3);
+    }
+
+    #[test]
+    fn writes_the_caller_gave_up_on_are_not_sent() {
+        let mut in_flight = InFlight::default();
+        let (a, acked) = write("a");
+        drop(acked);
+        let (b, _b) = write("b");
+        let entries = in_flight.number(vec![a, b]);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].operation.as_ref().unwrap().key, "b");
+    }
+
+    #[tokio::test]
+    async fn every_write_gets_its_own_result_in_any_order() {
+        let mut in_flight = InFlight::default();
+        let (a, a_acked) = write("a");
+        let (b, b_acked) = write("b");
+        in_flight.number(vec![a, b]);
+
+        in_flight.ack(vec![result(2, Code::InvalidArgument)]);
+        assert_eq!(in_flight.len(), 1);
+        in_flight.ack(vec![result(1, Code::Ok)]);
+        assert!(in_flight.is_empty());
+
+        assert!(a_acked.await.unwrap().is_ok());
+        assert!(matches!(
+            b_acked.await.unwrap(),
+            Err(CallError::Failed(status)) if status.code() == Code::InvalidArgument
+        ));
+    }
+
+    #[test]
+    fn acks_for_unknown_writes_are_ignored() {
+        let mut in_flight = InFlight::default();
+        let (a, _a) = write("a");
+        in_flight.number(vec![a]);
+        in_flight.ack(vec![result(7, Code::Ok)]);
+        assert_eq!(in_flight.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_broken_stream_fails_what_is_in_flight() {
+        let mut in_flight = InFlight::default();
+        let (a, acked) = write("a");
+        in_flight.number(vec![a]);
+        in_flight.fail_all(&Status::unavailable("gone"));
+        assert!(in_flight.is_empty());
+        assert!(matches!(
+            acked.await.unwrap(),
+            Err(CallError::Failed(status)) if status.code() == Code::Unavailable
+        ));
+
+        // a new stream keeps counting, a late ack for the old one answers nothing
+        let (b, _b) = write("b");
+        assert_eq!(in_flight.number(vec![b])[0].seq, 2);
+    }
+
+    #[tokio::test]
+    async fn a_batch_takes_what_is_queued_up_to_the_limit() {
+        let (queue, mut writes) = mpsc::channel(8);
+        let mut receivers = Vec::new();
+        for key in ["b", "c", "d"] {
+            let (pending, acked) = write(key);
+            queue.send(pending).await.unwrap();
+            receivers.push(acked);
+        }
+        let (first, _first) = write("a");
+        let (batch, disconnected) = collect(first, &mut writes, 3, Duration::ZERO).await;
+        let keys: Vec<&str> = batch.iter().map(|w| w.operation.key.as_str()).collect();
+        assert_eq!(keys, ["a", "b", "c"]);
+        assert!(!disconnected);
+
+        drop(queue);
+        let (first, _first) = write("e");
+        let (batch, disconnected) = collect(first, &mut writes, 3, Duration::ZERO).await;
+        assert_eq!(batch.len(), 2);
+        assert!(disconnected);
+    }
+}
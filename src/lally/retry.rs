@@ -0,0 +1,199 @@
+use crate::config::Config;
+use rand::Rng;
+use std::fmt;
+use std::future::Future;
+use tokio::time::{sleep, timeout, Duration, Instant};
+use tonic::{Code, Response, Status};
+
+// however many attempts came before, a single backoff never gets longer than this
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+// why a replica didn't answer, a slow replica and a broken one call for different fixes
+#[derive(Debug)]
+pub enum CallError {
+    TimedOut,
+    Failed(Box<Status>),
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallError::TimedOut => write!(f, "timed out"),
+            CallError::Failed(status) => write!(f, "{}", status),
+        }
+    }
+}
+
+// errors that say nothing about the request itself, so trying again may well work
+fn retryable(status: &Status) -> bool {
+    matches!(
+        status.code(),
+        Code::Unavailable | Code::ResourceExhausted | Code::Aborted | Code::Unknown
+    )
+}
+
+// deadline and retries for a call to a replica
+#[derive(Clone, Copy)]
+pub struct Retry {
+    retries: usize,
+    backoff: Duration,
+}
+
+impl Retry {
+    pub fn new(config: &Config) -> Self {
+        Retry {
+            retries: config.rpc_retries(),
+            backoff: Duration::from_millis(config.rpc_retry_backoff()),
+        }
+    }
+
+    // full jitter, so replicas that failed together don't get retried in lockstep
+    fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .backoff
+            .saturating_mul(1 << attempt.min(16))
+            .min(MAX_BACKOFF);
+        ceiling.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+
+    // runs the call until it succeeds, fails for good or the deadline passes; only
+    // idempotent calls are retried, the call gets the time it has left to pass on to
+    // the server
+    pub async fn call<T, F, Fut>(
+        self,
+        deadline: Duration,
+        idempotent: bool,
+        mut call: F,
+    ) -> Result<T, CallError>
+    where
+        F: FnMut(Duration) -> Fut,
+        Fut: Future<Output = Result<Response<T>, Status>>,
+    {
+        let until = Instant::now() + deadline;
+        let retries = if idempotent { self.retries } else { 0 };
+        let attempts = async {
+            let mut attempt = 0;
+            loop {
+                let remaining = until.saturating_duration_since(Instant::now());
+                match call(remaining).await {
+                    Ok(response) => return Ok(response.into_inner()),
+                    Err(status)
+                        if matches!(status.code(), Code::DeadlineExceeded | Code::Cancelled) =>
+                    {
+                        return Err(CallError::TimedOut)
+                    }
+                    Err(status) if attempt < retries && retryable(&status) => {
+                        sleep(self.backoff(attempt as u32)).await;
+                        attempt += 1;
+                    }
+                    Err(status) => return Err(CallError::Failed(Box::new(status))),
+                }
+            }
+        };
+        timeout(deadline, attempts)
+            .await
+            .unwrap_or(Err(CallError::TimedOut))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn retry(retries: usize, backoff_ms: u64) -> Retry {
+        Retry {
+            retries,
+            backoff: Duration::from_millis(backoff_ms),
+        }
+    }
+
+    // fails with the status until it has been called `failures` times
+    async fn flaky(
+        retry: Retry,
+        idempotent: bool,
+        failures: usize,
+        status: fn() -> Status,
+    ) -> (Result<(), CallError>, usize) {
+        let calls = AtomicUsize::new(0);
+        let result = retry
+            .call(Duration::from_secs(5), idempotent, |_| {
+                let call = calls.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    if call < failures {
+                        Err(status())
+                    } else {
+                        Ok(Response::new(()))
+                    }
+                }
+            })
+            .await;
+        (result, calls.load(Ordering::SeqCst))
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let retry = retry(3, 10);
+        for attempt in 0..20 {
+            let ceiling = Duration::from_millis(10 << attempt.min(16)).min(MAX_BACKOFF);
+            for _ in 0..50 {
+                assert!(retry.backoff(attempt) <= ceiling);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_until_it_works() {
+        let (result, calls) = flaky(retry(3, 1), true, 2, || Status::unavailable("down")).await;
+        assert!(result.is_ok());
+        assert_eq!(calls, 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_retries() {
+        let (result, calls) = flaky(retry(2, 1), true, 10, || Status::unavailable("down")).await;
+        assert!(
+            matches!(result, Err(CallError::Failed(status)) if status.code() == Code::Unavailable)
+        );
+        assert_eq!(calls, 3);
+    }
+
+    #[tokio::test]
+    async fn only_retries_idempotent_calls() {
+        let (result, calls) = flaky(retry(3, 1), false, 1, || Status::unavailable("down")).await;
+        assert!(matches!(result, Err(CallError::Failed(_))));
+        assert_eq!(calls, 1);
+    }
+
+    #[tokio::test]
+    async fn doesnt_retry_errors_about_the_request() {
+        let (result, calls) = flaky(retry(3, 1), true, 1, || Status::invalid_argument("bad")).await;
+        assert!(
+            matches!(result, Err(CallError::Failed(status)) if status.code() == Code::InvalidArgument)
+        );
+        assert_eq!(calls, 1);
+    }
+
+    #[tokio::test]
+    async fn reports_deadlines_as_timeouts() {
+        let (result, calls) =
+            flaky(retry(3, 1), true, 1, || Status::deadline_exceeded("slow")).await;
+        assert!(matches!(result, Err(CallError::TimedOut)));
+        assert_eq!(calls, 1);
+    }
+
+    #[tokio::test]
+    async fn stops_at_the_deadline() {
+        let deadline = Duration::from_millis(50);
+        let started = Instant::now();
+        let result: Result<(), CallError> = retry(100, 5)
+            .call(deadline, true, |remaining| async move {
+                assert!(remaining <= deadline);
+                sleep(Duration::from_millis(10)).await;
+                Err(Status::unavailable("down"))
+            })
+            .await;
+        assert!(matches!(result, Err(CallError::TimedOut)));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
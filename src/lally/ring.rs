@@ -0,0 +1,265 @@
+use rapidhash::v3::rapidhash_v3;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+// ring positions have to agree across every node in the cluster, so this uses the
+// unseeded v3 hash instead of the RandomState used for the in-memory maps
+pub fn hash_key(key: &str) -> u64 {
+    rapidhash_v3(key.as_bytes())
+}
+
+#[derive(Clone)]
+pub struct HashRing {
+    virtual_nodes: usize,
+    tokens: BTreeMap<u64, String>,
+    nodes: BTreeSet<String>,
+    // nodes that are in no zone are left out and share the unnamed one
+    zones: BTreeMap<String, String>,
+    // whether replicas are spread across zones, only once every node places them that way
+    zone_aware: bool,
+}
+
+impl HashRing {
+    pub fn new(virtual_nodes: usize) -> Self {
+        HashRing {
+            virtual_nodes: virtual_nodes.max(1),
+            tokens: BTreeMap::new(),
+            nodes: BTreeSet::new(),
+            zones: BTreeMap::new(),
+            zone_aware: false,
+        }
+    }
+
+    fn node_tokens<'a>(&self, node: &'a str) -> impl Iterator<Item = u64> + 'a {
+        (0..self.virtual_nodes).map(move |vnode| hash_key(&format!("{}#{}", node, vnode)))
+    }
+
+    pub fn add(&mut self, node: &str) -> bool {
+        if !self.nodes.insert(node.to_string()) {
+            return false;
+        }
+        for token in self.node_tokens(node).collect::<Vec<u64>>() {
+            // on the (unlikely) collision the lexically smaller node keeps the token, so
+            // every node resolves it the same way regardless of insertion order
+            match self.tokens.get(&token) {
+                Some(owner) if owner.as_str() <= node => {}
+                _ => {
+                    self.tokens.insert(token, node.to_string());
+                }
+            }
+        }
+        true
+    }
+
+    pub fn remove(&mut self, node: &str) -> bool {
+        if !self.nodes.remove(node) {
+            return false;
+        }
+        self.tokens.retain(|_, owner| owner != node);
+        self.zones.remove(node);
+        // re-adding the remaining nodes reclaims any token the removed node had won
+        let remaining: Vec<String> = self.nodes.iter().cloned().collect();
+        for other in remaining {
+            for token in self.node_tokens(&other).collect::<Vec<u64>>() {
+                match self.tokens.get(&token) {
+                    Some(owner) if owner.as_str() <= other.as_str() => {}
+                    _ => {
+                        self.tokens.insert(token, other.clone());
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    // true when the node moved to another zone
+    pub fn set_zone(&mut self, node: &str, zone: &str) -> bool {
+        if self.zone(node) == zone {
+            return false;
+        }
+        if zone.is_empty() {
+            self.zones.remove(node);
+        } else {
+            self.zones.insert(node.to_string(), zone.to_string());
+        }
+        true
+    }
+
+    pub fn zone(&self, node: &str) -> &str {
+        self.zones.get(node).map(String::as_str).unwrap_or_default()
+    }
+
+    // true when the placement changed
+    pub fn set_zone_aware(&mut self, zone_aware: bool) -> bool {
+        std::mem::replace(&mut self.zone_aware, zone_aware) != zone_aware
+    }
+
+    pub fn contains(&self, node: &str) -> bool {
+        self.nodes.contains(node)
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    // walks clockwise from the key's position and collects the first `n` distinct nodes,
+    // which is the key's preference list; the first entry is the key's primary owner. when
+    // zone aware, the first walk only takes nodes from zones that don't have a replica yet,
+    // and a second one fills up with the nodes it skipped
+    pub fn preference_list(&self, key: &str, n: usize) -> Vec<String> {
+        let n = n.min(self.nodes.len());
+        let mut replicas: Vec<String> = Vec::with_capacity(n);
+        if n == 0 {
+            return replicas;
+        }
+
+        let position = hash_key(key);
+        let clockwise = || {
+            self.tokens
+                .range(position..)
+                .chain(self.tokens.range(..position))
+        };
+        if self.zone_aware {
+            let all_zones: HashSet<&str> = self.nodes.iter().map(|node| self.zone(node)).collect();
+            let mut zones: HashSet<&str> = HashSet::new();
+            for (_, node) in clockwise() {
+                if zones.insert(self.zone(node)) {
+                    replicas.push(node.clone());
+                    if replicas.len() == n {
+                        return replicas;
+                    }
+                    if zones.len() == all_zones.len() {
+                        break;
+                    }
+                }
+            }
+        }
+        for (_, node) in clockwise() {
+            if !replicas.contains(node) {
+                replicas.push(node.clone());
+                if replicas.len() == n {
+                    break;
+                }
+            }
+        }
+        replicas
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ring(nodes: &[&str]) -> HashRing {
+        let mut ring = HashRing::new(16);
+        for node in nodes {
+            ring.add(node);
+        }
+        ring
+    }
+
+    fn keys() -> impl Iterator<Item = String> {
+        (0..500).map(|i| format!("key-{}", i))
+    }
+
+    #[test]
+    fn picks_distinct_nodes() {
+        let ring = ring(&["a", "b", "c", "d", "e"]);
+        for key in keys() {
+            let replicas = ring.preference_list(&key, 3);
+            assert_eq!(replicas.len(), 3);
+            let distinct: HashSet<&String> = replicas.iter().collect();
+            assert_eq!(distinct.len(), 3, "{:?}", replicas);
+        }
+    }
+
+    #[test]
+    fn caps_at_the_ring_size() {
+        assert!(HashRing::new(16).preference_list("key", 3).is_empty());
+        let ring = ring(&["a", "b"]);
+        assert_eq!(ring.preference_list("key", 3).len(), 2);
+        assert!(ring.preference_list("key", 0).is_empty());
+    }
+
+    #[test]
+    fn agrees_regardless_of_insertion_order() {
+        let forward = ring(&["a", "b", "c", "d"]);
+        let backward = ring(&["d", "c", "b", "a"]);
+        for key in keys() {
+            assert_eq!(
+                forward.preference_list(&key, 3),
+                backward.preference_list(&key, 3)
+            );
+        }
+    }
+
+    #[test]
+    fn removing_a_node_only_moves_its_keys() {
+        let before = ring(&["a", "b", "c", "d", "e"]);
+        let mut after = before.clone();
+        assert!(after.remove("c"));
+        assert!(!after.remove("c"));
+        for key in keys() {
+            let old = before.preference_list(&key, 2);
+            let new = after.preference_list(&key, 2);
+            assert!(!new.contains(&"c".to_string()));
+            if !old.contains(&"c".to_string()) {
+                assert_eq!(old, new);
+            }
+        }
+    }
+
+    #[test]
+    fn spreads_replicas_over_zones() {
+        let mut ring = ring(&["a1", "a2", "b1", "b2", "c1", "c2"]);
+        for node in ["a1", "a2", "b1", "b2", "c1", "c2"] {
+            ring.set_zone(node, &node[..1]);
+        }
+        assert!(ring.set_zone_aware(true));
+        assert!(!ring.set_zone_aware(true));
+        for key in keys() {
+            let replicas = ring.preference_list(&key, 3);
+            let zones: HashSet<&str> = replicas.iter().map(|node| ring.zone(node)).collect();
+            assert_eq!(zones.len(), 3, "{:?}", replicas);
+
+            // with more replicas than zones the rest fill up with the skipped nodes
+            let replicas = ring.preference_list(&key, 5);
+            let distinct: HashSet<&String> = replicas.iter().collect();
+            assert_eq!(distinct.len(), 5);
+            let zones: HashSet<&str> = replicas[..3].iter().map(|node| ring.zone(node)).collect();
+            assert_eq!(zones.len(), 3, "{:?}", replicas);
+        }
+    }
+
+    #[test]
+    fn nodes_without_a_zone_share_one() {
+        let mut ring = ring(&["a", "b", "x", "y"]);
+        ring.set_zone("a", "zone-a");
+        ring.set_zone("b", "zone-b");
+        ring.set_zone_aware(true);
+        for key in keys() {
+            let replicas = ring.preference_list(&key, 3);
+            let zones: HashSet<&str> = replicas.iter().map(|node| ring.zone(node)).collect();
+            assert_eq!(
+                zones,
+                HashSet::from(["zone-a", "zone-b", ""]),
+                "{:?}",
+                replicas
+            );
+        }
+    }
+
+    #[test]
+    fn ignores_zones_until_zone_aware() {
+        let mut ring = ring(&["a1", "a2", "b1"]);
+        for node in ["a1", "a2", "b1"] {
+            ring.set_zone(node, &node[..1]);
+        }
+        let plain = self::ring(&["a1", "a2", "b1"]);
+        for key in keys() {
+            assert_eq!(
+                ring.preference_list(&key, 2),
+                plain.preference_list(&key, 2)
+            );
+        }
+    }
+}
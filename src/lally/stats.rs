@@ -0,0 +1,142 @@
+use crate::cluster::services::{
+    ChannelStatus, ClusterStatusResponse, MemberStatus, NodeStats, NodeStatus,
+};
+use crate::config::{Config, QuorumMode};
+use crate::lally::detector::PeerState;
+use crate::lally::membership::MemberState;
+use crate::lally::protocol;
+use crate::lally::Lally;
+use crate::utils::timestamp::create_timestamp;
+use chrono::Utc;
+use prost_types::Timestamp;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::fs::metadata;
+use tokio::task::JoinSet;
+use tracing::{error, warn};
+
+// what this node reports about itself, and what it gathers from every peer for the
+// cluster status
+pub struct Stats {
+    started: Instant,
+    aof_path: PathBuf,
+}
+
+impl Stats {
+    pub fn new(config: &Config) -> Self {
+        Stats {
+            started: Instant::now(),
+            aof_path: config.aof_file().to_path_buf(),
+        }
+    }
+
+    pub async fn local(&self, lally: &Lally) -> NodeStats {
+        let (keys, tombstones) = lally.store.counts();
+        let (read_quorum, write_quorum, quorum_mode) = lally.pool.configured_quorums();
+        NodeStats {
+            id: lally.pool.local_id().to_string(),
+            addr: lally.pool.local_addr(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            uptime_ms: u64::try_from(self.started.elapsed().as_millis()).unwrap_or(u64::MAX),
+            keys,
+            tombstones,
+            aof_bytes: metadata(&self.aof_path)
+                .await
+                .map(|metadata| metadata.len())
+                .unwrap_or(0),
+            read_quorum: u32::try_from(read_quorum).unwrap_or(u32::MAX),
+            write_quorum: u32::try_from(write_quorum).unwrap_or(u32::MAX),
+            quorum_mode: String::from(match quorum_mode {
+                QuorumMode::Fixed => "fixed",
+                QuorumMode::Majority => "majority",
+            }),
+            replication_factor: u32::try_from(lally.pool.configured_replication_factor())
+                .unwrap_or(u32::MAX),
+            cluster_id: lally.cluster_state.cluster_id(),
+            protocol: Some(protocol::local()),
+            zone: lally.pool.local_zone(),
+        }
+    }
+
+    // asks every peer for its stats at once, a peer that doesn't answer is still listed
+    // with what this node knows about it
+    pub async fn cluster(lally: &Arc<Lally>) -> ClusterStatusResponse {
+        let local = lally.stats.local(lally).await;
+        let mut nodes = vec![NodeStatus {
+            id: local.id.clone(),
+            addr: local.addr.clone(),
+            state: MemberStatus::Alive.into(),
+            last_contact: Some(create_timestamp()),
+            stats: Some(local),
+            error: String::new(),
+            channel: None,
+        }];
+
+        let mut futures_set = JoinSet::new();
+        for peer in lally.pool.peers() {
+            let lally = Arc::clone(lally);
+            futures_set.spawn(async move {
+                // the detector only knows alive, suspect and dead, a node that left is
+                // only known to membership
+                let detected = match lally.detector.state(&peer.id) {
+                    PeerState::Alive => MemberState::Alive,
+                    PeerState::Suspect => MemberState::Suspect,
+                    PeerState::Dead => MemberState::Dead,
+                };
+                let state = detected.max(
+                    lally
+                        .membership
+                        .state(&peer.id)
+                        .unwrap_or(MemberState::Alive),
+                );
+                let last_contact = lally.detector.last_contact(&peer.id).and_then(ago);
+                let channel = lally.channels.health(&peer.id).map(|health| ChannelStatus {
+                    state: health.state.as_str().to_string(),
+                    failures: health.failures,
+                    last_ok: health.last_ok.and_then(|at| ago(at.elapsed())),
+                    error: health.error.unwrap_or_default(),
+                });
+                let (stats, error) = match lally.pool.stats(&peer.id).await {
+                    Ok(stats) => (Some(stats), String::new()),
+                    Err(e) => {
+                        warn!("Failed to get stats from {}: {:#}", peer.id, e);
+                        (None, format!("{:#}", e))
+                    }
+                };
+                NodeStatus {
+                    id: peer.id,
+                    addr: peer.addr,
+                    state: MemberStatus::from(state).into(),
+                    last_contact,
+                    stats,
+                    error,
+                    channel,
+                }
+            });
+        }
+        let mut peers = Vec::new();
+        while let Some(result) = futures_set.join_next().await {
+            match result {
+                Ok(status) => peers.push(status),
+                Err(e) => error!("Task panicked: {:?}", e),
+            }
+        }
+        peers.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+        nodes.extend(peers);
+
+        ClusterStatusResponse {
+            cluster_id: lally.cluster_state.cluster_id(),
+            nodes,
+        }
+    }
+}
+
+// the wall clock time that was this long ago
+fn ago(elapsed: Duration) -> Option<Timestamp> {
+    let at = Utc::now() - chrono::Duration::from_std(elapsed).ok()?;
+    Some(Timestamp {
+        seconds: at.timestamp(),
+        nanos: at.timestamp_subsec_nanos() as i32,
+    })
+}
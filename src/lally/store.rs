@@ -7,6 +7,7 @@ use papaya::HashMap;
 use prost_types::Timestamp;
 use rapidhash::fast::RandomState;
 use std::cmp::Ordering;
+use std::collections::HashMap as StdHashMap;
 use std::path::Path;
 use tokio::fs::OpenOptions;
 use tokio::io::{AsyncBufReadExt, BufReader};
@@ -53,24 +54,32 @@ impl Store {
 
         let mut lines = file.lines();
         let mut line_count = 0;
-        let pin = self.store.pin();
 
         while let Some(line) = lines.next_line().await? {
             line_count += 1;
             if let Ok(operation) = parse_aof_log(&line) {
                 match operation.name.as_str() {
+                    // hints and repairs can log an older write after a newer one, so the
+                    // log is replayed with last-write-wins too
                     "ADD" => {
                         if let Some(value) = operation.value {
-                            pin.insert(operation.key.clone(), (value, operation.timestamp, true));
+                            self.put(&operation.key, (value, operation.timestamp, true));
                             debug!("ADD operation: inserted key '{}'", operation.key);
                         } else {
                             error!("Missing value for ADD operation, this shouldn't happen");
                         }
                     }
+                    // the tombstone is kept, so an older write logged after it can't bring
+                    // the value back, and neither can a peer that missed the removal
                     "REMOVE" => {
-                        pin.remove(&operation.key);
+                        let value = operation.value.unwrap_or_default();
+                        self.put(&operation.key, (value, operation.timestamp, false));
                         debug!("REMOVE operation: removed key '{}'", operation.key);
                     }
+                    "DROP" => {
+                        self.drop_key(&operation.key, &operation.timestamp);
+                        debug!("DROP operation: dropped key '{}'", operation.key);
+                    }
                     _ => error!("Unknown operation: {}", operation.name),
                 }
             } else if !line.trim().is_empty() {
@@ -98,13 +107,74 @@ impl Store {
         result
     }
 
-    pub fn import_store(&self, store: Vec<KvData>) {
+    // keys written after `since`, or all of them without it, that the filter lets through
+    pub fn sorted_keys(
+        &self,
+        since: Option<&Timestamp>,
+        filter: impl Fn(&str) -> bool,
+    ) -> Vec<String> {
+        let pin = self.store.pin();
+        let mut keys: Vec<String> = pin
+            .iter()
+            .filter(|(key, value)| {
+                since.is_none_or(|since| compare_timestamps(&value.1, since) == Ordering::Greater)
+                    && filter(key)
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    // live keys and tombstones of removed ones
+    pub fn counts(&self) -> (u64, u64) {
+        let pin = self.store.pin();
+        let tombstones = pin.values().filter(|value| !value.2).count() as u64;
+        (pin.len() as u64 - tombstones, tombstones)
+    }
+
+    // the newest write this node has seen, removals included
+    pub fn latest_timestamp(&self) -> Option<Timestamp> {
+        let pin = self.store.pin();
+        pin.values().map(|value| value.1).max_by(compare_timestamps)
+    }
+
+    pub fn export_key(&self, key: &str) -> Option<KvData> {
+        let pin = self.store.pin();
+        pin.get(key).map(|value| KvData {
+            key: key.to_string(),
+            value: value.0.clone(),
+            timestamp: Some(value.1),
+            valid: value.2,
+        })
+    }
+
+    // like export_store, but only for the keys the filter lets through
+    pub fn export_where(&self, filter: impl Fn(&str) -> bool) -> Vec<KvData> {
+        let pin = self.store.pin();
+        pin.iter()
+            .filter(|(key, _)| filter(key))
+            .map(|(key, value)| KvData {
+                key: key.clone(),
+                value: value.0.clone(),
+                timestamp: Some(value.1),
+                valid: value.2,
+            })
+            .collect()
+    }
+
+    // merges the given entries with last-write-wins, returning the ones that actually won
+    // as operations so the caller can run them through the hooks
+    pub fn import_store(&self, store: Vec<KvData>) -> Vec<Operation> {
         info!("Importing store data with {} entries", store.len());
         let pin = self.store.pin();
+        let mut applied = Vec::new();
         for data in store {
             if let Some(timestamp) = data.timestamp {
                 let new_value = (data.value, timestamp, data.valid);
-                pin.update_or_insert_with(
+                // a copy we already have isn't news, and shouldn't be logged twice
+                let known = pin.get(&data.key).map(|existing| existing.1);
+                let stored = pin.update_or_insert_with(
                     data.key.clone(),
                     |existing| {
                         if compare_timestamps(&new_value.1, &existing.1) == Ordering::Greater {
@@ -115,10 +185,52 @@ impl Store {
                     },
                     || new_value.clone(),
                 );
-                debug!("Imported key '{}'", data.key);
+                if stored.1 == timestamp && known != Some(timestamp) {
+                    debug!("Imported key '{}'", data.key);
+                    applied.push(Operation {
+                        name: String::from(if new_value.2 { "ADD" } else { "REMOVE" }),
+                        level: String::from("INFO"),
+                        key: data.key,
+                        value: new_value.2.then_some(new_value.0),
+                        timestamp,
+                        annotations: StdHashMap::new(),
+                    });
+                }
             }
         }
-        info!("Store import completed");
+        info!(
+            "Store import completed, {} entries were newer than the local copy",
+            applied.len()
+        );
+        applied
+    }
+
+    // physically drops a key this node no longer replicates, unless it was written again
+    // after the given timestamp, in which case the newer write is kept
+    pub fn drop_key(&self, key: &str, timestamp: &Timestamp) -> bool {
+        let pin = self.store.pin();
+        let removed = pin.remove_if(key, |_, existing| {
+            compare_timestamps(&existing.1, timestamp) != Ordering::Greater
+        });
+        matches!(removed, Ok(Some(_)))
+    }
+
+    // last-write-wins, a write older than what's stored (a late hint, a slow replica call)
+    // leaves the newer value alone. returns whatever is stored afterwards
+    fn put(&self, key: &str, new_value: (String, Timestamp, bool)) -> (String, Timestamp, bool) {
+        let pin = self.store.pin();
+        pin.update_or_insert_with(
+            key.to_string(),
+            |existing| {
+                if compare_timestamps(&new_value.1, &existing.1) == Ordering::Greater {
+                    new_value.clone()
+                } else {
+                    existing.clone()
+                }
+            },
+            || new_value.clone(),
+        )
+        .clone()
     }
 
     pub fn add(&self, operation: &Operation) -> KVResult {
@@ -130,14 +242,15 @@ impl Store {
             "Performing ADD operation for key '{}' with value '{}'",
             key, value
         );
-        self.store
-            .pin()
-            .insert(key.clone(), (value.clone(), timestamp, true));
+        let stored = self.put(key, (value.clone(), timestamp, true));
+        if stored.1 != timestamp {
+            debug!("Key '{}' has a newer write, ADD was ignored", key);
+        }
 
         KVResult {
             success: true,
             value: None,
-            timestamp: Some(timestamp),
+            timestamp: Some(stored.1),
         }
     }
 
@@ -145,13 +258,18 @@ impl Store {
         debug!("Performing REMOVE operation for key '{}'", operation.key);
         let pin = self.store.pin();
 
-        // Snapshot the current state. The check-then-update is benign-racy:
-        // a concurrent REMOVE arriving between get and update just overwrites
-        // with the same tombstone shape.
-        match pin.get(&operation.key) {
-            Some(existing) if existing.2 => {
-                let new_value = (existing.0.clone(), operation.timestamp, false);
-                pin.update(operation.key.clone(), |_| new_value.clone());
+        // last-write-wins like add, a removal older than the stored write doesn't undo it
+        let stored = pin.update(operation.key.clone(), |existing| {
+            if existing.2
+                && compare_timestamps(&operation.timestamp, &existing.1) == Ordering::Greater
+            {
+                (existing.0.clone(), operation.timestamp, false)
+            } else {
+                existing.clone()
+            }
+        });
+        match stored {
+            Some(stored) if !stored.2 && stored.1 == operation.timestamp => {
                 debug!("Key '{}' successfully removed", operation.key);
                 KVResult {
                     success: true,
@@ -159,6 +277,17 @@ impl Store {
                     timestamp: Some(operation.timestamp),
                 }
             }
+            Some(stored) if stored.2 => {
+                debug!(
+                    "Key '{}' has a write newer than the removal, REMOVE was ignored",
+                    operation.key
+                );
+                KVResult {
+                    success: false,
+                    value: None,
+                    timestamp: None,
+                }
+            }
             Some(_) => {
                 error!(
                     "Failed to remove key '{}': it is already marked as invalid",
@@ -196,10 +325,11 @@ impl Store {
                     }
                 } else {
                     debug!("Key '{}' found but marked as invalid", operation.key);
+                    // the removal's timestamp lets a read tell it apart from an older value
                     KVResult {
                         success: false,
                         value: None,
-                        timestamp: None,
+                        timestamp: Some(value.1),
                     }
                 }
             }
@@ -1,16 +1,21 @@
+mod auth;
 mod cluster;
 mod config;
 mod hooks;
 mod http_server;
 mod lally;
+mod tls;
 mod utils;
 
 use crate::cluster::GrpcServer;
 use crate::config::Config;
 use crate::hooks::aof::AppendOnlyLog;
+use crate::hooks::script::ScriptHook;
+use crate::lally::bootstrap::Bootstrap;
+use crate::lally::cluster_state::ClusterState;
 use crate::lally::Lally;
 use std::sync::Arc;
-use tracing::{error, info, warn};
+use tracing::{error, info};
 
 const LOGO: &str = r#"
 
@@ -42,33 +47,40 @@ async fn main() {
                 }
             };
 
+            // Scripts are checked before the node takes part in the cluster, a broken one stops
+            // it before anyone depends on it
+            match ScriptHook::init(&config).await {
+                Ok(Some(script_hook)) => lally.hooks.register(script_hook),
+                Ok(None) => {}
+                Err(e) => {
+                    error!("Failed to load scripts: {}", e);
+                    return;
+                }
+            }
+
+            // Registered before the gRPC server starts, a replica write that comes in right
+            // after would otherwise never reach the log
+            let wal_hook = AppendOnlyLog::init(&config).await;
+            lally.hooks.register(wal_hook);
+
             info!("Starting gRPC server...");
             if let Err(e) = GrpcServer::run(Arc::clone(&lally), &config).await {
                 error!("Failed to start gRPC server: {}", e);
                 return;
             }
 
-            // Joining the cluster if a seed node addr is provided
-            match config.seed_node() {
-                Some(addr) => {
-                    info!("Attempting to join cluster on address: {}", addr);
-                    match lally.pool.join(addr.to_string()).await {
-                        Ok(store_data) => {
-                            info!("Successfully joined cluster.");
-                            lally.store.import_store(store_data);
-                        }
-                        Err(e) => {
-                            error!("Failed to join cluster: {}", e);
-                        }
-                    };
-                }
-                None => {
-                    warn!("No seed node address provided; this node will act as the first node in the cluster.");
-                }
+            // Joining the cluster through the seed nodes, the peers we knew before a restart or
+            // the ones discovery finds
+            let seed_id = ClusterState::join(&lally).await;
+            if seed_id.is_none() {
+                lally.bootstrap.mark_ready();
             }
 
-            let wal_hook = AppendOnlyLog::init(&config).await;
-            lally.hooks.register(wal_hook);
+            // Copying the seed's store over, the hooks are in place so the imported keys are
+            // logged too
+            if let Some(seed_id) = seed_id {
+                tokio::spawn(Bootstrap::run(Arc::clone(&lally), seed_id));
+            }
 
             if let Err(e) = http_server::run(Arc::clone(&lally), config).await {
                 error!("Failed to run HTTP server: {}", e);
@@ -0,0 +1,293 @@
+use crate::config::Config;
+use anyhow::{bail, Context, Result};
+use hyper_util::rt::TokioIo;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context as TaskContext, Poll};
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::{interval, sleep, timeout, Duration};
+use tokio_rustls::client::TlsStream as ClientTlsStream;
+use tokio_rustls::rustls::crypto::ring::default_provider;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream as ServerTlsStream;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::server::{Connected, TcpConnectInfo};
+use tonic::transport::Uri;
+use tracing::{debug, error, info, warn};
+
+// handshakes that haven't been picked up by the grpc server yet
+const PENDING_HANDSHAKES: usize = 128;
+// errors like running out of file descriptors don't go away by retrying right away
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+// a peer's connection once the handshake is done, it tells the grpc server the peer's tcp
+// address like a plain connection would
+pub struct TlsConnection(ServerTlsStream<TcpStream>);
+
+impl Connected for TlsConnection {
+    type ConnectInfo = TcpConnectInfo;
+
+    fn connect_info(&self) -> TcpConnectInfo {
+        self.0.get_ref().0.connect_info()
+    }
+}
+
+impl AsyncRead for TlsConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.0.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to parse certificates in {:?}", path))?;
+    if certs.is_empty() {
+        bail!("No certificates found in {:?}", path);
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to parse the private key in {:?}", path))?
+        .with_context(|| format!("No private key found in {:?}", path))
+}
+
+struct Identity {
+    ca: PathBuf,
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+impl Identity {
+    // the newest modification time of the three files, a change means it's time to reload
+    fn modified(&self) -> Option<SystemTime> {
+        [&self.ca, &self.cert, &self.key]
+            .iter()
+            .filter_map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+            .max()
+    }
+
+    // both sides present the node certificate and only trust peers signed by the ca
+    fn build(&self) -> Result<(Arc<ServerConfig>, Arc<ClientConfig>)> {
+        let mut roots = RootCertStore::empty();
+        for cert in read_certs(&self.ca)? {
+            roots.add(cert).context("Invalid CA certificate")?;
+        }
+        let roots = Arc::new(roots);
+        let certs = read_certs(&self.cert)?;
+        let key = read_key(&self.key)?;
+        let provider = Arc::new(default_provider());
+
+        let verifier =
+            WebPkiClientVerifier::builder_with_provider(Arc::clone(&roots), Arc::clone(&provider))
+                .build()
+                .context("Failed to build the client certificate verifier")?;
+        let mut server = ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs.clone(), key.clone_key())
+            .context("Node certificate and key don't match")?;
+        server.alpn_protocols = vec![b"h2".to_vec()];
+
+        let mut client = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_client_auth_cert(certs, key)
+            .context("Node certificate and key don't match")?;
+        client.alpn_protocols = vec![b"h2".to_vec()];
+
+        Ok((Arc::new(server), Arc::new(client)))
+    }
+}
+
+// mutual tls for the grpc traffic between nodes, the certificates are watched and
+// swapped in without a restart; connections already open keep their session
+pub struct Tls {
+    identity: Identity,
+    server_name: Option<String>,
+    reload_interval: Duration,
+    handshake_timeout: Duration,
+    server: RwLock<Arc<ServerConfig>>,
+    client: RwLock<Arc<ClientConfig>>,
+    loaded: Mutex<Option<SystemTime>>,
+}
+
+impl Tls {
+    pub fn load(config: &Config) -> Result<Option<Arc<Self>>> {
+        let (Some(ca), Some(cert), Some(key)) =
+            (config.tls_ca(), config.tls_cert(), config.tls_key())
+        else {
+            return Ok(None);
+        };
+        let identity = Identity {
+            ca: ca.to_path_buf(),
+            cert: cert.to_path_buf(),
+            key: key.to_path_buf(),
+        };
+        let loaded = identity.modified();
+        let (server, client) = identity
+            .build()
+            .context("Failed to load TLS certificates")?;
+        info!("Loaded TLS certificates from {:?}", identity.cert);
+        Ok(Some(Arc::new(Tls {
+            identity,
+            server_name: config.tls_server_name().map(str::to_string),
+            reload_interval: Duration::from_millis(config.tls_reload_interval().max(1)),
+            handshake_timeout: Duration::from_millis(config.tls_handshake_timeout().max(1)),
+            server: RwLock::new(server),
+            client: RwLock::new(client),
+            loaded: Mutex::new(loaded),
+        })))
+    }
+
+    fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(Arc::clone(
+            &self.server.read().expect("tls config lock poisoned"),
+        ))
+    }
+
+    fn connector(&self) -> TlsConnector {
+        TlsConnector::from(Arc::clone(
+            &self.client.read().expect("tls config lock poisoned"),
+        ))
+    }
+
+    // accepts tcp connections and hands the ones that complete a handshake to the grpc server
+    pub async fn incoming(
+        self: Arc<Self>,
+        addr: SocketAddr,
+    ) -> Result<ReceiverStream<Result<TlsConnection, std::io::Error>>> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .context("Failed to bind the GRPC server")?;
+        let (sender, receiver) = mpsc::channel(PENDING_HANDSHAKES);
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        error!("Failed to accept a connection: {}", e);
+                        sleep(ACCEPT_BACKOFF).await;
+                        continue;
+                    }
+                };
+                // handshakes run on their own so a slow peer doesn't hold up the rest, and get
+                // a deadline so one that never finishes doesn't keep its connection forever
+                let acceptor = self.acceptor();
+                let sender = sender.clone();
+                let deadline = self.handshake_timeout;
+                tokio::spawn(async move {
+                    match timeout(deadline, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = sender.send(Ok(TlsConnection(stream))).await;
+                        }
+                        Ok(Err(e)) => debug!("TLS handshake with {} failed: {}", peer, e),
+                        Err(_) => warn!("TLS handshake with {} timed out", peer),
+                    }
+                });
+            }
+        });
+        Ok(ReceiverStream::new(receiver))
+    }
+
+    // dials a peer, used as the connector of every channel so reconnects pick up
+    // reloaded certificates
+    pub async fn connect(&self, uri: Uri) -> std::io::Result<TokioIo<ClientTlsStream<TcpStream>>> {
+        let invalid = |message: &str| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, message.to_string())
+        };
+        let host = uri
+            .host()
+            .ok_or_else(|| invalid("Peer address has no host"))?;
+        let port = uri
+            .port_u16()
+            .ok_or_else(|| invalid("Peer address has no port"))?;
+        let server_name = self
+            .server_name
+            .as_deref()
+            .unwrap_or(host.trim_start_matches('[').trim_end_matches(']'));
+        let server_name = ServerName::try_from(server_name.to_string())
+            .map_err(|_| invalid("Peer address isn't a valid TLS server name"))?;
+
+        let stream = TcpStream::connect((host, port)).await?;
+        let stream = self.connector().connect(server_name, stream).await?;
+        Ok(TokioIo::new(stream))
+    }
+
+    fn reload(&self) -> Result<bool> {
+        let modified = self.identity.modified();
+        let mut loaded = self.loaded.lock().expect("tls reload lock poisoned");
+        if modified == *loaded {
+            return Ok(false);
+        }
+        let (server, client) = self.identity.build()?;
+        *self.server.write().expect("tls config lock poisoned") = server;
+        *self.client.write().expect("tls config lock poisoned") = client;
+        *loaded = modified;
+        Ok(true)
+    }
+
+    pub async fn run(tls: Arc<Tls>) {
+        let mut ticker = interval(tls.reload_interval);
+        loop {
+            ticker.tick().await;
+            match tls.reload() {
+                Ok(true) => info!("Reloaded TLS certificates"),
+                Ok(false) => {}
+                // keep serving with the old certificates, the files may be halfway rewritten
+                Err(e) => error!("Failed to reload TLS certificates: {:#}", e),
+            }
+        }
+    }
+}
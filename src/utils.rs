@@ -42,6 +42,7 @@ pub fn parse_aof_log(line: &str) -> Result<Operation> {
         level,
         timestamp: timestamp_from_rfc3339(timestamp)
             .context("failed to parse rfc3339 to Timestamp")?,
+        annotations: HashMap::new(),
     })
 }
 
@@ -52,6 +53,8 @@ pub struct Operation {
     pub key: String,
     pub value: Option<String>,
     pub timestamp: Timestamp,
+    // free-form notes attached by hooks, only lives on the coordinating node
+    pub annotations: HashMap<String, String>,
 }
 
 pub struct KVResult {